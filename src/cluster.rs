@@ -0,0 +1,199 @@
+// File: cluster.rs
+// This file has corpus clustering. Grains are grouped into clusters of similar grains with k-means
+// over their normalized features, and new grains are assigned to the nearest cluster when they are inserted.
+
+use crate::grain_extractor::GrainEntry;
+use crate::similarity::Similarity;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+/// The settings for k-means clustering
+#[derive(Debug, Clone)]
+pub struct KMeansSettings {
+    /// The number of clusters
+    pub k: usize,
+    /// Whether to use mini-batch k-means, which updates the centroids from a random sample of grains in
+    /// each iteration instead of from all grains
+    pub mini_batch: bool,
+    /// The number of grains in each mini-batch
+    pub batch_size: usize,
+    pub max_iterations: usize,
+    /// The random seed. If omitted, every run is different.
+    pub seed: Option<u64>,
+}
+
+/// The clusters of a corpus: the feature normalization that was used, and the centroid of each cluster
+/// as a normalized feature vector. The cluster ids are the centroid indices.
+#[derive(Debug, Clone)]
+pub struct ClusterModel {
+    pub similarity: Similarity,
+    pub centroids: Vec<Vec<f64>>,
+}
+
+impl ClusterModel {
+    /// Finds the nearest cluster to a normalized feature vector. Returns the cluster id and the distance to its centroid.
+    pub fn assign_vector(&self, vector: &[f64]) -> Option<(usize, f64)> {
+        let mut best: Option<(usize, f64)> = None;
+        for (i, centroid) in self.centroids.iter().enumerate() {
+            let distance = self.similarity.distance(vector, centroid);
+            match best {
+                Some((_, best_distance)) if best_distance <= distance => (),
+                _ => best = Some((i, distance))
+            }
+        }
+        best
+    }
+
+    /// Finds the nearest cluster to a grain. Returns the cluster id and the distance to its centroid.
+    pub fn assign(&self, grain: &GrainEntry) -> Option<(usize, f64)> {
+        self.assign_vector(&self.similarity.features(grain))
+    }
+}
+
+/// Chooses the initial centroids with k-means++: each centroid is chosen at random, with a probability
+/// proportional to the squared distance to the nearest centroid chosen so far
+fn initial_centroids(vectors: &[Vec<f64>], k: usize, similarity: &Similarity, rng: &mut StdRng) -> Vec<Vec<f64>> {
+    let mut centroids: Vec<Vec<f64>> = vec![vectors[rng.gen_range(0..vectors.len())].clone()];
+    let mut nearest: Vec<f64> = vectors.iter().map(|v| similarity.distance(v, &centroids[0]).powi(2)).collect();
+    while centroids.len() < k {
+        let total: f64 = nearest.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.gen::<f64>() * total;
+            let mut chosen = vectors.len() - 1;
+            for (i, d) in nearest.iter().enumerate() {
+                if target < *d {
+                    chosen = i;
+                    break;
+                }
+                target -= d;
+            }
+            chosen
+        } else {
+            // All grains are at a centroid already
+            rng.gen_range(0..vectors.len())
+        };
+        centroids.push(vectors[next].clone());
+        let centroid = &centroids[centroids.len() - 1];
+        for (d, v) in nearest.iter_mut().zip(vectors.iter()) {
+            *d = f64::min(*d, similarity.distance(v, centroid).powi(2));
+        }
+    }
+    centroids
+}
+
+/// Runs k-means over normalized feature vectors. Returns the centroids. There must be at least `k` vectors.
+pub fn kmeans(vectors: &[Vec<f64>], settings: &KMeansSettings, similarity: &Similarity) -> Vec<Vec<f64>> {
+    let mut rng = match settings.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+    };
+    let mut model = ClusterModel{similarity: similarity.clone(), centroids: initial_centroids(vectors, settings.k, similarity, &mut rng)};
+    let dims = vectors.first().map(|v| v.len()).unwrap_or(0);
+
+    if settings.mini_batch {
+        // Each centroid moves toward the grains assigned to it, with a learning rate that shrinks
+        // as more grains are assigned to it
+        let mut counts: Vec<usize> = vec![0; settings.k];
+        for _ in 0..settings.max_iterations {
+            let batch: Vec<usize> = (0..settings.batch_size).map(|_| rng.gen_range(0..vectors.len())).collect();
+            let assignments: Vec<usize> = batch.iter().map(|&i| model.assign_vector(&vectors[i]).map(|(c, _)| c).unwrap_or(0)).collect();
+            for (&i, &cluster) in batch.iter().zip(assignments.iter()) {
+                counts[cluster] += 1;
+                let rate = 1.0 / counts[cluster] as f64;
+                for (c, x) in model.centroids[cluster].iter_mut().zip(vectors[i].iter()) {
+                    *c += rate * (x - *c);
+                }
+            }
+        }
+        return model.centroids;
+    }
+
+    let mut assignments: Vec<usize> = vec![usize::MAX; vectors.len()];
+    for _ in 0..settings.max_iterations {
+        let mut changed = false;
+        let mut distances: Vec<f64> = Vec::with_capacity(vectors.len());
+        for (assignment, v) in assignments.iter_mut().zip(vectors.iter()) {
+            let (cluster, distance) = model.assign_vector(v).unwrap_or((0, 0.0));
+            if *assignment != cluster {
+                *assignment = cluster;
+                changed = true;
+            }
+            distances.push(distance);
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums: Vec<Vec<f64>> = vec![vec![0.0; dims]; settings.k];
+        let mut counts: Vec<usize> = vec![0; settings.k];
+        for (&cluster, v) in assignments.iter().zip(vectors.iter()) {
+            counts[cluster] += 1;
+            for (s, x) in sums[cluster].iter_mut().zip(v.iter()) {
+                *s += x;
+            }
+        }
+        for cluster in 0..settings.k {
+            if counts[cluster] > 0 {
+                model.centroids[cluster] = sums[cluster].iter().map(|s| s / counts[cluster] as f64).collect();
+            } else {
+                // An empty cluster takes over the grain that is farthest from its centroid
+                let farthest = (0..vectors.len()).max_by(|&a, &b| distances[a].total_cmp(&distances[b])).unwrap_or(0);
+                model.centroids[cluster] = vectors[farthest].clone();
+                distances[farthest] = 0.0;
+            }
+        }
+    }
+    model.centroids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::{CorpusStats, DEFAULT_FEATURES, SimilaritySettings};
+
+    /// Makes a euclidean similarity over two features
+    fn two_features() -> Similarity {
+        let mut settings = SimilaritySettings::default();
+        for feature in DEFAULT_FEATURES.iter().skip(2) {
+            settings.weights.insert(String::from(*feature), 0.0);
+        }
+        Similarity::new(&settings, &CorpusStats::default())
+    }
+
+    /// Makes two blobs of points around (-5, -5) and (5, 5)
+    fn two_blobs() -> Vec<Vec<f64>> {
+        let mut rng = StdRng::seed_from_u64(1);
+        (0..200).map(|i| {
+            let center = if i % 2 == 0 { -5.0 } else { 5.0 };
+            vec![center + rng.gen_range(-1.0..1.0), center + rng.gen_range(-1.0..1.0)]
+        }).collect()
+    }
+
+    fn assert_separates_blobs(mut centroids: Vec<Vec<f64>>) {
+        assert_eq!(centroids.len(), 2);
+        centroids.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        for (centroid, center) in centroids.iter().zip([-5.0, 5.0]) {
+            for x in centroid.iter() {
+                assert!((x - center).abs() < 0.5, "centroid {:?} is not at the blob around {}", centroid, center);
+            }
+        }
+    }
+
+    #[test]
+    fn kmeans_separates_two_blobs() {
+        let settings = KMeansSettings{k: 2, mini_batch: false, batch_size: 0, max_iterations: 100, seed: Some(2)};
+        assert_separates_blobs(kmeans(&two_blobs(), &settings, &two_features()));
+    }
+
+    #[test]
+    fn mini_batch_kmeans_separates_two_blobs() {
+        let settings = KMeansSettings{k: 2, mini_batch: true, batch_size: 20, max_iterations: 100, seed: Some(3)};
+        assert_separates_blobs(kmeans(&two_blobs(), &settings, &two_features()));
+    }
+
+    #[test]
+    fn seeded_kmeans_is_repeatable() {
+        let settings = KMeansSettings{k: 2, mini_batch: true, batch_size: 20, max_iterations: 10, seed: Some(4)};
+        let vectors = two_blobs();
+        assert_eq!(kmeans(&vectors, &settings, &two_features()), kmeans(&vectors, &settings, &two_features()));
+    }
+}
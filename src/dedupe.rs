@@ -0,0 +1,218 @@
+// File: dedupe.rs
+// This file has near-duplicate detection. Grains that are close in feature space are grouped, optionally
+// confirmed by comparing their waveforms, so that one grain per group can be kept.
+
+use crate::grain_extractor::GrainEntry;
+use crate::knn::GrainIndex;
+use crate::{render, sqlite};
+use aus::spectrum::{irfft, rfft};
+use std::collections::{HashMap, HashSet};
+
+// Grains must overlap by at least this fraction of the shorter grain to be compared at a lag
+const MIN_OVERLAP: f64 = 0.5;
+
+/// The settings for finding near-duplicate grains
+#[derive(Debug, Clone)]
+pub struct DedupeSettings {
+    /// The largest feature distance between duplicates
+    pub threshold: f64,
+    /// Whether grains from different source files can be duplicates
+    pub across_files: bool,
+    /// If set, duplicates must also have at least this waveform cross-correlation
+    pub min_correlation: Option<f64>,
+}
+
+/// A grain that is kept, and the grains that are near-duplicates of it
+#[derive(Debug, Clone)]
+pub struct DuplicateGroup {
+    pub representative: i64,
+    pub duplicates: Vec<i64>,
+}
+
+/// Gets the highest normalized cross-correlation between two signals, over the lags at which they overlap by
+/// at least half of the shorter signal. At each lag, the correlation is normalized by the energy of the
+/// overlapping parts, so a signal has a correlation of 1.0 with a longer signal that contains it.
+pub fn max_correlation(a: &[f64], b: &[f64]) -> f64 {
+    let (m, n) = (a.len(), b.len());
+    if m == 0 || n == 0 {
+        return 0.0;
+    }
+    let fft_size = (m + n).next_power_of_two();
+    let pad = |x: &[f64]| -> Vec<f64> {
+        let mut padded = x.to_vec();
+        padded.resize(fft_size, 0.0);
+        padded
+    };
+    let spectrum_a = rfft(&pad(a), fft_size);
+    let spectrum_b = rfft(&pad(b), fft_size);
+    let product: Vec<_> = spectrum_a.iter().zip(spectrum_b.iter()).map(|(x, y)| x.conj() * y).collect();
+    // Index l of the inverse transform is the sum of a[i] * b[i + l], and negative lags wrap around
+    let correlation = match irfft(&product, fft_size) {
+        Ok(x) => x,
+        Err(_) => return 0.0
+    };
+
+    // Prefix sums of the squared signals give the energy of any overlap
+    let energy = |x: &[f64]| -> Vec<f64> {
+        let mut sums: Vec<f64> = Vec::with_capacity(x.len() + 1);
+        sums.push(0.0);
+        for sample in x.iter() {
+            sums.push(sums[sums.len() - 1] + sample * sample);
+        }
+        sums
+    };
+    let (energy_a, energy_b) = (energy(a), energy(b));
+    let min_overlap = ((usize::min(m, n) as f64 * MIN_OVERLAP).ceil() as usize).max(1);
+    let mut best: f64 = 0.0;
+    for lag in -(m as i64) + 1..n as i64 {
+        let lo = (-lag).max(0) as usize;
+        let hi = usize::min(m, (n as i64 - lag) as usize);
+        if hi < lo + min_overlap {
+            continue;
+        }
+        let ea = energy_a[hi] - energy_a[lo];
+        let eb = energy_b[(hi as i64 + lag) as usize] - energy_b[(lo as i64 + lag) as usize];
+        if ea <= 0.0 || eb <= 0.0 {
+            continue;
+        }
+        let index = lag.rem_euclid(fft_size as i64) as usize;
+        best = f64::max(best, correlation[index] / fft_size as f64 / (ea * eb).sqrt());
+    }
+    best.min(1.0)
+}
+
+/// Loads the audio of a grain at a sample rate
+fn load_grain_audio(grain: &GrainEntry, sample_rate: u32, cache: &mut render::SourceCache) -> Option<Vec<f64>> {
+    let location = sqlite::GrainLocation{file: grain.file.clone(), start_frame: grain.start_frame, end_frame: grain.end_frame};
+    let (grain_rate, audio) = render::load_grain(&location, cache)?;
+    Some(render::resample(&audio, grain_rate, sample_rate))
+}
+
+/// Groups near-duplicate grains. The grains are visited in id order, and each grain that isn't a duplicate
+/// of an earlier grain is kept, and takes every grain within the threshold that isn't taken yet as a duplicate.
+/// Only the grains in `grains` are grouped, and grains without duplicates are left out of the result.
+pub fn find_duplicates(grains: &[(i64, GrainEntry)], index: &GrainIndex, settings: &DedupeSettings) -> Vec<DuplicateGroup> {
+    let mut order: Vec<usize> = (0..grains.len()).collect();
+    order.sort_by_key(|&i| grains[i].0);
+    let positions: HashMap<i64, usize> = grains.iter().enumerate().map(|(i, (id, _))| (*id, i)).collect();
+    let mut taken: HashSet<i64> = HashSet::new();
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    // Only the most recently used source files are cached, so that memory doesn't grow with the corpus
+    let mut cache = render::SourceCache::new();
+
+    for i in order {
+        let (id, grain) = &grains[i];
+        if taken.contains(id) {
+            continue;
+        }
+        taken.insert(*id);
+        let candidates = index.within(&index.similarity().features(grain), settings.threshold, |other| {
+            !taken.contains(&other) && positions.get(&other).is_some_and(|&j| settings.across_files || grains[j].1.file == grain.file)
+        });
+        if candidates.is_empty() {
+            continue;
+        }
+
+        let mut duplicates: Vec<i64> = Vec::new();
+        match settings.min_correlation {
+            Some(min_correlation) => {
+                let audio = match load_grain_audio(grain, grain.sample_rate, &mut cache) {
+                    Some(x) => x,
+                    None => continue
+                };
+                for (other, _) in candidates {
+                    let other_grain = &grains[positions[&other]].1;
+                    if let Some(other_audio) = load_grain_audio(other_grain, grain.sample_rate, &mut cache) {
+                        if max_correlation(&audio, &other_audio) >= min_correlation {
+                            duplicates.push(other);
+                        }
+                    }
+                }
+            },
+            None => duplicates.extend(candidates.into_iter().map(|(other, _)| other))
+        }
+        if !duplicates.is_empty() {
+            taken.extend(duplicates.iter().copied());
+            groups.push(DuplicateGroup{representative: *id, duplicates});
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::{CorpusStats, FeatureStats, SimilaritySettings};
+    use crate::stft;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    fn noise(len: usize, seed: u64) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect()
+    }
+
+    /// Makes a grain from a file whose features are all `value`
+    fn grain(file: &str, value: f64) -> GrainEntry {
+        GrainEntry{
+            file: String::from(file), start_frame: 0, end_frame: 100, sample_rate: 44100, grain_duration: value, energy: value,
+            pitch_estimation: value, midi: value, spectral_centroid: value, spectral_entropy: value, spectral_flatness: value,
+            spectral_kurtosis: value, spectral_roll_off_50: value, spectral_roll_off_75: value, spectral_roll_off_90: value,
+            spectral_roll_off_95: value, spectral_skewness: value, spectral_slope: value, spectral_slope_0_1_khz: value,
+            spectral_slope_1_5_khz: value, spectral_slope_0_5_khz: value, spectral_variance: value, voicing_probability: value,
+            hnr: value, inharmonicity: value, odd_even_ratio: value, tristimulus_1: value, tristimulus_2: value, tristimulus_3: value,
+            rms: value, peak: value, crest_factor: value, zero_crossing_rate: value, attack_time: value, decay_slope: value,
+            temporal_centroid: value, envelope_flatness: value, frame_statistics: stft::FrameStatistics::unavailable(),
+            mel_bands: Vec::new(), mfccs: Vec::new(), spectral_peaks: Vec::new(), tags: Vec::new()
+        }
+    }
+
+    #[test]
+    fn a_signal_correlates_with_itself() {
+        let a = noise(500, 1);
+        assert!((max_correlation(&a, &a) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_shifted_copy_inside_a_longer_signal_correlates() {
+        let a = noise(500, 2);
+        let mut b = noise(300, 3);
+        b.extend(a.iter().map(|x| x * 0.5));
+        b.extend(noise(200, 4));
+        assert!((max_correlation(&a, &b) - 1.0).abs() < 1e-9);
+        assert!((max_correlation(&b, &a) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lags_below_the_minimum_overlap_are_ignored() {
+        // The end of `a` matches the start of `b`, but they only overlap by 30% of their length there
+        let a = noise(100, 5);
+        let mut b = a[70..].to_vec();
+        b.extend(noise(70, 6));
+        assert!(max_correlation(&a, &b) < 0.9);
+        // With more overlap, the match is found
+        let mut c = a[40..].to_vec();
+        c.extend(noise(40, 7));
+        assert!((max_correlation(&a, &c) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn grains_from_other_files_are_only_duplicates_across_files() {
+        let grains: Vec<(i64, GrainEntry)> = vec![(1, grain("a.wav", 0.0)), (2, grain("a.wav", 0.1)), (3, grain("b.wav", 0.05)), (4, grain("a.wav", 5.0))];
+        // Only the energy is scaled, so the distance between grains is the difference in their feature value
+        let mut stats = CorpusStats::default();
+        stats.features.insert(String::from("energy"), FeatureStats{count: 4, mean: 0.0, std: 1.0, min: 0.0, max: 5.0});
+        let index = GrainIndex::from_grains(&SimilaritySettings::default(), &stats, &grains);
+
+        let mut settings = DedupeSettings{threshold: 0.25, across_files: false, min_correlation: None};
+        let groups = find_duplicates(&grains, &index, &settings);
+        assert_eq!(groups.len(), 1);
+        assert_eq!((groups[0].representative, groups[0].duplicates.clone()), (1, vec![2]));
+
+        settings.across_files = true;
+        let groups = find_duplicates(&grains, &index, &settings);
+        assert_eq!(groups.len(), 1);
+        let mut duplicates = groups[0].duplicates.clone();
+        duplicates.sort();
+        assert_eq!((groups[0].representative, duplicates), (1, vec![2, 3]));
+    }
+}
@@ -0,0 +1,180 @@
+// File: embedding.rs
+// This file has the corpus embedding, which places each grain in a 2-D or 3-D space for browsing the corpus.
+// The coordinates come from principal component analysis over the normalized features, and the projection
+// is saved so that grains extracted later are placed in the same space.
+
+use crate::grain_extractor::GrainEntry;
+use crate::similarity::Similarity;
+use serde::{Serialize, Deserialize};
+
+// The Jacobi eigenvalue iteration stops once the off-diagonal entries are this small
+const JACOBI_TOLERANCE: f64 = 1e-12;
+const JACOBI_MAX_SWEEPS: usize = 100;
+
+/// A linear projection of normalized grain features onto the principal components of a corpus
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Projection {
+    /// The feature normalization. The normalized features are scaled by the square root of their weights
+    /// before they are projected, so that heavily weighted features count for more.
+    pub similarity: Similarity,
+    /// The mean of the weighted feature vectors of the corpus
+    pub mean: Vec<f64>,
+    /// The principal components, one per dimension, each a unit vector
+    pub components: Vec<Vec<f64>>,
+    /// The fraction of the corpus variance along each principal component
+    pub explained_variance: Vec<f64>,
+}
+
+impl Projection {
+    /// Finds the principal components of weighted feature vectors (from `Similarity::weighted`), and keeps
+    /// the first `dimensions` of them. Returns `None` if there are no vectors.
+    pub fn fit(vectors: &[Vec<f64>], dimensions: usize, similarity: &Similarity) -> Option<Projection> {
+        let n = vectors.first()?.len();
+        let count = vectors.len() as f64;
+        let mut mean: Vec<f64> = vec![0.0; n];
+        for v in vectors.iter() {
+            for (m, x) in mean.iter_mut().zip(v.iter()) {
+                *m += x / count;
+            }
+        }
+        let mut covariance: Vec<Vec<f64>> = vec![vec![0.0; n]; n];
+        for v in vectors.iter() {
+            for i in 0..n {
+                let di = v[i] - mean[i];
+                for j in i..n {
+                    covariance[i][j] += di * (v[j] - mean[j]) / count;
+                }
+            }
+        }
+        let upper = covariance.clone();
+        for (i, row) in covariance.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate().take(i) {
+                *x = upper[j][i];
+            }
+        }
+
+        let (eigenvalues, eigenvectors) = symmetric_eigen(&covariance);
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by(|&a, &b| eigenvalues[b].total_cmp(&eigenvalues[a]));
+        let total: f64 = eigenvalues.iter().map(|x| x.max(0.0)).sum();
+        let mut components: Vec<Vec<f64>> = Vec::with_capacity(dimensions);
+        let mut explained_variance: Vec<f64> = Vec::with_capacity(dimensions);
+        for &k in order.iter().take(dimensions) {
+            let mut component: Vec<f64> = (0..n).map(|i| eigenvectors[i][k]).collect();
+            // The sign of an eigenvector is arbitrary, so the largest entry is made positive to keep the
+            // axes from flipping when the corpus changes a little
+            let largest = component.iter().copied().max_by(|a, b| a.abs().total_cmp(&b.abs())).unwrap_or(0.0);
+            if largest < 0.0 {
+                component.iter_mut().for_each(|x| *x = -*x);
+            }
+            components.push(component);
+            explained_variance.push(if total > 0.0 { eigenvalues[k].max(0.0) / total } else { 0.0 });
+        }
+        // With fewer features than dimensions, the extra coordinates are always 0
+        while components.len() < dimensions {
+            components.push(vec![0.0; n]);
+            explained_variance.push(0.0);
+        }
+        Some(Projection{similarity: similarity.clone(), mean, components, explained_variance})
+    }
+
+    /// Projects a weighted feature vector. Returns one coordinate per dimension.
+    pub fn project_vector(&self, vector: &[f64]) -> Vec<f64> {
+        self.components.iter().map(|component| {
+            component.iter().zip(vector.iter().zip(self.mean.iter())).map(|(c, (x, m))| c * (x - m)).sum()
+        }).collect()
+    }
+
+    /// Projects a grain. Returns one coordinate per dimension.
+    pub fn project(&self, grain: &GrainEntry) -> Vec<f64> {
+        self.project_vector(&self.similarity.weighted(&self.similarity.features(grain)))
+    }
+}
+
+/// Finds the eigenvalues and eigenvectors of a symmetric matrix with the cyclic Jacobi method.
+/// Returns the eigenvalues and a matrix with the eigenvectors as columns, in the same order.
+fn symmetric_eigen(matrix: &[Vec<f64>]) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = matrix.len();
+    let mut a: Vec<Vec<f64>> = matrix.to_vec();
+    let mut v: Vec<Vec<f64>> = (0..n).map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect()).collect();
+    let scale: f64 = a.iter().flatten().map(|x| x * x).sum::<f64>().max(f64::MIN_POSITIVE);
+    for _ in 0..JACOBI_MAX_SWEEPS {
+        let off_diagonal: f64 = (0..n).flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j))).map(|(i, j)| a[i][j] * a[i][j]).sum();
+        if off_diagonal <= JACOBI_TOLERANCE * scale {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q] == 0.0 {
+                    continue;
+                }
+                // Rotate rows and columns p and q so that a[p][q] becomes 0
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for row in a.iter_mut().chain(v.iter_mut()) {
+                    let (xp, xq) = (row[p], row[q]);
+                    row[p] = c * xp - s * xq;
+                    row[q] = s * xp + c * xq;
+                }
+                let (top, bottom) = a.split_at_mut(q);
+                for (xp, xq) in top[p].iter_mut().zip(bottom[0].iter_mut()) {
+                    let (yp, yq) = (*xp, *xq);
+                    *xp = c * yp - s * yq;
+                    *xq = s * yp + c * yq;
+                }
+            }
+        }
+    }
+    ((0..n).map(|i| a[i][i]).collect(), v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::{CorpusStats, DEFAULT_FEATURES, SimilaritySettings};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn jacobi_finds_the_eigenpairs_of_a_known_matrix() {
+        let matrix = vec![vec![2.0, -1.0, 0.0], vec![-1.0, 2.0, -1.0], vec![0.0, -1.0, 2.0]];
+        let (eigenvalues, eigenvectors) = symmetric_eigen(&matrix);
+        let r = f64::sqrt(2.0);
+        let expected = [
+            (2.0 - r, [0.5, r / 2.0, 0.5]),
+            (2.0, [1.0 / r, 0.0, -1.0 / r]),
+            (2.0 + r, [0.5, -r / 2.0, 0.5])
+        ];
+        for (value, vector) in expected.iter() {
+            let k = eigenvalues.iter().position(|x| (x - value).abs() < 1e-9)
+                .unwrap_or_else(|| panic!("eigenvalue {} is missing from {:?}", value, eigenvalues));
+            let found: Vec<f64> = (0..3).map(|i| eigenvectors[i][k]).collect();
+            // The sign of an eigenvector is arbitrary
+            let dot: f64 = found.iter().zip(vector.iter()).map(|(a, b)| a * b).sum();
+            assert!((dot.abs() - 1.0).abs() < 1e-9, "eigenvector {:?} for {} should be {:?}", found, value, vector);
+        }
+    }
+
+    #[test]
+    fn first_component_follows_the_long_axis() {
+        let mut settings = SimilaritySettings::default();
+        for feature in DEFAULT_FEATURES.iter().skip(2) {
+            settings.weights.insert(String::from(*feature), 0.0);
+        }
+        let similarity = Similarity::new(&settings, &CorpusStats::default());
+        // A cloud that is ten times longer along (cos 30°, sin 30°) than across it
+        let axis = [f64::cos(std::f64::consts::PI / 6.0), f64::sin(std::f64::consts::PI / 6.0)];
+        let mut rng = StdRng::seed_from_u64(1);
+        let vectors: Vec<Vec<f64>> = (0..500).map(|_| {
+            let (along, across) = (rng.gen_range(-10.0..10.0), rng.gen_range(-1.0..1.0));
+            vec![3.0 + along * axis[0] - across * axis[1], -2.0 + along * axis[1] + across * axis[0]]
+        }).collect();
+
+        let projection = Projection::fit(&vectors, 2, &similarity).unwrap();
+        let first = &projection.components[0];
+        assert!((first[0] - axis[0]).abs() < 0.01 && (first[1] - axis[1]).abs() < 0.01, "first component {:?}", first);
+        assert!(projection.explained_variance[0] > 0.95);
+        assert!((projection.explained_variance.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+}
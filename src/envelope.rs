@@ -0,0 +1,160 @@
+// File: envelope.rs
+// This file has the time-domain descriptors of a grain, which describe its level and how its amplitude
+// envelope evolves: RMS, peak, crest factor, zero-crossing rate, attack time, decay slope,
+// temporal centroid and envelope flatness. They are found from the grain before it is windowed.
+
+// The length of the frames of the amplitude envelope, in seconds. The frames overlap by half.
+const ENVELOPE_FRAME_SECONDS: f64 = 0.005;
+
+// The fractions of the envelope maximum where the attack starts and ends
+const ATTACK_START: f64 = 0.1;
+const ATTACK_END: f64 = 0.9;
+
+// The envelope is kept above this fraction of its maximum (-120 dB) when it is converted to dB
+const MIN_ENVELOPE_RATIO: f64 = 1e-6;
+
+// The smallest envelope value used when taking logarithms
+const MIN_ENVELOPE: f64 = 1e-20;
+
+/// The time-domain descriptors of a grain
+#[derive(Debug, Clone, Copy)]
+pub struct TemporalFeatures {
+    /// The RMS level
+    pub rms: f64,
+    /// The largest absolute sample value
+    pub peak: f64,
+    /// The peak divided by the RMS level. It is 1.0 for a square wave, about 1.41 for a sine wave, and high for impulsive sounds.
+    pub crest_factor: f64,
+    /// The number of zero crossings per second
+    pub zero_crossing_rate: f64,
+    /// The time in seconds for the envelope to rise from 10% to 90% of its maximum
+    pub attack_time: f64,
+    /// The slope of the envelope in dB per second from the end of the attack to the end of the grain.
+    /// It is NaN if the attack ends at the end of the grain.
+    pub decay_slope: f64,
+    /// The center of gravity of the envelope, in seconds from the start of the grain
+    pub temporal_centroid: f64,
+    /// The geometric mean of the envelope divided by its arithmetic mean. It is close to 1.0 for a steady sound,
+    /// and close to 0.0 for a sound with a short burst of energy.
+    pub envelope_flatness: f64,
+}
+
+/// Gets the amplitude envelope of a grain as the RMS level of overlapping frames.
+/// Returns the time of the center of each frame in seconds, and the envelope.
+fn amplitude_envelope(grain: &[f64], sample_rate: u32) -> (Vec<f64>, Vec<f64>) {
+    let frame_size = ((ENVELOPE_FRAME_SECONDS * sample_rate as f64).round() as usize).clamp(1, grain.len().max(1));
+    let hop = (frame_size / 2).max(1);
+    let mut times: Vec<f64> = Vec::new();
+    let mut envelope: Vec<f64> = Vec::new();
+    let mut start = 0;
+    while start + frame_size <= grain.len() {
+        times.push((start as f64 + frame_size as f64 / 2.0) / sample_rate as f64);
+        envelope.push(aus::analysis::energy(&grain[start..start + frame_size]));
+        start += hop;
+    }
+    (times, envelope)
+}
+
+/// Finds the time-domain descriptors of a grain
+pub fn temporal_features(grain: &[f64], sample_rate: u32) -> TemporalFeatures {
+    let rms = aus::analysis::energy(grain);
+    let peak = grain.iter().fold(0.0, |max: f64, x| max.max(x.abs()));
+    let crest_factor = if rms > 0.0 { peak / rms } else { f64::NAN };
+    let zero_crossing_rate = aus::analysis::zero_crossing_rate(grain, sample_rate);
+
+    let (times, envelope) = amplitude_envelope(grain, sample_rate);
+    let (max_index, max_level) = envelope.iter().copied().enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.0));
+    if max_level <= 0.0 {
+        return TemporalFeatures{
+            rms,
+            peak,
+            crest_factor,
+            zero_crossing_rate,
+            attack_time: f64::NAN,
+            decay_slope: f64::NAN,
+            temporal_centroid: f64::NAN,
+            envelope_flatness: f64::NAN
+        };
+    }
+
+    let first_above = |fraction: f64| envelope.iter().position(|&x| x >= fraction * max_level).unwrap_or(max_index);
+    let attack_end = first_above(ATTACK_END);
+    let attack_time = times[attack_end] - times[first_above(ATTACK_START)];
+
+    // The decay slope is the least squares fit of a line to the envelope in dB after the attack. Starting at the
+    // end of the attack rather than at the maximum keeps the small ripples of a steady sound from deciding the slope.
+    let decay_times = &times[attack_end..];
+    let decay_levels: Vec<f64> = envelope[attack_end..].iter().map(|x| 20.0 * x.max(max_level * MIN_ENVELOPE_RATIO).log10()).collect();
+    let decay_slope = if decay_times.len() < 2 {
+        f64::NAN
+    } else {
+        let n = decay_times.len() as f64;
+        let mean_time = decay_times.iter().sum::<f64>() / n;
+        let mean_level = decay_levels.iter().sum::<f64>() / n;
+        let covariance: f64 = decay_times.iter().zip(decay_levels.iter()).map(|(t, l)| (t - mean_time) * (l - mean_level)).sum();
+        let variance: f64 = decay_times.iter().map(|t| (t - mean_time) * (t - mean_time)).sum();
+        covariance / variance
+    };
+
+    let envelope_sum: f64 = envelope.iter().sum();
+    let temporal_centroid = times.iter().zip(envelope.iter()).map(|(t, x)| t * x).sum::<f64>() / envelope_sum;
+    let log_mean = envelope.iter().map(|x| x.max(MIN_ENVELOPE).ln()).sum::<f64>() / envelope.len() as f64;
+    let envelope_flatness = log_mean.exp() / (envelope_sum / envelope.len() as f64);
+
+    TemporalFeatures{
+        rms,
+        peak,
+        crest_factor,
+        zero_crossing_rate,
+        attack_time,
+        decay_slope,
+        temporal_centroid,
+        envelope_flatness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn sine(frequency: f64, seconds: f64) -> Vec<f64> {
+        (0..(seconds * SAMPLE_RATE as f64) as usize).map(|n| (2.0 * PI * frequency * n as f64 / SAMPLE_RATE as f64).sin()).collect()
+    }
+
+    #[test]
+    fn sine_has_the_crest_factor_of_a_sine() {
+        let features = temporal_features(&sine(441.0, 0.5), SAMPLE_RATE);
+        assert!((features.crest_factor - f64::sqrt(2.0)).abs() < 1e-3, "crest factor {}", features.crest_factor);
+        assert!((features.zero_crossing_rate - 882.0).abs() < 5.0, "zero crossing rate {}", features.zero_crossing_rate);
+        assert!(features.envelope_flatness > 0.99);
+    }
+
+    #[test]
+    fn decaying_sound_has_the_expected_attack_and_decay() {
+        // A linear attack of 20 ms, followed by an exponential decay with a time constant of 50 ms
+        let (attack, time_constant) = (0.02, 0.05);
+        let grain: Vec<f64> = sine(1000.0, 0.3).iter().enumerate().map(|(n, x)| {
+            let t = n as f64 / SAMPLE_RATE as f64;
+            x * if t < attack { t / attack } else { (-(t - attack) / time_constant).exp() }
+        }).collect();
+        let features = temporal_features(&grain, SAMPLE_RATE);
+
+        // The envelope rises from 10% to 90% in 80% of the attack, give or take a frame hop
+        let expected_attack = (ATTACK_END - ATTACK_START) * attack;
+        assert!((features.attack_time - expected_attack).abs() < ENVELOPE_FRAME_SECONDS / 2.0, "attack time {}", features.attack_time);
+        let expected_slope = -20.0 / (time_constant * f64::ln(10.0));
+        assert!((features.decay_slope - expected_slope).abs() < 0.05 * expected_slope.abs(), "decay slope {} should be {}", features.decay_slope, expected_slope);
+        assert!(features.temporal_centroid < 0.1);
+    }
+
+    #[test]
+    fn silence_has_no_envelope_features() {
+        let features = temporal_features(&[0.0; 1000], SAMPLE_RATE);
+        assert!(features.attack_time.is_nan() && features.decay_slope.is_nan() && features.crest_factor.is_nan());
+    }
+}
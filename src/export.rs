@@ -0,0 +1,69 @@
+// File: export.rs
+// This file has grain export to CSV and JSON files.
+
+use rusqlite::types::Value;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// The file format of an export
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json
+}
+
+impl ExportFormat {
+    /// Guesses the export format from a file extension. Anything other than `.json` is exported as CSV.
+    pub fn from_path(path: &str) -> ExportFormat {
+        if path.to_lowercase().ends_with(".json") {
+            ExportFormat::Json
+        } else {
+            ExportFormat::Csv
+        }
+    }
+}
+
+/// Formats a database value as a CSV field. Text is quoted if it contains a delimiter, quote or line break.
+fn csv_field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(x) => x.to_string(),
+        Value::Real(x) => x.to_string(),
+        Value::Text(x) if x.contains([',', '"', '\n', '\r']) => format!("\"{}\"", x.replace('"', "\"\"")),
+        Value::Text(x) => x.clone(),
+        Value::Blob(_) => String::new()
+    }
+}
+
+/// Converts a database value to a JSON value
+fn json_value(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(x) => serde_json::Value::from(*x),
+        Value::Real(x) => serde_json::Value::from(*x),
+        Value::Text(x) => serde_json::Value::from(x.as_str()),
+        Value::Blob(_) => serde_json::Value::Null
+    }
+}
+
+/// Writes rows from the database to a file. CSV files have a header row with the column names,
+/// and JSON files have an array with one object per row.
+pub fn export_rows(path: &str, format: ExportFormat, columns: &[String], rows: &[Vec<Value>]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        ExportFormat::Csv => {
+            writeln!(writer, "{}", columns.join(","))?;
+            for row in rows.iter() {
+                writeln!(writer, "{}", row.iter().map(csv_field).collect::<Vec<String>>().join(","))?;
+            }
+        },
+        ExportFormat::Json => {
+            let objects: Vec<serde_json::Map<String, serde_json::Value>> = rows.iter().map(|row| {
+                columns.iter().cloned().zip(row.iter().map(json_value)).collect()
+            }).collect();
+            serde_json::to_writer_pretty(&mut writer, &objects)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()
+}
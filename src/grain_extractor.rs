@@ -2,16 +2,30 @@
 // This file contains functionality for grain extraction and analysis.
 
 use aus::{
-    analysis::Analysis,
-    grain, 
-    spectrum::{rfft, complex_to_polar_rfft}
+    analysis::mel::{MelFilterbank, mfcc_spectrum},
+    spectrum::{rfft, rfftfreq, complex_to_polar_rfft}
 };
 use biquad::*;
-use crate::{sqlite, io};
+use crate::{sqlite, envelope, harmonics, io, knn, labels, onset, stft, tags};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Instant;
 use threadpool::ThreadPool;
 
+// The lower edge of the mel filterbank. The upper edge is the Nyquist frequency.
+const MEL_FREQ_LOW: f64 = 20.0;
+
+// The frame where grain extraction starts in each file, unless the grain profile sets it
+const INITIAL_OFFSET: usize = 20000;
+
+// The largest FFT size that grains can be analyzed with
+pub const MAX_FFT_SIZE: usize = 65536;
+
 
 #[derive(Debug, Clone)]
 pub enum GrainError {
@@ -19,6 +33,60 @@ pub enum GrainError {
     GrainTooLong(String)
 }
 
+impl fmt::Display for GrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrainError::GrainTooShort(msg) => write!(f, "{}", msg),
+            GrainError::GrainTooLong(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+/// The reason a grain was screened out during analysis
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectionReason {
+    /// More than 12.5% of the high-passed grain in a row is near silent. Either the grain
+    /// contains silence, or its content is almost entirely below the high-pass cutoff.
+    NearSilence,
+    /// The high-passed grain has a run of 50 samples that are effectively zero
+    Dropout,
+    /// The grain has no energy after level adjustment
+    ZeroEnergy
+}
+
+impl RejectionReason {
+    /// Gets the name of the reason, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::NearSilence => "near_silence",
+            RejectionReason::Dropout => "dropout",
+            RejectionReason::ZeroEnergy => "zero_energy"
+        }
+    }
+}
+
+/// A grain that was screened out during analysis
+#[derive(Debug, Clone)]
+pub struct RejectedGrain {
+    pub file: String,
+    pub start_frame: usize,
+    pub end_frame: usize,
+    pub reason: RejectionReason,
+}
+
+/// A chunk of a mono audio file. Neighboring chunks overlap, but the ranges where their grains
+/// may start do not, so each grain is extracted from exactly one chunk.
+#[derive(Debug, Clone)]
+pub struct AudioChunk {
+    pub file: String,
+    pub sample_rate: u32,
+    /// The position of the first sample of the chunk in the file
+    pub offset: usize,
+    /// The file positions where grains extracted from this chunk may start
+    pub grain_starts: Range<usize>,
+    pub samples: Vec<f64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct GrainEntry {
     pub file: String,
@@ -42,35 +110,86 @@ pub struct GrainEntry {
     pub spectral_slope_0_1_khz: f64,
     pub spectral_slope_1_5_khz: f64,
     pub spectral_slope_0_5_khz: f64,
-    pub spectral_variance: f64
+    pub spectral_variance: f64,
+    /// The probability that the grain is pitched, from pYIN
+    pub voicing_probability: f64,
+    pub hnr: f64,
+    pub inharmonicity: f64,
+    pub odd_even_ratio: f64,
+    pub tristimulus_1: f64,
+    pub tristimulus_2: f64,
+    pub tristimulus_3: f64,
+    pub rms: f64,
+    pub peak: f64,
+    pub crest_factor: f64,
+    pub zero_crossing_rate: f64,
+    pub attack_time: f64,
+    pub decay_slope: f64,
+    pub temporal_centroid: f64,
+    pub envelope_flatness: f64,
+    /// Statistics of the spectral descriptors over the STFT frames of the grain
+    pub frame_statistics: stft::FrameStatistics,
+    pub mel_bands: Vec<f64>,
+    pub mfccs: Vec<f64>,
+    /// The frequencies and magnitudes of the strongest spectral peaks, strongest first
+    pub spectral_peaks: Vec<(f64, f64)>,
+    /// The tags added by the tagging rules
+    pub tags: Vec<String>
 }
 
-/// Computes a basic similarity measurement between two grains. Measurement is between 0.0 (no similarity) and 1.0 (identity).
-pub fn similarity(grain1: &GrainEntry, grain2: &GrainEntry) -> f64 {
-    let mut similarity = 0.0;
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_centroid - grain2.spectral_centroid) / grain1.spectral_centroid), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_entropy - grain2.spectral_entropy) / grain1.spectral_entropy), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_flatness - grain2.spectral_flatness) / grain1.spectral_flatness), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_kurtosis - grain2.spectral_kurtosis) / grain1.spectral_kurtosis), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_roll_off_50 - grain2.spectral_roll_off_50) / grain1.spectral_roll_off_50), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_roll_off_75 - grain2.spectral_roll_off_75) / grain1.spectral_roll_off_75), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_roll_off_90 - grain2.spectral_roll_off_90) / grain1.spectral_roll_off_90), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_roll_off_95 - grain2.spectral_roll_off_95) / grain1.spectral_roll_off_95), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_skewness - grain2.spectral_skewness) / grain1.spectral_skewness), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_slope - grain2.spectral_slope) / grain1.spectral_slope), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_slope_0_1_khz - grain2.spectral_slope_0_1_khz) / grain1.spectral_slope_0_1_khz), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_slope_1_5_khz - grain2.spectral_slope_1_5_khz) / grain1.spectral_slope_1_5_khz), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_slope_0_5_khz - grain2.spectral_slope_0_5_khz) / grain1.spectral_slope_0_5_khz), 0.0);
-    similarity += f64::max(1.0 - f64::abs((grain1.spectral_variance - grain2.spectral_variance) / grain1.spectral_variance), 0.0);
-    similarity / 14.0
+impl GrainEntry {
+    /// Gets a feature by its column name in the grains table. Returns `None` if there is no such numeric column.
+    pub fn feature(&self, column: &str) -> Option<f64> {
+        let value = match column {
+            "start_frame" => self.start_frame as f64,
+            "end_frame" => self.end_frame as f64,
+            "length" => (self.end_frame - self.start_frame) as f64,
+            "sample_rate" => self.sample_rate as f64,
+            "grain_duration" => self.grain_duration,
+            "frequency" => self.pitch_estimation,
+            "midi" => self.midi,
+            "energy" => self.energy,
+            "spectral_centroid" => self.spectral_centroid,
+            "spectral_entropy" => self.spectral_entropy,
+            "spectral_flatness" => self.spectral_flatness,
+            "spectral_kurtosis" => self.spectral_kurtosis,
+            "spectral_roll_off_50" => self.spectral_roll_off_50,
+            "spectral_roll_off_75" => self.spectral_roll_off_75,
+            "spectral_roll_off_90" => self.spectral_roll_off_90,
+            "spectral_roll_off_95" => self.spectral_roll_off_95,
+            "spectral_skewness" => self.spectral_skewness,
+            "spectral_slope" => self.spectral_slope,
+            "spectral_slope_0_1_khz" => self.spectral_slope_0_1_khz,
+            "spectral_slope_1_5_khz" => self.spectral_slope_1_5_khz,
+            "spectral_slope_0_5_khz" => self.spectral_slope_0_5_khz,
+            "spectral_variance" => self.spectral_variance,
+            "voicing_probability" => self.voicing_probability,
+            "hnr" => self.hnr,
+            "inharmonicity" => self.inharmonicity,
+            "odd_even_ratio" => self.odd_even_ratio,
+            "tristimulus_1" => self.tristimulus_1,
+            "tristimulus_2" => self.tristimulus_2,
+            "tristimulus_3" => self.tristimulus_3,
+            "rms" => self.rms,
+            "peak" => self.peak,
+            "crest_factor" => self.crest_factor,
+            "zero_crossing_rate" => self.zero_crossing_rate,
+            "attack_time" => self.attack_time,
+            "decay_slope" => self.decay_slope,
+            "temporal_centroid" => self.temporal_centroid,
+            "envelope_flatness" => self.envelope_flatness,
+            _ => return self.frame_statistics.feature(column)
+        };
+        Some(value)
+    }
 }
 
 /// Checks to see if a grain has more than N consecutive zero samples in it.
 /// This is useful for screening out silent grains.
-pub fn check_zeros(grain: &Vec<f64>, num_consecutive_zeros: usize, effective_zero: f64) -> bool {
+pub fn check_zeros(grain: &[f64], num_consecutive_zeros: usize, effective_zero: f64) -> bool {
     let mut consecutive: usize = 0;
-    for i in 0..grain.len() {
-        if grain[i].abs() < effective_zero {
+    for sample in grain.iter() {
+        if sample.abs() < effective_zero {
             consecutive += 1;
             if consecutive >= num_consecutive_zeros {
                 return true;
@@ -82,10 +201,24 @@ pub fn check_zeros(grain: &Vec<f64>, num_consecutive_zeros: usize, effective_zer
     false
 }
 
+/// Applies a window to a grain. The first half of the window fades in the start of the grain,
+/// and the second half fades out the end of the grain. If the window is as long as the grain,
+/// this is the same as multiplying the grain by the window. The window cannot be longer than the grain.
+pub fn apply_window(grain: &mut [f64], window: &[f64]) {
+    let half = window.len() / 2;
+    let offset = grain.len() - window.len();
+    for (sample, coef) in grain[..half].iter_mut().zip(window[..half].iter()) {
+        *sample *= coef;
+    }
+    for (sample, coef) in grain[offset + half..].iter_mut().zip(window[half..].iter()) {
+        *sample *= coef;
+    }
+}
+
 /// Extracts grains from an audio sequence.
 /// You specify the grain size and spacing between grain onsets. 
 /// If you don't want grain overlap, the spacing must be at least as large as the grain size.
-pub fn extract_grain_frames(audio: &Vec<f64>, grain_size: usize, grain_spacing: usize, initial_offset: usize) -> Vec<(usize, usize)> {
+pub fn extract_grain_frames(audio: &[f64], grain_size: usize, grain_spacing: usize, initial_offset: usize) -> Vec<(usize, usize)> {
     let mut grains: Vec<(usize, usize)> = Vec::new();
     let mut i = initial_offset;
     while i + grain_size < audio.len() {
@@ -95,11 +228,204 @@ pub fn extract_grain_frames(audio: &Vec<f64>, grain_size: usize, grain_spacing:
     grains
 }
 
+/// Extracts grains from an audio sequence, starting each grain on a detected onset.
+/// Onsets closer than `min_spacing` frames to the previous onset are ignored, and onsets
+/// before `initial_offset` are skipped.
+pub fn extract_onset_grain_frames(audio: &[f64], grain_size: usize, min_spacing: usize, initial_offset: usize, novelty_function: onset::NoveltyFunction, threshold: f64) -> Vec<(usize, usize)> {
+    let mut grains: Vec<(usize, usize)> = Vec::new();
+    for onset in onset::detect_onsets(audio, novelty_function, threshold, min_spacing) {
+        if onset >= initial_offset && onset + grain_size < audio.len() {
+            grains.push((onset, onset + grain_size));
+        }
+    }
+    grains
+}
+
+/// Extracts grains from an audio sequence using the segmentation mode of a grain profile
+pub fn segment_grain_frames(audio: &[f64], profile: &io::GrainProfile, initial_offset: usize) -> Vec<(usize, usize)> {
+    match profile.segmentation {
+        io::SegmentationMode::FixedHop => extract_grain_frames(audio, profile.grain_size, profile.grain_spacing, initial_offset),
+        io::SegmentationMode::SpectralFlux => extract_onset_grain_frames(audio, profile.grain_size, profile.grain_spacing, initial_offset, onset::NoveltyFunction::SpectralFlux, profile.onset_threshold),
+        io::SegmentationMode::EnergyNovelty => extract_onset_grain_frames(audio, profile.grain_size, profile.grain_spacing, initial_offset, onset::NoveltyFunction::Energy, profile.onset_threshold)
+    }
+}
+
+/// Reads a mono audio file as a sequence of chunks of `chunk_size` frames for faster processing. Each chunk
+/// is extended by `overlap` frames on both sides, so that grains which straddle a chunk boundary are not lost,
+/// and so that onset detection and filtering have some context at the chunk edges.
+/// The file is decoded as the chunks are read, so only about one chunk of audio is buffered at a time.
+pub struct ChunkReader {
+    file: String,
+    stream: io::AudioStream,
+    chunk_size: usize,
+    overlap: usize,
+    /// The decoded audio that later chunks still need
+    buffer: Vec<f64>,
+    /// The file position of the first sample in the buffer
+    buffer_offset: usize,
+    /// The file position where grains in the next chunk may start
+    next_start: usize,
+    end_of_file: bool,
+    done: bool,
+}
+
+impl ChunkReader {
+    pub fn new(file: &str, stream: io::AudioStream, chunk_size: usize, overlap: usize) -> ChunkReader {
+        ChunkReader{
+            file: file.to_string(),
+            stream,
+            chunk_size: usize::max(chunk_size, 1),
+            overlap,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+            next_start: 0,
+            end_of_file: false,
+            done: false
+        }
+    }
+}
+
+impl Iterator for ChunkReader {
+    type Item = Result<AudioChunk, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        // Decode until the buffer reaches the end of the next chunk's overlap
+        let target_end = self.next_start + self.chunk_size + self.overlap;
+        while !self.end_of_file && self.buffer_offset + self.buffer.len() < target_end {
+            match self.stream.next_block() {
+                Some(Ok(block)) => self.buffer.extend(block),
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(err));
+                },
+                None => self.end_of_file = true
+            }
+        }
+
+        let buffer_end = self.buffer_offset + self.buffer.len();
+        if self.next_start >= buffer_end {
+            self.done = true;
+            return None;
+        }
+        let start_idx = self.next_start;
+        let end_idx = usize::min(buffer_end, start_idx + self.chunk_size);
+        let offset = start_idx.saturating_sub(self.overlap);
+        let samples = self.buffer[offset - self.buffer_offset..usize::min(buffer_end, end_idx + self.overlap) - self.buffer_offset].to_vec();
+        self.next_start = end_idx;
+
+        // Release the audio that no later chunk overlaps
+        let keep_from = self.next_start.saturating_sub(self.overlap);
+        self.buffer.drain(..keep_from - self.buffer_offset);
+        self.buffer_offset = keep_from;
+
+        Some(Ok(AudioChunk{
+            file: self.file.clone(),
+            sample_rate: self.stream.sample_rate,
+            offset,
+            grain_starts: start_idx..end_idx,
+            samples
+        }))
+    }
+}
+
+/// Extracts grains from an audio chunk using a grain profile. Only grains that start in the chunk's
+/// `grain_starts` range are returned, and the frames are relative to the start of the chunk.
+/// The `initial_offset` is the first file position where a grain may start. For fixed-hop segmentation,
+/// grains start at the same positions they would if the file were not split into chunks.
+pub fn chunk_grain_frames(chunk: &AudioChunk, profile: &io::GrainProfile, initial_offset: usize) -> Vec<(usize, usize)> {
+    let local_offset = match profile.segmentation {
+        io::SegmentationMode::FixedHop if chunk.offset > initial_offset && profile.grain_spacing > 0 => {
+            (profile.grain_spacing - (chunk.offset - initial_offset) % profile.grain_spacing) % profile.grain_spacing
+        },
+        _ => initial_offset.saturating_sub(chunk.offset)
+    };
+    segment_grain_frames(&chunk.samples, profile, local_offset)
+        .into_iter()
+        .filter(|(start, _)| chunk.grain_starts.contains(&(chunk.offset + start)))
+        .collect()
+}
+
+/// The default pitch estimation range, in Hz
+pub const DEFAULT_PITCH_MIN: f64 = 50.0;
+pub const DEFAULT_PITCH_MAX: f64 = 800.0;
+
+/// The default voicing probability below which a grain is considered unpitched
+pub const DEFAULT_VOICING_THRESHOLD: f64 = 0.25;
+
+/// The pitch estimation settings of a grain profile
+#[derive(Debug, Clone, Copy)]
+pub struct PitchSettings {
+    /// The lowest frequency that pYIN looks for, in Hz
+    pub f_min: f64,
+    /// The highest frequency that pYIN looks for, in Hz
+    pub f_max: f64,
+    /// The voicing probability below which a grain gets no pitch
+    pub voicing_threshold: f64,
+}
+
+/// Estimates the pitch of a grain with pYIN. Returns the median frequency of the voiced pYIN frames, and the
+/// mean voicing probability of the frames. Only the frames that overlap the grain by at least half are used, since
+/// the frames at the edges of the grain are mostly padding. Averaging keeps a single spuriously voiced frame from
+/// giving a noise grain a pitch. The frequency is NaN if the voicing probability is below the voicing threshold,
+/// or if no frame is voiced.
+pub fn estimate_pitch(grain: &[f64], sample_rate: u32, settings: &PitchSettings) -> (f64, f64) {
+    let frame_length = usize::min(grain.len(), 14000);
+    let (times, frequencies, _, probabilities) = aus::analysis::pyin_pitch_estimator(grain, sample_rate, settings.f_min, settings.f_max, frame_length);
+    // The frames are centered on their times, so a frame overlaps the grain by at least half if its center
+    // is at least a quarter frame inside the grain
+    let margin = frame_length as f64 / 4.0;
+    let inside: Vec<usize> = (0..times.len())
+        .filter(|&i| {
+            let center = times[i] * sample_rate as f64;
+            center >= margin && center <= grain.len() as f64 - margin
+        })
+        .collect();
+    let frames: Vec<usize> = if inside.is_empty() { (0..times.len()).collect() } else { inside };
+
+    let frame_probabilities: Vec<f64> = frames.iter().map(|&i| probabilities[i]).filter(|x| x.is_finite()).collect();
+    let voicing_probability = if frame_probabilities.is_empty() {
+        0.0
+    } else {
+        frame_probabilities.iter().sum::<f64>() / frame_probabilities.len() as f64
+    };
+    // Unvoiced frames have a NaN frequency
+    let mut frequencies: Vec<f64> = frames.iter().map(|&i| frequencies[i]).filter(|x| !x.is_nan()).collect();
+    if frequencies.is_empty() || voicing_probability < settings.voicing_threshold {
+        return (f64::NAN, voicing_probability);
+    }
+    frequencies.sort_unstable_by(|a, b| a.total_cmp(b));
+    (frequencies[frequencies.len() / 2], voicing_probability)
+}
+
+/// Gets the FFT size for analyzing grains of a given size.
+/// The FFT size has to be at least as large as the grain size.
+pub fn fft_size_for_grain(grain_size: usize) -> usize {
+    let mut fft_size: usize = 512;
+    while fft_size < grain_size {
+        fft_size *= 2;
+    }
+    fft_size
+}
+
 /// Analyzes grains
 /// Note: the fft size must be at least as large as the grain size!
-pub fn analyze_grains(file_name: &str, audio: &Vec<f64>, grain_frames: Vec<(usize, usize)>, window_type: aus::WindowType, max_window_length: usize, sample_rate: u32, fft_size: usize) -> Result<Vec<GrainEntry>, GrainError> {
+/// The mel band energies are computed with a `num_mel_bands` filterbank from 20 Hz to the Nyquist frequency,
+/// and the first `num_mfccs` MFCCs are derived from them.
+/// The pitch is estimated with `pitch`, and grains that are not pitched get a NaN frequency and MIDI note,
+/// and NaN harmonic descriptors. The `num_spectral_peaks` strongest spectral peaks are kept.
+/// The time-domain descriptors are found from the grain before it is windowed. If `stft` is set, the grain is
+/// also analyzed with an STFT, and the spectral descriptors of its frames are summarized.
+/// Returns the analyzed grains, and the grains that were screened out because they are silent.
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_grains(file_name: &str, audio: &[f64], grain_frames: Vec<(usize, usize)>, window_type: aus::WindowType, max_window_length: usize, sample_rate: u32, fft_size: usize, num_mel_bands: usize, num_mfccs: usize, num_spectral_peaks: usize, pitch: &PitchSettings, stft: Option<&stft::StftSettings>) -> Result<(Vec<GrainEntry>, Vec<RejectedGrain>), GrainError> {
     let mut analysis_vec: Vec<GrainEntry> = Vec::with_capacity(grain_frames.len());
-    let mut grains: Vec<Vec<f64>> = Vec::with_capacity(grain_frames.len());
+    let mut rejected_vec: Vec<RejectedGrain> = Vec::new();
+    // Each grain keeps its own frame span, so that the spans stay aligned with the grains that survive screening
+    let mut grains: Vec<((usize, usize), Vec<f64>)> = Vec::with_capacity(grain_frames.len());
     let mut filtered_audio = vec![0.0; audio.len()];
 
     // Filter the audio for checking super low frequencies.
@@ -115,65 +441,90 @@ pub fn analyze_grains(file_name: &str, audio: &Vec<f64>, grain_frames: Vec<(usiz
         filtered_audio[i] = filter.run(audio[i]);
     }
 
-    // For pyin
-    const F_MIN: f64 = 50.0;
-    const F_MAX: f64 = 800.0;
-
     // Verify grain size
-    for i in 0..grain_frames.len() {
-        let grain_size = grain_frames[i].1 - grain_frames[i].0;
+    for (i, (start, end)) in grain_frames.iter().enumerate() {
+        if end <= start {
+            return Err(GrainError::GrainTooShort(format!("Grain {} is empty. It starts at frame {} and ends at frame {}.", i, start, end)));
+        }
+        let grain_size = end - start;
         if grain_size > fft_size {
-            return Err(GrainError::GrainTooLong(String::from(format!("Grain {} is too long. The FFT size is {}, but the grain len is {}.", i, fft_size, grain_size))));
+            return Err(GrainError::GrainTooLong(format!("Grain {} is too long. The FFT size is {}, but the grain len is {}.", i, fft_size, grain_size)));
         }
     }
     
     // Extract the grains
-    if grain_frames.len() > 0 {
+    if !grain_frames.is_empty() {
         let window = aus::generate_window(window_type, usize::min(max_window_length, grain_frames[0].1 - grain_frames[0].0));
-        for i in 0..grain_frames.len() {
-            let mut grain = audio[grain_frames[i].0..grain_frames[i].1].to_vec();
-            let mut filtered_grain = filtered_audio[grain_frames[i].0..grain_frames[i].1].to_vec();
-            for j in 0..window.len() / 2 {
-                grain[j] *= window[j];
-                filtered_grain[j] *= window[j];
-            }
-            let mut idx = grain.len() - (window.len() - window.len() / 2);
-            for j in window.len() / 2..window.len() {
-                grain[idx] *= window[j];
-                filtered_grain[idx] *= window[j];
-                idx += 1;
-            }
+        for (start, end) in grain_frames.iter() {
+            let mut grain = audio[*start..*end].to_vec();
+            let mut filtered_grain = filtered_audio[*start..*end].to_vec();
+            apply_window(&mut grain, &window);
+            apply_window(&mut filtered_grain, &window);
             // If more than 12.5% of the samples in order are 0 for the filtered grain, we don't add the grain
             // We use the filtered grain because we don't want grains with only super low frequency content.
-            if !check_zeros(&filtered_grain, filtered_grain.len() / 8, 1e-3) && !check_zeros(&filtered_grain, 50, 1e-5) {
-                grains.push(grain);
+            let rejection = if check_zeros(&filtered_grain, filtered_grain.len() / 8, 1e-3) {
+                Some(RejectionReason::NearSilence)
+            } else if check_zeros(&filtered_grain, 50, 1e-5) {
+                Some(RejectionReason::Dropout)
+            } else {
+                None
+            };
+            match rejection {
+                Some(reason) => rejected_vec.push(RejectedGrain{file: file_name.to_string(), start_frame: *start, end_frame: *end, reason}),
+                None => grains.push(((*start, *end), grain))
             }
         }
     }
 
     // Analyze the grains
-    for i in 0..grains.len() {
+    let rfft_freqs = rfftfreq(fft_size, sample_rate);
+    let mel_filterbank = MelFilterbank::new(MEL_FREQ_LOW, sample_rate as f64 / 2.0, num_mel_bands, &rfft_freqs, true);
+    for ((start_frame, end_frame), mut grain) in grains {
         // Zero pad the grain
-        let zeros = vec![0.0; fft_size - grains[i].len()];
-        grains[i].extend(zeros);
-        aus::operations::adjust_level(&mut grains[i], -6.0);
+        let zeros = vec![0.0; fft_size - grain.len()];
+        grain.extend(zeros);
+        aus::operations::adjust_level(&mut grain, -6.0);
+
+        // We only add grains with an energy above 0.0. This is because we might have taken a grain of silence.
+        let energy = aus::analysis::energy(&grain);
+        if energy.is_nan() || energy <= 0.0 {
+            rejected_vec.push(RejectedGrain{file: file_name.to_string(), start_frame, end_frame, reason: RejectionReason::ZeroEnergy});
+            continue;
+        }
 
         // Compute spectrum and analyze the grain
-        let spectrum = rfft(&grains[i], fft_size);
+        let spectrum = rfft(&grain, fft_size);
         let (magnitude_spectrum, _) = complex_to_polar_rfft(&spectrum);
-        let grain_analysis = aus::analysis::analyzer(&magnitude_spectrum, fft_size, sample_rate);
-        let pitch_estimation = aus::analysis::pyin_pitch_estimator_single(&grains[i], sample_rate, F_MIN, F_MAX);
-        let midi = aus::tuning::freq_to_midi(pitch_estimation);
+        let grain_analysis = aus::analysis::analyzer(&magnitude_spectrum, None, sample_rate, &rfft_freqs);
+        let mel_bands = mel_filterbank.filter(&aus::analysis::make_power_spectrum(&magnitude_spectrum));
+        let mfccs = mfcc_spectrum(&mel_bands, usize::min(num_mfccs, num_mel_bands), None);
+        // The FFT padding is left out, since pYIN would find its frames unvoiced
+        let (pitch_estimation, voicing_probability) = estimate_pitch(&grain[..end_frame - start_frame], sample_rate, pitch);
+        let midi = if pitch_estimation.is_nan() { f64::NAN } else { aus::tuning::freq_to_midi(pitch_estimation) };
+        // The main lobe of the analysis window is about two bins of an unpadded FFT wide on each side
+        let lobe_bins = (2 * fft_size).div_ceil(end_frame - start_frame);
+        let harmonic_features = harmonics::harmonic_features(&magnitude_spectrum, &rfft_freqs, pitch_estimation, lobe_bins);
+        let spectral_peaks = harmonics::spectral_peaks(&magnitude_spectrum, &rfft_freqs, num_spectral_peaks);
+        // The time-domain descriptors describe the grain as it is in the source, before windowing and level adjustment
+        let temporal_features = envelope::temporal_features(&audio[start_frame..end_frame], sample_rate);
+        let frame_statistics = match stft {
+            Some(settings) => {
+                let mut frames_grain = audio[start_frame..end_frame].to_vec();
+                aus::operations::adjust_level(&mut frames_grain, -6.0);
+                stft::frame_statistics(&frames_grain, sample_rate, window_type, settings)
+            },
+            None => stft::FrameStatistics::unavailable()
+        };
 
         let grain_entry: GrainEntry = GrainEntry{
             file: file_name.to_string(),
-            start_frame: grain_frames[i].0,
-            end_frame: grain_frames[i].1,
-            sample_rate: sample_rate,
-            grain_duration: sample_rate as f64 / (grain_frames[i].1 - grain_frames[i].0) as f64,
-            energy: aus::analysis::energy(&grains[i]),
-            pitch_estimation: pitch_estimation,
-            midi: midi,
+            start_frame,
+            end_frame,
+            sample_rate,
+            grain_duration: sample_rate as f64 / (end_frame - start_frame) as f64,
+            energy,
+            pitch_estimation,
+            midi,
             spectral_centroid: grain_analysis.spectral_centroid,
             spectral_entropy: grain_analysis.spectral_entropy,
             spectral_flatness: grain_analysis.spectral_flatness,
@@ -187,104 +538,529 @@ pub fn analyze_grains(file_name: &str, audio: &Vec<f64>, grain_frames: Vec<(usiz
             spectral_slope_0_1_khz: grain_analysis.spectral_slope_0_1_khz,
             spectral_slope_0_5_khz: grain_analysis.spectral_slope_0_5_khz,
             spectral_slope_1_5_khz: grain_analysis.spectral_slope_1_5_khz,
-            spectral_variance: grain_analysis.spectral_variance
+            spectral_variance: grain_analysis.spectral_variance,
+            voicing_probability,
+            hnr: harmonic_features.hnr,
+            inharmonicity: harmonic_features.inharmonicity,
+            odd_even_ratio: harmonic_features.odd_even_ratio,
+            tristimulus_1: harmonic_features.tristimulus[0],
+            tristimulus_2: harmonic_features.tristimulus[1],
+            tristimulus_3: harmonic_features.tristimulus[2],
+            rms: temporal_features.rms,
+            peak: temporal_features.peak,
+            crest_factor: temporal_features.crest_factor,
+            zero_crossing_rate: temporal_features.zero_crossing_rate,
+            attack_time: temporal_features.attack_time,
+            decay_slope: temporal_features.decay_slope,
+            temporal_centroid: temporal_features.temporal_centroid,
+            envelope_flatness: temporal_features.envelope_flatness,
+            frame_statistics,
+            mel_bands,
+            mfccs,
+            spectral_peaks,
+            tags: Vec::new()
         };
-        if i > 0 {
-            //println!("similarity: {}", similarity(&analysis_vec[analysis_vec.len() - 1], &grain_entry));
-        }
         analysis_vec.push(grain_entry);
     }
 
-    Ok(analysis_vec)
+    Ok((analysis_vec, rejected_vec))
 }
 
-/// Processes the grains. Reads audio files and extracts and analyzes grains.
-pub fn process_grains(config: &io::GranulatorConfig, max_audio_size: usize) {
-    let audio_file_list = io::find_audio(&config.audio_source_directory);
-    println!("Found {} files", audio_file_list.len());
-    
-    // Read all the files, mix to mono, and split into smaller audio chunks for faster processing
-    let mut audio_chunks: Vec<(String, u32, Vec<f64>)> = Vec::new();
-    let pool = ThreadPool::new(config.max_num_threads);
-    let (tx, rx) = mpsc::channel();  // the message passing channel
-    for file in audio_file_list {
-        let tx_clone = tx.clone();
-        pool.execute(move || {
-            let a = aus::read(&file);
-            match a {
-                Ok(mut x) => {
-                    aus::mixdown(&mut x);
-                    let mut start_idx = 0;
-                    let mut end_idx = usize::min(x.num_frames, max_audio_size);
-                    while start_idx < x.num_frames {
-                        let _ = match tx_clone.send((file.clone(), x.sample_rate, x.samples[0][start_idx..end_idx].to_vec())) {
-                            Ok(_) => (),
-                            Err(_) => ()
-                        };
-                        start_idx = end_idx;
-                        end_idx = usize::min(x.num_frames, start_idx + max_audio_size);
+/// A source file that needs to be processed with at least one grain profile
+struct PendingFile {
+    path: String,
+    stat: io::FileStat,
+    hash: String,
+    /// Indices into the configured grain profiles
+    profiles: Vec<usize>,
+}
+
+/// Filters a list of audio files down to the (file, grain profile) pairs that are new or have changed
+/// since they were last processed. A file is considered unchanged if its size and modification time match
+/// the `files` table, or if its content hash matches. Files that were only partially processed, or that were processed
+/// with other analysis settings, are processed again.
+/// Returns the pending files and the number of files that couldn't be read, or an error if the database can't be used.
+fn find_pending_files(config: &io::GranulatorConfig, audio_file_list: Vec<String>, dry_run: bool) -> Result<(Vec<PendingFile>, usize), String> {
+    let profile_keys: Vec<String> = config.grain_profiles.iter().map(|p| p.key()).collect();
+    let analysis_key = config.analysis_key();
+    // A dry run doesn't create the database, so if it doesn't exist yet, every file is new
+    let records = if Path::new(&config.database_path).exists() {
+        match sqlite::get_file_records(&config.database_path) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("Error looking up files: {}", err))
+        }
+    } else {
+        HashMap::new()
+    };
+    let mut pending_files: Vec<PendingFile> = Vec::new();
+    let mut unchanged_files: Vec<(i64, io::FileStat)> = Vec::new();
+    let mut num_errors: usize = 0;
+    for path in audio_file_list {
+        let stat = match io::stat_file(&path) {
+            Ok(x) => x,
+            Err(err) => {
+                eprintln!("Error reading file {}: {}", path, err);
+                num_errors += 1;
+                continue;
+            }
+        };
+
+        // The hash is only computed if the size or modification time doesn't match a record
+        let mut hash: Option<String> = None;
+        let mut profiles: Vec<usize> = Vec::new();
+        for (i, profile_key) in profile_keys.iter().enumerate() {
+            match records.get(&(path.clone(), profile_key.clone())) {
+                Some(record) if !record.complete || record.analysis != analysis_key => profiles.push(i),
+                Some(record) if record.size == stat.size && record.mtime == stat.mtime => (),
+                Some(record) => {
+                    if hash.is_none() {
+                        hash = io::hash_file(&path).ok();
+                    }
+                    if hash.as_deref() == Some(record.hash.as_str()) {
+                        unchanged_files.push((record.id, stat));
+                    } else {
+                        profiles.push(i);
                     }
                 },
-                Err(_) => ()
+                None => profiles.push(i)
             }
-        });
+        }
+
+        if !profiles.is_empty() {
+            if hash.is_none() {
+                hash = match io::hash_file(&path) {
+                    Ok(x) => Some(x),
+                    Err(err) => {
+                        eprintln!("Error hashing file {}: {}", path, err);
+                        num_errors += 1;
+                        continue;
+                    }
+                };
+            }
+            pending_files.push(PendingFile{path, stat, hash: hash.unwrap_or_default(), profiles});
+        }
+    }
+
+    // Files that were only touched keep their grains, but their new size and modification time are recorded
+    if !dry_run && !unchanged_files.is_empty() {
+        if let Err(err) = sqlite::touch_files(&config.database_path, &unchanged_files) {
+            return Err(format!("Error updating files: {}", err));
+        }
+    }
+    Ok((pending_files, num_errors))
+}
+
+/// The analysis results of one audio chunk: the file index, and the grains for each grain profile
+type ChunkResult = (usize, Vec<(usize, Result<(Vec<GrainEntry>, Vec<RejectedGrain>), String>)>);
+
+/// A message for the database writer thread
+enum WriterMessage {
+    /// A file is about to be processed. The writer registers it for each grain profile, given as
+    /// (profile index, profile key) pairs, along with the analysis key of the configuration.
+    BeginFile{file_idx: usize, path: String, stat: io::FileStat, hash: String, analysis: String, profiles: Vec<(usize, String)>},
+    /// The grains from one audio chunk
+    Chunk(ChunkResult),
+    /// All chunks of a file have been sent to workers. If `failed` is true, the file could not be completely decoded.
+    EndFile{file_idx: usize, num_chunks: usize, failed: bool},
+}
+
+/// The progress of a file whose chunks are being written
+struct FileProgress {
+    path: String,
+    /// The grain profiles that the file is processed with, and the file record for each of them
+    file_ids: Vec<(usize, i64)>,
+    /// The grain profiles whose grains could not all be analyzed or inserted
+    failed_profiles: Vec<usize>,
+    chunks_done: usize,
+    /// The total number of chunks, once the file has been completely decoded
+    num_chunks: Option<usize>,
+    /// Whether the file could not be completely decoded
+    failed: bool,
+}
+
+/// Runs the database writer. The writer owns the only connection that writes grains, and it receives
+/// grain batches from the workers over a bounded channel. After each chunk is written, a message is sent
+/// on `chunk_done` so that decoding can continue. Once all chunks of a file are written, the file is marked
+/// complete for each grain profile whose grains were all inserted.
+/// Returns the number of errors.
+fn run_writer(mut writer: sqlite::GrainWriter, rx: mpsc::Receiver<WriterMessage>, chunk_done: mpsc::Sender<()>) -> usize {
+    let mut num_errors: usize = 0;
+    let mut progress: HashMap<usize, FileProgress> = HashMap::new();
+    for message in rx {
+        let file_idx = match message {
+            WriterMessage::BeginFile{file_idx, path, stat, hash, analysis, profiles} => {
+                let mut file_progress = FileProgress{path, file_ids: Vec::new(), failed_profiles: Vec::new(), chunks_done: 0, num_chunks: None, failed: false};
+                match writer.count_legacy_grains(&file_progress.path) {
+                    Ok(0) => (),
+                    Ok(num_legacy) => eprintln!("Warning: replacing {} grains of {} from an older version of the grain processor. \
+                        Their tags will be added to the new grains that overlap them, but their positions may have been wrong.",
+                        num_legacy, file_progress.path),
+                    Err(err) => {
+                        eprintln!("Error registering file {}: {}", file_progress.path, err);
+                        num_errors += 1;
+                    }
+                }
+                for (profile_idx, profile_key) in profiles {
+                    match writer.begin_file(&file_progress.path, &profile_key, &stat, &hash, &analysis) {
+                        Ok(file_id) => file_progress.file_ids.push((profile_idx, file_id)),
+                        Err(err) => {
+                            eprintln!("Error registering file {}: {}", file_progress.path, err);
+                            num_errors += 1;
+                            file_progress.failed_profiles.push(profile_idx);
+                        }
+                    }
+                }
+                progress.insert(file_idx, file_progress);
+                continue;
+            },
+            WriterMessage::Chunk((file_idx, profile_results)) => {
+                if let Some(file_progress) = progress.get_mut(&file_idx) {
+                    let start_time = Instant::now();
+                    let mut num_grains = 0;
+                    for (profile_idx, profile_result) in profile_results {
+                        let file_id = match file_progress.file_ids.iter().find(|(p, _)| *p == profile_idx) {
+                            Some((_, id)) => *id,
+                            None => continue
+                        };
+                        let stored = match profile_result {
+                            Ok((grains, rejected)) => {
+                                num_grains += grains.len();
+                                writer.insert_grains(&grains, &rejected, file_id).map_err(|err| format!("Error in file {}: {}", file_progress.path, err))
+                            },
+                            Err(err) => Err(err)
+                        };
+                        if let Err(err) = stored {
+                            eprintln!("{}", err);
+                            num_errors += 1;
+                            file_progress.failed_profiles.push(profile_idx);
+                        }
+                    }
+                    let elapsed = start_time.elapsed().as_secs_f64();
+                    eprintln!("Chunk of file {} done. Inserted {} grains in {:.1} ms ({:.0} grains/s).",
+                        file_progress.path, num_grains, elapsed * 1000.0, num_grains as f64 / f64::max(elapsed, 1e-9));
+                    file_progress.chunks_done += 1;
+                }
+                let _ = chunk_done.send(());
+                file_idx
+            },
+            WriterMessage::EndFile{file_idx, num_chunks, failed} => {
+                if let Some(file_progress) = progress.get_mut(&file_idx) {
+                    file_progress.num_chunks = Some(num_chunks);
+                    file_progress.failed = failed;
+                }
+                file_idx
+            }
+        };
+
+        // Complete the file if all of its chunks are written
+        match progress.get(&file_idx) {
+            Some(file_progress) if file_progress.num_chunks == Some(file_progress.chunks_done) => {
+                if !file_progress.failed {
+                    for (profile_idx, file_id) in file_progress.file_ids.iter() {
+                        if file_progress.failed_profiles.contains(profile_idx) {
+                            continue;
+                        }
+                        if let Err(err) = writer.complete_file(*file_id) {
+                            eprintln!("Error in file {}: {}", file_progress.path, err);
+                            num_errors += 1;
+                        }
+                    }
+                }
+                progress.remove(&file_idx);
+            },
+            _ => ()
+        }
     }
+    num_errors
+}
 
-    // Drop the original sender. Once all senders are dropped, receiving will end automatically.
-    drop(tx);
+/// Processes the grains. Reads audio files and extracts and analyzes grains.
+/// Files that have already been processed with a grain profile are skipped for that profile,
+/// and files that have changed since they were processed have their old grains replaced.
+/// If `dry_run` is true, the files that would be processed are listed, and nothing is written to the database.
+///
+/// Files are decoded one chunk at a time, and each chunk is analyzed with every grain profile that the file
+/// needs before it is released. Decoding waits whenever every thread is busy, so at most one chunk per
+/// thread (plus the chunk being decoded) is in memory at once.
+/// Returns the number of errors, or an error if the database can't be used, which stops extraction.
+pub fn process_grains(config: &io::GranulatorConfig, dry_run: bool) -> Result<usize, String> {
+    let audio_file_list = io::find_audio(&config.audio_source_directory);
+    eprintln!("Found {} files", audio_file_list.len());
+    let (pending_files, mut num_errors) = find_pending_files(config, audio_file_list, dry_run)?;
+    eprintln!("{} files are new or have changed", pending_files.len());
+    if dry_run {
+        for pending_file in pending_files.iter() {
+            println!("Would process {} with {} grain profile(s)", pending_file.path, pending_file.profiles.len());
+        }
+        return Ok(num_errors);
+    }
 
-    // Collect the audio chunks
-    for val in rx {
-        audio_chunks.push(val);
+    for (i, grain_spec) in config.grain_profiles.iter().enumerate() {
+        eprintln!("-------------------------------------------\nGrain profile {}\nGrain size: {}\nGrain spacing: {}\nSegmentation: {:?}", i, grain_spec.grain_size, grain_spec.grain_spacing, grain_spec.segmentation);
     }
+    eprintln!("-------------------------------------------");
 
-    pool.join();  // let all threads wrap up
-    println!("Audio files loaded.");
-
-    // Iterate through the grain specifications, extracting grains
-    for grain_spec in config.grain_profiles.iter() {
-        let grain_size = grain_spec["grain_size"];
-        let grain_spacing = grain_spec["grain_spacing"];
-        println!("-------------------------------------------\nGrain size: {}\nGrain spacing: {}\nStarting grain extraction for {} audio file chunks...", grain_size, grain_spacing, audio_chunks.len());
-        let pool = ThreadPool::new(config.max_num_threads);
-        let (tx, rx) = mpsc::channel();  // the message passing channel
-        for chunk in audio_chunks.iter() {
-            let chunk_name = chunk.0.clone();
-            let sample_rate = chunk.1;
-            let chunk = chunk.2.clone();
-            
-            let tx_clone = tx.clone();
+    // The chunks overlap by the largest grain size, or by one second if that is longer.
+    let max_grain_size = config.grain_profiles.iter().map(|p| p.grain_size).max().unwrap_or(0);
+    let chunk_size = config.chunk_size();
+    let max_chunks_in_flight = usize::max(config.max_num_threads, 1);
+    let profiles: Arc<Vec<io::GrainProfile>> = Arc::new(config.grain_profiles.clone());
+    let num_mel_bands = config.num_mel_bands;
+    let num_mfccs = config.num_mfccs;
+    let num_spectral_peaks = config.num_spectral_peaks;
+    let analysis_key = config.analysis_key();
+    let tag_rules = Arc::new(config.tag_rules.clone());
+    let note_tags = config.note_tags;
+
+    let writer = match sqlite::GrainWriter::open(&config.database_path) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("Error opening database: {}", err))
+    };
+    // The writer channel is bounded, so workers wait if the writer falls behind
+    let (writer_tx, writer_rx) = mpsc::sync_channel::<WriterMessage>(max_chunks_in_flight);
+    let (chunk_done_tx, chunk_done_rx) = mpsc::channel::<()>();
+    let writer_thread = thread::spawn(move || run_writer(writer, writer_rx, chunk_done_tx));
+    let pool = ThreadPool::new(config.max_num_threads);
+    let mut chunks_in_flight: usize = 0;
+
+    for (file_idx, pending_file) in pending_files.iter().enumerate() {
+        let stream = match io::AudioStream::open(&pending_file.path) {
+            Ok(x) => x,
+            Err(err) => {
+                eprintln!("{}", err);
+                num_errors += 1;
+                continue;
+            }
+        };
+        eprintln!("Processing file {}", pending_file.path);
+        let regions = Arc::new(labels::read_regions(&pending_file.path, stream.sample_rate));
+        if !regions.is_empty() {
+            eprintln!("Found {} labelled regions for file {}", regions.len(), pending_file.path);
+        }
+        let overlap = usize::max(max_grain_size, stream.sample_rate as usize);
+        let file_chunk_size = chunk_size.frames(stream.sample_rate);
+        let reader = ChunkReader::new(&pending_file.path, stream, file_chunk_size, overlap);
+        let profile_keys: Vec<(usize, String)> = pending_file.profiles.iter().map(|&i| (i, config.grain_profiles[i].key())).collect();
+        let begin_file = WriterMessage::BeginFile{file_idx, path: pending_file.path.clone(), stat: pending_file.stat, hash: pending_file.hash.clone(), analysis: analysis_key.clone(), profiles: profile_keys};
+        if writer_tx.send(begin_file).is_err() {
+            eprintln!("Error: the database writer stopped");
+            num_errors += 1;
+            break;
+        }
+
+        let mut num_chunks: usize = 0;
+        let mut failed = false;
+        for chunk in reader {
+            let chunk = match chunk {
+                Ok(x) => x,
+                Err(err) => {
+                    eprintln!("Error in file {}: {}", pending_file.path, err);
+                    num_errors += 1;
+                    failed = true;
+                    break;
+                }
+            };
+
+            // Wait for a chunk to be written before decoding any more audio
+            while chunks_in_flight >= max_chunks_in_flight {
+                match chunk_done_rx.recv() {
+                    Ok(_) => chunks_in_flight -= 1,
+                    Err(_) => break
+                }
+            }
+
+            num_chunks += 1;
+            chunks_in_flight += 1;
+            let profiles = Arc::clone(&profiles);
+            let tag_rules = Arc::clone(&tag_rules);
+            let regions = Arc::clone(&regions);
+            let job_profiles = pending_file.profiles.clone();
+            let tx_clone = writer_tx.clone();
             // Start the thread
             pool.execute(move || {
-                let frames = extract_grain_frames(&chunk, grain_size, grain_spacing, 20000);
-                // the fft size has to be at least as large as the grain size
-                let mut fft_size: usize = 512;
-                while fft_size < grain_size {
-                    fft_size *= 2;
+                let mut results = Vec::with_capacity(job_profiles.len());
+                for profile_idx in job_profiles {
+                    let profile = &profiles[profile_idx];
+                    let analysis = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let frames = chunk_grain_frames(&chunk, profile, profile.initial_offset.unwrap_or(INITIAL_OFFSET));
+                        analyze_grains(&chunk.file, &chunk.samples, frames, profile.window_type(), 5000, chunk.sample_rate, profile.analysis_fft_size(), num_mel_bands, num_mfccs, num_spectral_peaks, &profile.pitch_settings(), profile.stft_settings().as_ref())
+                    }));
+                    let result = match analysis {
+                        Ok(Ok((mut grains, mut rejected))) => {
+                            // Convert the grain positions from chunk positions to file positions
+                            for grain in grains.iter_mut() {
+                                grain.start_frame += chunk.offset;
+                                grain.end_frame += chunk.offset;
+                                grain.tags = tags::apply_rules(grain, &tag_rules, note_tags);
+                                for label in labels::overlapping_labels(&regions, grain.start_frame, grain.end_frame) {
+                                    if !grain.tags.contains(&label) {
+                                        grain.tags.push(label);
+                                    }
+                                }
+                            }
+                            for grain in rejected.iter_mut() {
+                                grain.start_frame += chunk.offset;
+                                grain.end_frame += chunk.offset;
+                            }
+                            Ok((grains, rejected))
+                        },
+                        Ok(Err(err)) => Err(format!("Error analyzing grains in file {}: {}", chunk.file, err)),
+                        Err(_) => Err(format!("Error analyzing grains in file {}: the analysis panicked", chunk.file))
+                    };
+                    results.push((profile_idx, result));
+                }
+                // The chunk result must always be sent, since the writer counts them
+                match tx_clone.send(WriterMessage::Chunk((file_idx, results))) {
+                    Ok(_) => (),
+                    Err(_) => eprintln!("Error sending grains in chunk of file {}", chunk.file)
                 }
-                match analyze_grains(&chunk_name, &chunk, frames, aus::WindowType::Hanning, 5000, sample_rate, fft_size) {
-                    Ok(grains) => {
-                        match tx_clone.send((chunk_name.clone(), grains)) {
-                            Ok(_) => (),
-                            Err(_) => println!("Error sending grains in chunk of file {}", chunk_name)
-                        }
-                    },
-                    Err(err) => println!("Error analyzing grains: {:?}", err)
-                };
             });
         }
 
-        // Drop the original sender. Once all senders are dropped, receiving will end automatically.
-        drop(tx);
+        if writer_tx.send(WriterMessage::EndFile{file_idx, num_chunks, failed}).is_err() {
+            eprintln!("Error: the database writer stopped");
+            num_errors += 1;
+            break;
+        }
+    }
+
+    // Drop the original sender. Once the workers finish and drop theirs, the writer will stop.
+    drop(writer_tx);
+    pool.join();  // let all threads wrap up
+    match writer_thread.join() {
+        Ok(writer_errors) => num_errors += writer_errors,
+        Err(_) => {
+            eprintln!("Error: the database writer panicked");
+            num_errors += 1;
+        }
+    }
 
-        // Collect the analysis vectors and sort them by thread id
-        for (file, grains) in rx {
-            match sqlite::insert_grains(&config.database_path, &grains) {
-                Ok(_) => println!("Chunk of file {} done.", file),
-                Err(err) => println!("Error in file {}: {}", file, err)
+    // The corpus has changed, so the feature statistics used for similarity are out of date
+    if !pending_files.is_empty() {
+        if let Err(err) = sqlite::clear_feature_stats(&config.database_path) {
+            eprintln!("Error clearing feature statistics: {}", err);
+            num_errors += 1;
+        }
+        // The nearest neighbor index is only kept up to date once it has been built
+        if Path::new(&knn::index_path(&config.database_path)).exists() {
+            if let Err(err) = knn::update_index(&config.database_path, &config.similarity) {
+                eprintln!("Error updating the nearest neighbor index: {}", err);
+                num_errors += 1;
             }
         }
+    }
+    Ok(num_errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    /// Writes a mono 16-bit WAV file in the temporary directory whose sample values count up from 0,
+    /// so that each sample can be traced back to its file position. The file is deleted when it is dropped.
+    fn ramp_wav(name: &str, num_frames: usize) -> TempFile {
+        let data: Vec<u8> = (0..num_frames).flat_map(|i| (i as i16).to_le_bytes()).collect();
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend((36 + data.len() as u32).to_le_bytes());
+        bytes.extend(b"WAVEfmt ");
+        bytes.extend(16u32.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(44100u32.to_le_bytes());
+        bytes.extend((44100u32 * 2).to_le_bytes());
+        bytes.extend(2u16.to_le_bytes());
+        bytes.extend(16u16.to_le_bytes());
+        bytes.extend(b"data");
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        TempFile::with_contents("chunks", &format!("{}.wav", name), bytes)
+    }
 
-        pool.join();  // let all threads wrap up
+    fn read_chunks(path: &str, chunk_size: usize, overlap: usize) -> Vec<AudioChunk> {
+        let stream = io::AudioStream::open(path).unwrap();
+        ChunkReader::new(path, stream, chunk_size, overlap).map(|chunk| chunk.unwrap()).collect()
     }
-}
\ No newline at end of file
+
+    /// Gets the file positions of the samples of a ramp file
+    fn positions(samples: &[f64]) -> Vec<usize> {
+        samples.iter().map(|x| (x * 32768.0).round() as usize).collect()
+    }
+
+    fn profile(json: &str) -> io::GrainProfile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn chunks_cover_the_file_with_overlap() {
+        let num_frames = 20000;
+        let path = ramp_wav("cover", num_frames);
+        for (chunk_size, overlap) in [(1000, 0), (1000, 300), (4096, 2048), (100, 250), (7, 3), (50000, 100)] {
+            let chunks = read_chunks(&path.0, chunk_size, overlap);
+            let mut next_start = 0;
+            for chunk in chunks.iter() {
+                assert_eq!(chunk.sample_rate, 44100);
+                assert_eq!(chunk.grain_starts.start, next_start);
+                assert!(chunk.grain_starts.end > chunk.grain_starts.start);
+                assert!(chunk.grain_starts.end - chunk.grain_starts.start <= chunk_size);
+                assert_eq!(chunk.offset, chunk.grain_starts.start.saturating_sub(overlap));
+                let end = usize::min(num_frames, chunk.grain_starts.end + overlap);
+                assert_eq!(positions(&chunk.samples), (chunk.offset..end).collect::<Vec<usize>>(), "chunk size {}, overlap {}", chunk_size, overlap);
+                next_start = chunk.grain_starts.end;
+            }
+            assert_eq!(next_start, num_frames, "chunk size {}, overlap {}", chunk_size, overlap);
+        }
+    }
+
+    #[test]
+    fn empty_file_has_no_chunks() {
+        let path = ramp_wav("empty", 0);
+        assert!(read_chunks(&path.0, 1000, 100).is_empty());
+    }
+
+    #[test]
+    fn chunked_grains_match_whole_file_grains() {
+        let num_frames = 20000;
+        let path = ramp_wav("grains", num_frames);
+        let whole = vec![0.0; num_frames];
+        for (grain_size, grain_spacing, initial_offset) in [(256, 100, 0), (256, 100, 37), (1000, 333, 2500), (64, 64, 19999)] {
+            let profile = profile(&format!("{{\"grain_size\": {}, \"grain_spacing\": {}}}", grain_size, grain_spacing));
+            let expected = extract_grain_frames(&whole, grain_size, grain_spacing, initial_offset);
+            for chunk_size in [1000, 1024, 4096, 30000] {
+                let mut grains: Vec<(usize, usize)> = Vec::new();
+                for chunk in read_chunks(&path.0, chunk_size, grain_size) {
+                    for (start, end) in chunk_grain_frames(&chunk, &profile, initial_offset) {
+                        // The grain's samples are the ones at its absolute file position
+                        assert_eq!(positions(&chunk.samples[start..end]), (chunk.offset + start..chunk.offset + end).collect::<Vec<usize>>());
+                        grains.push((chunk.offset + start, chunk.offset + end));
+                    }
+                }
+                assert_eq!(grains, expected, "grain size {}, spacing {}, offset {}, chunk size {}", grain_size, grain_spacing, initial_offset, chunk_size);
+            }
+        }
+    }
+
+    /// Analyzes `audio` as one grain, which is zero padded to the FFT size, and returns its frequency and voicing probability
+    fn padded_grain_pitch(audio: &[f64]) -> (f64, f64) {
+        let pitch = PitchSettings{f_min: DEFAULT_PITCH_MIN, f_max: DEFAULT_PITCH_MAX, voicing_threshold: DEFAULT_VOICING_THRESHOLD};
+        let fft_size = fft_size_for_grain(audio.len());
+        let (grains, _) = analyze_grains("test.wav", audio, vec![(0, audio.len())], aus::WindowType::Hanning, 5000, 44100, fft_size, 40, 20, 0, &pitch, None).unwrap();
+        (grains[0].pitch_estimation, grains[0].voicing_probability)
+    }
+
+    #[test]
+    fn padded_sine_is_voiced_and_padded_noise_is_not() {
+        // The grains are padded to an FFT size of almost twice their length
+        let sine: Vec<f64> = (0..17000).map(|i| 0.5 * (2.0 * std::f64::consts::PI * 220.0 * i as f64 / 44100.0).sin()).collect();
+        let (frequency, voicing_probability) = padded_grain_pitch(&sine);
+        assert!(voicing_probability > 0.9, "voicing probability {}", voicing_probability);
+        assert!((frequency - 220.0).abs() < 2.0, "frequency {}", frequency);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let noise: Vec<f64> = (0..17000).map(|_| rng.gen_range(-0.5..0.5)).collect();
+        let (frequency, voicing_probability) = padded_grain_pitch(&noise);
+        assert!(voicing_probability < DEFAULT_VOICING_THRESHOLD, "voicing probability {}", voicing_probability);
+        assert!(frequency.is_nan());
+    }
+}
@@ -0,0 +1,166 @@
+// File: harmonics.rs
+// This file has the descriptors of harmonic structure: the harmonic-to-noise ratio, inharmonicity,
+// the odd/even harmonic ratio and tristimulus, which are found from the magnitude spectrum and the
+// estimated pitch, and the strongest peaks in the magnitude spectrum.
+
+// The highest harmonic that is looked for
+const MAX_HARMONICS: usize = 50;
+
+// The smallest magnitude used when taking logarithms
+const MIN_MAGNITUDE: f64 = 1e-20;
+
+/// The harmonic descriptors of a grain. They are all NaN if the grain has no pitch.
+#[derive(Debug, Clone, Copy)]
+pub struct HarmonicFeatures {
+    /// The harmonic-to-noise ratio in dB: the energy near the harmonics compared to the rest of the spectrum
+    pub hnr: f64,
+    /// How far the harmonics are from integer multiples of the fundamental, weighted by their energy,
+    /// relative to the fundamental. It is 0.0 for a perfectly harmonic sound.
+    pub inharmonicity: f64,
+    /// The energy of the odd harmonics above the fundamental compared to the energy of the even harmonics
+    pub odd_even_ratio: f64,
+    /// The amplitude of the fundamental, of harmonics 2 to 4, and of harmonics 5 and up, as fractions of
+    /// the amplitude of all harmonics
+    pub tristimulus: [f64; 3],
+}
+
+impl HarmonicFeatures {
+    fn unpitched() -> HarmonicFeatures {
+        HarmonicFeatures{hnr: f64::NAN, inharmonicity: f64::NAN, odd_even_ratio: f64::NAN, tristimulus: [f64::NAN; 3]}
+    }
+}
+
+/// Refines a peak at bin `k` with parabolic interpolation of the log magnitudes around it.
+/// Returns the frequency and the magnitude of the peak.
+fn interpolate_peak(magnitude: &[f64], bin_width: f64, k: usize) -> (f64, f64) {
+    if k == 0 || k + 1 >= magnitude.len() {
+        return (k as f64 * bin_width, magnitude[k]);
+    }
+    let (a, b, c) = (magnitude[k - 1].max(MIN_MAGNITUDE).ln(), magnitude[k].max(MIN_MAGNITUDE).ln(), magnitude[k + 1].max(MIN_MAGNITUDE).ln());
+    let denominator = a - 2.0 * b + c;
+    let p = if denominator < 0.0 { (0.5 * (a - c) / denominator).clamp(-0.5, 0.5) } else { 0.0 };
+    ((k as f64 + p) * bin_width, (b - 0.25 * (a - c) * p).exp())
+}
+
+/// Finds the `n` strongest peaks of a magnitude spectrum. A peak is a bin that is larger than the bin below it,
+/// and at least as large as the bin above it. Returns the frequencies and magnitudes of the peaks, strongest first.
+pub fn spectral_peaks(magnitude: &[f64], freqs: &[f64], n: usize) -> Vec<(f64, f64)> {
+    if magnitude.len() < 3 || freqs.len() < 2 {
+        return Vec::new();
+    }
+    let bin_width = freqs[1] - freqs[0];
+    let mut peaks: Vec<usize> = (1..magnitude.len() - 1)
+        .filter(|&k| magnitude[k] > magnitude[k - 1] && magnitude[k] >= magnitude[k + 1])
+        .collect();
+    peaks.sort_by(|&a, &b| magnitude[b].total_cmp(&magnitude[a]));
+    peaks.into_iter().take(n).map(|k| interpolate_peak(magnitude, bin_width, k)).collect()
+}
+
+/// Finds the harmonic descriptors of a magnitude spectrum with fundamental frequency `f0`. Each harmonic is the
+/// largest peak within a quarter of the fundamental of its ideal frequency. `lobe_bins` is the half-width of the
+/// main lobe of the analysis window in bins, and the energy within it around each harmonic counts as harmonic energy.
+pub fn harmonic_features(magnitude: &[f64], freqs: &[f64], f0: f64, lobe_bins: usize) -> HarmonicFeatures {
+    if !f0.is_finite() || f0 <= 0.0 || magnitude.len() < 3 || freqs.len() < 2 {
+        return HarmonicFeatures::unpitched();
+    }
+    let bin_width = freqs[1] - freqs[0];
+    let nyquist = freqs[freqs.len() - 1];
+
+    // (harmonic number, frequency, amplitude, bin) of each harmonic that has a peak
+    let mut harmonics: Vec<(usize, f64, f64, usize)> = Vec::new();
+    for h in 1..=MAX_HARMONICS {
+        let ideal = h as f64 * f0;
+        if ideal + f0 / 4.0 > nyquist {
+            break;
+        }
+        let lo = ((ideal - f0 / 4.0) / bin_width).ceil().max(1.0) as usize;
+        let hi = usize::min(((ideal + f0 / 4.0) / bin_width).floor() as usize, magnitude.len() - 2);
+        if lo > hi {
+            continue;
+        }
+        let k = (lo..=hi).max_by(|&a, &b| magnitude[a].total_cmp(&magnitude[b])).unwrap_or(lo);
+        let (frequency, amplitude) = interpolate_peak(magnitude, bin_width, k);
+        harmonics.push((h, frequency, amplitude, k));
+    }
+    let total_amplitude: f64 = harmonics.iter().map(|x| x.2).sum();
+    let harmonic_energy_sum: f64 = harmonics.iter().map(|x| x.2 * x.2).sum();
+    if harmonics.is_empty() || total_amplitude <= 0.0 {
+        return HarmonicFeatures::unpitched();
+    }
+
+    // The harmonic bands are kept narrower than half the distance between harmonics, so that they don't overlap
+    let half_width = usize::min(lobe_bins, (f0 / (2.0 * bin_width)).floor() as usize);
+    let mut is_harmonic: Vec<bool> = vec![false; magnitude.len()];
+    for &(_, _, _, k) in harmonics.iter() {
+        for flag in is_harmonic.iter_mut().take(usize::min(k + half_width + 1, magnitude.len())).skip(k.saturating_sub(half_width)) {
+            *flag = true;
+        }
+    }
+    // The spectrum below half the fundamental is left out, since it has no harmonics
+    let first_bin = (f0 / (2.0 * bin_width)).ceil() as usize;
+    let (mut harmonic_energy, mut noise_energy) = (0.0, 0.0);
+    for (k, m) in magnitude.iter().enumerate().skip(first_bin) {
+        if is_harmonic[k] { harmonic_energy += m * m } else { noise_energy += m * m }
+    }
+    let hnr = if harmonic_energy <= 0.0 {
+        f64::NAN
+    } else {
+        10.0 * (harmonic_energy / noise_energy.max(harmonic_energy * 1e-12)).log10()
+    };
+
+    let inharmonicity = 2.0 / f0 * harmonics.iter().map(|&(h, frequency, amplitude, _)| (frequency - h as f64 * f0).abs() * amplitude * amplitude).sum::<f64>() / harmonic_energy_sum;
+    let odd_energy: f64 = harmonics.iter().filter(|x| x.0 > 1 && x.0 % 2 == 1).map(|x| x.2 * x.2).sum();
+    let even_energy: f64 = harmonics.iter().filter(|x| x.0 % 2 == 0).map(|x| x.2 * x.2).sum();
+    let odd_even_ratio = if even_energy > 0.0 { odd_energy / even_energy } else { f64::NAN };
+    let band_amplitude = |lo: usize, hi: usize| harmonics.iter().filter(|x| x.0 >= lo && x.0 <= hi).map(|x| x.2).sum::<f64>() / total_amplitude;
+    HarmonicFeatures{
+        hnr,
+        inharmonicity,
+        odd_even_ratio,
+        tristimulus: [band_amplitude(1, 1), band_amplitude(2, 4), band_amplitude(5, MAX_HARMONICS)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aus::spectrum::{rfft, rfftfreq, complex_to_polar_rfft};
+
+    const SAMPLE_RATE: u32 = 44100;
+    const FFT_SIZE: usize = 4096;
+
+    /// Gets the magnitude spectrum of a Hanning windowed signal, and the frequency of each bin
+    fn magnitude_spectrum(signal: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        let window = aus::generate_window(aus::WindowType::Hanning, FFT_SIZE);
+        let windowed: Vec<f64> = signal.iter().zip(window.iter()).map(|(x, w)| x * w).collect();
+        let (magnitude, _) = complex_to_polar_rfft(&rfft(&windowed, FFT_SIZE));
+        (magnitude, rfftfreq(FFT_SIZE, SAMPLE_RATE))
+    }
+
+    #[test]
+    fn sawtooth_is_harmonic_with_the_expected_tristimulus() {
+        // The fundamental is on a bin, and every harmonic up to MAX_HARMONICS is below the Nyquist frequency
+        let f0 = 40.0 * SAMPLE_RATE as f64 / FFT_SIZE as f64;
+        let sawtooth: Vec<f64> = (0..FFT_SIZE).map(|n| {
+            let t = n as f64 / SAMPLE_RATE as f64;
+            (1..=MAX_HARMONICS).map(|h| (2.0 * std::f64::consts::PI * h as f64 * f0 * t).sin() / h as f64).sum()
+        }).collect();
+        let (magnitude, freqs) = magnitude_spectrum(&sawtooth);
+        let features = harmonic_features(&magnitude, &freqs, f0, 2);
+
+        assert!(features.inharmonicity.abs() < 1e-3, "inharmonicity {}", features.inharmonicity);
+        let total: f64 = (1..=MAX_HARMONICS).map(|h| 1.0 / h as f64).sum();
+        let expected = [1.0 / total, (1.0 / 2.0 + 1.0 / 3.0 + 1.0 / 4.0) / total, 1.0 - (1.0 + 1.0 / 2.0 + 1.0 / 3.0 + 1.0 / 4.0) / total];
+        for (found, expected) in features.tristimulus.iter().zip(expected.iter()) {
+            assert!((found - expected).abs() < 0.01, "tristimulus {:?} should be {:?}", features.tristimulus, expected);
+        }
+        assert!(features.hnr > 20.0, "hnr {}", features.hnr);
+    }
+
+    #[test]
+    fn unpitched_grains_have_no_harmonic_features() {
+        let (magnitude, freqs) = magnitude_spectrum(&vec![0.5; FFT_SIZE]);
+        let features = harmonic_features(&magnitude, &freqs, f64::NAN, 2);
+        assert!(features.inharmonicity.is_nan() && features.tristimulus.iter().all(|x| x.is_nan()));
+    }
+}
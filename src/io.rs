@@ -1,19 +1,300 @@
 // File: io.rs
 // This file has IO operations.
 
+use crate::{grain_extractor, labels, similarity, sqlite, stft, tags};
 use glob::glob;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
-use serde_json;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, Decoder, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// How grain start positions are chosen for a grain profile
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentationMode {
+    /// Grains start every `grain_spacing` frames
+    #[default]
+    FixedHop,
+    /// Grains start on onsets detected with spectral flux
+    SpectralFlux,
+    /// Grains start on onsets detected with energy novelty
+    EnergyNovelty
+}
+
+/// A window shape that can be named in JSON files. These correspond to the `aus` window types.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowShape {
+    Bartlett,
+    Blackman,
+    #[default]
+    Hanning,
+    Hamming,
+    Rectangular
+}
+
+impl WindowShape {
+    /// Gets the corresponding `aus` window type
+    pub fn window_type(&self) -> aus::WindowType {
+        match self {
+            WindowShape::Bartlett => aus::WindowType::Bartlett,
+            WindowShape::Blackman => aus::WindowType::Blackman,
+            WindowShape::Hanning => aus::WindowType::Hanning,
+            WindowShape::Hamming => aus::WindowType::Hamming,
+            WindowShape::Rectangular => aus::WindowType::Rectangular
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigError {
+    Read(String),
+    Parse(String),
+    /// Every problem found while validating the configuration
+    Invalid(Vec<String>)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(msg) => write!(f, "{}", msg),
+            ConfigError::Parse(msg) => write!(f, "{}", msg),
+            ConfigError::Invalid(problems) => {
+                write!(f, "The configuration has {} problem(s):", problems.len())?;
+                for problem in problems.iter() {
+                    write!(f, "\n    {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// The optional profile settings are left out of the profile key when they are not set,
+// so that adding them doesn't change the keys of profiles that were processed before they existed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GrainProfile {
+    pub grain_size: usize,
+    /// The distance between grain onsets. In onset segmentation modes, this is the minimum distance between onsets.
+    pub grain_spacing: usize,
+    #[serde(default)]
+    pub segmentation: SegmentationMode,
+    /// The onset detection threshold (0.0 to 1.0), relative to the normalized novelty curve
+    #[serde(default = "default_onset_threshold")]
+    pub onset_threshold: f64,
+    /// The window applied to grains before analysis. If omitted, a Hanning window is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub analysis_window: Option<WindowShape>,
+    /// The FFT size for analysis. If omitted, the smallest power of two that fits the grain (at least 512) is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fft_size: Option<usize>,
+    /// The frame where grain extraction starts in each file. If omitted, the default offset is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial_offset: Option<usize>,
+    /// The lowest frequency in Hz that pitch estimation looks for. If omitted, 50 Hz is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch_min: Option<f64>,
+    /// The highest frequency in Hz that pitch estimation looks for. If omitted, 800 Hz is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch_max: Option<f64>,
+    /// The voicing probability (0.0 to 1.0) below which a grain is considered unpitched. If omitted, 0.25 is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voicing_threshold: Option<f64>,
+    /// The hop in frames for intra-grain STFT analysis. If omitted, grains are analyzed with a single FFT only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stft_hop: Option<usize>,
+    /// The frame size for intra-grain STFT analysis. If omitted, 1024 is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stft_frame_size: Option<usize>,
+}
+
+impl GrainProfile {
+    /// A key that identifies the profile in the database. Two profiles with the same settings have the same key.
+    pub fn key(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Gets the window type used for analysis
+    pub fn window_type(&self) -> aus::WindowType {
+        self.analysis_window.unwrap_or_default().window_type()
+    }
+
+    /// Gets the FFT size used for analysis
+    pub fn analysis_fft_size(&self) -> usize {
+        match self.fft_size {
+            Some(x) => x,
+            None => grain_extractor::fft_size_for_grain(self.grain_size)
+        }
+    }
+
+    /// Gets the pitch estimation settings
+    pub fn pitch_settings(&self) -> grain_extractor::PitchSettings {
+        grain_extractor::PitchSettings{
+            f_min: self.pitch_min.unwrap_or(grain_extractor::DEFAULT_PITCH_MIN),
+            f_max: self.pitch_max.unwrap_or(grain_extractor::DEFAULT_PITCH_MAX),
+            voicing_threshold: self.voicing_threshold.unwrap_or(grain_extractor::DEFAULT_VOICING_THRESHOLD)
+        }
+    }
+
+    /// Gets the intra-grain STFT settings. Returns `None` if the profile doesn't use intra-grain analysis.
+    pub fn stft_settings(&self) -> Option<stft::StftSettings> {
+        self.stft_hop.map(|hop| stft::StftSettings{
+            frame_size: self.stft_frame_size.unwrap_or(stft::DEFAULT_STFT_FRAME_SIZE),
+            hop
+        })
+    }
+
+    /// Checks the profile settings. Returns a description of every problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.grain_size == 0 {
+            problems.push(String::from("The grain size is 0."));
+        }
+        if self.grain_spacing == 0 {
+            problems.push(String::from("The grain spacing is 0."));
+        }
+        if self.grain_size > grain_extractor::MAX_FFT_SIZE {
+            problems.push(format!("The grain size {} is larger than the maximum FFT size {}.", self.grain_size, grain_extractor::MAX_FFT_SIZE));
+        }
+        if let Some(fft_size) = self.fft_size {
+            if fft_size < self.grain_size {
+                problems.push(format!("The FFT size {} is smaller than the grain size {}.", fft_size, self.grain_size));
+            }
+            if fft_size > grain_extractor::MAX_FFT_SIZE {
+                problems.push(format!("The FFT size {} is larger than the maximum FFT size {}.", fft_size, grain_extractor::MAX_FFT_SIZE));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.onset_threshold) {
+            problems.push(format!("The onset threshold {} is not between 0.0 and 1.0.", self.onset_threshold));
+        }
+        let pitch = self.pitch_settings();
+        if !(pitch.f_min > 0.0 && pitch.f_min < pitch.f_max && pitch.f_max.is_finite()) {
+            problems.push(format!("The pitch range {} to {} Hz is invalid. The lowest pitch must be above 0 and below the highest pitch.", pitch.f_min, pitch.f_max));
+        }
+        if !(0.0..=1.0).contains(&pitch.voicing_threshold) {
+            problems.push(format!("The voicing threshold {} is not between 0.0 and 1.0.", pitch.voicing_threshold));
+        }
+        match self.stft_settings() {
+            Some(settings) => {
+                if settings.hop == 0 {
+                    problems.push(String::from("The STFT hop is 0."));
+                }
+                if settings.frame_size == 0 {
+                    problems.push(String::from("The STFT frame size is 0."));
+                }
+                if settings.frame_size + settings.hop > self.grain_size {
+                    problems.push(format!("The grain size {} is too small for two STFT frames of size {} with a hop of {}.", self.grain_size, settings.frame_size, settings.hop));
+                }
+            },
+            None if self.stft_frame_size.is_some() => problems.push(String::from("The STFT frame size is set, but the STFT hop is not.")),
+            None => ()
+        }
+        problems
+    }
+}
+
+fn default_onset_threshold() -> f64 {
+    0.1
+}
+
+fn default_num_mel_bands() -> usize {
+    40
+}
+
+fn default_num_mfccs() -> usize {
+    13
+}
+
+fn default_num_spectral_peaks() -> usize {
+    8
+}
+
+fn default_memory_budget_mb() -> usize {
+    1024
+}
+
+// The estimated memory used per audio chunk frame while a chunk is processed: the chunk itself,
+// its high-pass filtered copy, and the windowed grains, at 8 bytes per sample.
+const BYTES_PER_CHUNK_FRAME: usize = 8 * 4;
+
+// The shortest chunk, in frames, that can be chosen from the memory budget without a warning (10 seconds at 48 kHz).
+// Shorter chunks work, but they are slow to process, since neighboring chunks overlap by at least a second.
+const MIN_AUTO_CHUNK_SIZE: usize = 480000;
+
+/// The maximum length of an audio chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkSize {
+    Frames(usize),
+    /// A duration in seconds, which is converted to frames at each file's sample rate
+    Seconds(f64),
+}
+
+impl ChunkSize {
+    /// Gets the chunk size in frames for a file with the given sample rate
+    pub fn frames(&self, sample_rate: u32) -> usize {
+        match self {
+            ChunkSize::Frames(x) => *x,
+            ChunkSize::Seconds(x) => usize::max((x * sample_rate as f64).round() as usize, 1)
+        }
+    }
+}
+
+impl fmt::Display for ChunkSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkSize::Frames(x) => write!(f, "{} frames", x),
+            ChunkSize::Seconds(x) => write!(f, "{} seconds", x)
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GranulatorConfig {
     pub database_path: String,
     pub audio_source_directory: String,
-    pub grain_profiles: Vec<HashMap<String, usize>>,
-    pub max_audio_chunk_size: usize,
+    pub grain_profiles: Vec<GrainProfile>,
+    /// The maximum audio chunk length in frames
+    #[serde(default)]
+    pub max_audio_chunk_size: Option<usize>,
+    /// The maximum audio chunk length in seconds. This can be set instead of `max_audio_chunk_size`.
+    #[serde(default)]
+    pub max_audio_chunk_seconds: Option<f64>,
+    /// The approximate memory in megabytes that audio chunks may use while they are processed. If neither
+    /// chunk length is set, the chunk length is chosen so that one chunk per thread fits in this budget.
+    #[serde(default = "default_memory_budget_mb")]
+    pub memory_budget_mb: usize,
     pub max_num_threads: usize,
+    /// The number of mel bands stored for each grain
+    #[serde(default = "default_num_mel_bands")]
+    pub num_mel_bands: usize,
+    /// The number of MFCCs stored for each grain. This cannot be larger than `num_mel_bands`.
+    #[serde(default = "default_num_mfccs")]
+    pub num_mfccs: usize,
+    /// The number of spectral peaks stored for each grain
+    #[serde(default = "default_num_spectral_peaks")]
+    pub num_spectral_peaks: usize,
+    /// Tags that are added to grains automatically when they are extracted. Each tag has a condition
+    /// on the grain features, such as `spectral_centroid > 3000`.
+    #[serde(default)]
+    pub tag_rules: BTreeMap<String, tags::RuleCondition>,
+    /// Whether to tag each grain with its nearest note, such as `note:C4`
+    #[serde(default)]
+    pub note_tags: bool,
+    /// How grains are compared by the nearest neighbor index
+    #[serde(default)]
+    pub similarity: similarity::SimilaritySettings,
 }
 
 /// Finds all files in a directory and its subdirectories
@@ -25,42 +306,330 @@ pub fn find_audio(directory: &str) -> Vec<String> {
     for extension in extensions {
         let pattern = format!("{}/**/*.{}", directory, extension);
         let entries = glob(&pattern);
-        match entries {
-            Ok(paths) => {
-                for entry in paths {
-                    match entry {
-                        Ok(path) => {
-                            let path = match path.to_str() {
-                                Some(x) => String::from(x),
-                                None => String::from("")
-                            };
-                            file_paths.push(path);
-                        },
-                        Err(_) => ()
-                    };
-                }
-            },
-            Err(_) => ()
+        if let Ok(paths) = entries {
+            for path in paths.flatten() {
+                let path = match path.to_str() {
+                    Some(x) => String::from(x),
+                    None => String::from("")
+                };
+                file_paths.push(path);
+            }
         }
     }
     file_paths
 }
 
+/// The size and modification time of a source file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileStat {
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub mtime: i64,
+}
+
+/// Gets the size and modification time of a source file. If the file has label files next to it,
+/// they are included, so that a changed label file counts as a change to the source file.
+pub fn stat_file(path: &str) -> std::io::Result<FileStat> {
+    let mut stat = FileStat{size: 0, mtime: 0};
+    for file_path in std::iter::once(PathBuf::from(path)).chain(labels::sidecar_paths(path)) {
+        let metadata = fs::metadata(file_path)?;
+        let mtime = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+            Ok(x) => x.as_secs() as i64,
+            Err(_) => 0
+        };
+        stat.size += metadata.len();
+        stat.mtime = i64::max(stat.mtime, mtime);
+    }
+    Ok(stat)
+}
+
+/// An audio file that is decoded a packet at a time, so that the whole file never has to be in memory.
+/// Decoded audio is mixed down to mono.
+pub struct AudioStream {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    pub sample_rate: u32,
+}
+
+impl AudioStream {
+    /// Opens an audio file for decoding
+    pub fn open(path: &str) -> Result<AudioStream, String> {
+        let src = match fs::File::open(path) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("Error opening file {}: {}", path, err))
+        };
+        let mss = MediaSourceStream::new(Box::new(src), Default::default());
+        let probed = match symphonia::default::get_probe().format(&Hint::new(), mss, &FormatOptions::default(), &MetadataOptions::default()) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("Error reading file {}: {}", path, err))
+        };
+        let format = probed.format;
+        let track = match format.tracks().iter().find(|t| t.codec_params.codec != CODEC_TYPE_NULL) {
+            Some(x) => x,
+            None => return Err(format!("Error reading file {}: there is no audio track", path))
+        };
+        let sample_rate = match track.codec_params.sample_rate {
+            Some(x) => x,
+            None => return Err(format!("Error reading file {}: the sample rate is unknown", path))
+        };
+        let decoder = match symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default()) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("Error reading file {}: {}", path, err))
+        };
+        let track_id = track.id;
+        Ok(AudioStream{format, decoder, track_id, sample_rate})
+    }
+
+    /// Decodes the next packet of audio and mixes it down to mono.
+    /// Returns `None` at the end of the file.
+    pub fn next_block(&mut self) -> Option<Result<Vec<f64>, String>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(x) => x,
+                Err(SymphoniaError::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+                Err(SymphoniaError::ResetRequired) => return None,
+                Err(err) => return Some(Err(format!("Error decoding audio: {}", err)))
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(x) => x,
+                Err(err) => return Some(Err(format!("Error decoding audio: {}", err)))
+            };
+            let num_channels = decoded.spec().channels.count();
+            let num_frames = decoded.frames();
+            let mut buffer = SampleBuffer::<f64>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_planar_ref(decoded);
+            let samples = buffer.samples();
+            let mut block = vec![0.0; num_frames];
+            for channel in 0..num_channels {
+                for (out, sample) in block.iter_mut().zip(samples[channel * num_frames..(channel + 1) * num_frames].iter()) {
+                    *out += sample;
+                }
+            }
+            if num_channels > 1 {
+                block.iter_mut().for_each(|x| *x /= num_channels as f64);
+            }
+            return Some(Ok(block));
+        }
+    }
+}
+
+/// Reads an entire audio file and mixes it down to mono.
+/// Returns the sample rate and the samples.
+pub fn read_mono(path: &str) -> Result<(u32, Vec<f64>), String> {
+    let mut stream = AudioStream::open(path)?;
+    let mut samples: Vec<f64> = Vec::new();
+    while let Some(block) = stream.next_block() {
+        samples.extend(block?);
+    }
+    Ok((stream.sample_rate, samples))
+}
+
+/// Computes the SHA-256 hash of a source file's contents, as a hex string. The contents of its label files
+/// are hashed after the source file, so the hash of a file without label files is the hash of the file alone.
+pub fn hash_file(path: &str) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    for file_path in std::iter::once(PathBuf::from(path)).chain(labels::sidecar_paths(path)) {
+        let mut file = fs::File::open(file_path)?;
+        std::io::copy(&mut file, &mut hasher)?;
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl GranulatorConfig {
+    /// A key that identifies the analysis settings that apply to every grain profile, such as the number of MFCCs
+    /// and the tag rules. It is stored with each file record, and files are processed again if it changes.
+    pub fn analysis_key(&self) -> String {
+        let settings = serde_json::json!({
+            "num_mel_bands": self.num_mel_bands,
+            "num_mfccs": self.num_mfccs,
+            "num_spectral_peaks": self.num_spectral_peaks,
+            "tag_rules": self.tag_rules,
+            "note_tags": self.note_tags
+        });
+        Sha256::digest(settings.to_string().as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Checks the configuration. If `for_extraction` is true, the settings that are only needed for grain
+    /// extraction (the audio source directory and the grain profiles) are checked too.
+    /// Returns a description of every problem found.
+    pub fn validate(&self, for_extraction: bool) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.database_path.is_empty() {
+            problems.push(String::from("The database path is empty."));
+        }
+        if self.max_audio_chunk_size.is_some() && self.max_audio_chunk_seconds.is_some() {
+            problems.push(String::from("Both max_audio_chunk_size and max_audio_chunk_seconds are set. Only one of them can be used."));
+        }
+        if self.max_audio_chunk_size == Some(0) {
+            problems.push(String::from("The maximum audio chunk size is 0."));
+        }
+        if let Some(seconds) = self.max_audio_chunk_seconds {
+            if seconds.is_nan() || seconds <= 0.0 {
+                problems.push(format!("The maximum audio chunk length {} seconds is not greater than 0.", seconds));
+            }
+        }
+        if self.memory_budget_mb == 0 {
+            problems.push(String::from("The memory budget is 0."));
+        }
+        if self.num_mel_bands == 0 {
+            problems.push(String::from("The number of mel bands is 0."));
+        }
+        if self.num_mfccs > self.num_mel_bands {
+            problems.push(format!("The number of MFCCs ({}) is larger than the number of mel bands ({}).", self.num_mfccs, self.num_mel_bands));
+        }
+        for problem in self.similarity.validate() {
+            problems.push(format!("Similarity: {}", problem));
+        }
+        for (tag, condition) in self.tag_rules.iter() {
+            if tag.is_empty() {
+                problems.push(String::from("A tagging rule has an empty tag."));
+            }
+            for comparison in condition.comparisons() {
+                if !sqlite::GRAIN_COLUMNS.contains(&comparison.column.as_str()) {
+                    problems.push(format!("The tagging rule for {} uses the unknown feature {}.", tag, comparison.column));
+                }
+            }
+        }
+        if for_extraction {
+            if !Path::new(&self.audio_source_directory).is_dir() {
+                problems.push(format!("The audio source directory {} does not exist.", self.audio_source_directory));
+            }
+            if self.grain_profiles.is_empty() {
+                problems.push(String::from("There are no grain profiles."));
+            }
+            for (i, profile) in self.grain_profiles.iter().enumerate() {
+                for problem in profile.validate() {
+                    problems.push(format!("Grain profile {}: {}", i, problem));
+                }
+                if let Some(j) = self.grain_profiles[..i].iter().position(|p| p.key() == profile.key()) {
+                    problems.push(format!("Grain profile {}: It is the same as grain profile {}.", i, j));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Gets the maximum audio chunk length. If it isn't set in the configuration, it is derived from the
+    /// memory budget and the number of threads, so `max_num_threads` should be resolved before calling this.
+    pub fn chunk_size(&self) -> ChunkSize {
+        if let Some(frames) = self.max_audio_chunk_size {
+            return ChunkSize::Frames(frames);
+        }
+        if let Some(seconds) = self.max_audio_chunk_seconds {
+            return ChunkSize::Seconds(seconds);
+        }
+        ChunkSize::Frames(usize::max(self.budget_chunk_frames(), 1))
+    }
+
+    /// Gets the chunk length in frames that lets one chunk per thread fit in the memory budget
+    fn budget_chunk_frames(&self) -> usize {
+        self.memory_budget_mb * 1024 * 1024 / (usize::max(self.max_num_threads, 1) * BYTES_PER_CHUNK_FRAME)
+    }
+
+    /// Gets a warning if the chunk length comes from the memory budget and the budget is too small for chunks
+    /// of `MIN_AUTO_CHUNK_SIZE` frames. Like `chunk_size`, this depends on `max_num_threads`.
+    pub fn memory_budget_warning(&self) -> Option<String> {
+        if self.max_audio_chunk_size.is_some() || self.max_audio_chunk_seconds.is_some() || self.budget_chunk_frames() >= MIN_AUTO_CHUNK_SIZE {
+            return None;
+        }
+        let threads = usize::max(self.max_num_threads, 1);
+        let min_budget_mb = (MIN_AUTO_CHUNK_SIZE * threads * BYTES_PER_CHUNK_FRAME).div_ceil(1024 * 1024);
+        Some(format!("Warning: a memory budget of {} MB for {} threads only allows chunks of {} frames, which are slow to process. \
+            Set memory_budget_mb to at least {} for chunks of {} frames.",
+            self.memory_budget_mb, threads, self.budget_chunk_frames(), min_budget_mb, MIN_AUTO_CHUNK_SIZE))
+    }
+}
+
 /// Reads the configuration for the granulator
-pub fn read_config(config_file_path: &str) -> GranulatorConfig {
+pub fn read_config(config_file_path: &str) -> Result<GranulatorConfig, ConfigError> {
     let config_contents = match fs::read_to_string(config_file_path) {
         Ok(x) => x,
-        Err(err) => {
-            println!("Error reading file: {}", err.to_string());
-            String::from("")
-        }
+        Err(err) => return Err(ConfigError::Read(format!("Error reading configuration file {}: {}", config_file_path, err)))
     };
-    let json_contents: GranulatorConfig = match serde_json::from_str(&config_contents){
-        Ok(x) => x,
-        Err(err) => {
-            println!("Error parsing JSON file: {}", err.to_string());
-            GranulatorConfig{database_path: String::from("grains.sqlite3"), audio_source_directory: String::from("."), grain_profiles: Vec::new(), max_audio_chunk_size: 44100 * 60, max_num_threads: 0}
+    match serde_json::from_str(&config_contents) {
+        Ok(x) => Ok(x),
+        Err(err) => Err(ConfigError::Parse(format!("Error parsing configuration file {}: {}", config_file_path, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<GranulatorConfig, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config = parse(r#"{
+            "database_path": "",
+            "audio_source_directory": "/nonexistent/grain_processor_audio",
+            "max_num_threads": 0,
+            "grain_profiles": [
+                {"grain_size": 0, "grain_spacing": 1024},
+                {"grain_size": 2048, "grain_spacing": 1024, "onset_threshold": 2.0},
+                {"grain_size": 2048, "grain_spacing": 1024, "onset_threshold": 2.0}
+            ],
+            "num_mel_bands": 10,
+            "num_mfccs": 20
+        }"#).unwrap();
+        let problems = config.validate(true);
+        let expected = [
+            "The database path is empty.",
+            "The number of MFCCs (20) is larger than the number of mel bands (10).",
+            "The audio source directory /nonexistent/grain_processor_audio does not exist.",
+            "Grain profile 0: The grain size is 0.",
+            "Grain profile 1: The onset threshold 2 is not between 0.0 and 1.0.",
+            "Grain profile 2: It is the same as grain profile 1."
+        ];
+        for problem in expected.iter() {
+            assert!(problems.iter().any(|p| p == problem), "{:?} is missing from {:?}", problem, problems);
         }
-    };
-    json_contents
+        // The extraction settings are only checked for extraction
+        assert_eq!(config.validate(false).len(), 2);
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        let config = parse(&format!(r#"{{
+            "database_path": "grains.sqlite3",
+            "audio_source_directory": {:?},
+            "max_num_threads": 0,
+            "grain_profiles": [{{"grain_size": 2048, "grain_spacing": 1024}}]
+        }}"#, std::env::temp_dir().to_string_lossy())).unwrap();
+        assert_eq!(config.validate(true), Vec::<String>::new());
+    }
+
+    #[test]
+    fn memory_budget_is_honoured() {
+        let mut config = parse(r#"{"database_path": "grains.sqlite3", "audio_source_directory": ".", "grain_profiles": [], "max_num_threads": 4}"#).unwrap();
+        assert_eq!(config.chunk_size(), ChunkSize::Frames(1024 * 1024 * 1024 / (4 * BYTES_PER_CHUNK_FRAME)));
+        assert!(config.memory_budget_warning().is_none());
+
+        config.memory_budget_mb = 16;
+        assert_eq!(config.chunk_size(), ChunkSize::Frames(16 * 1024 * 1024 / (4 * BYTES_PER_CHUNK_FRAME)));
+        let warning = config.memory_budget_warning().expect("a small budget should give a warning");
+        assert!(warning.contains("at least 59 "), "{}", warning);
+
+        // A chunk size that is set explicitly doesn't depend on the budget
+        config.max_audio_chunk_seconds = Some(5.0);
+        assert_eq!(config.chunk_size(), ChunkSize::Seconds(5.0));
+        assert!(config.memory_budget_warning().is_none());
+    }
+
+    #[test]
+    fn misspelled_profile_keys_are_rejected() {
+        let err = parse(r#"{
+            "database_path": "grains.sqlite3",
+            "audio_source_directory": ".",
+            "max_num_threads": 0,
+            "grain_profiles": [{"grain_size": 2048, "grain_spacing": 1024, "onset_treshold": 0.2}]
+        }"#).err().expect("the misspelled key should be rejected");
+        assert!(err.to_string().contains("onset_treshold"), "{}", err);
+    }
 }
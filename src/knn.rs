@@ -0,0 +1,635 @@
+// File: knn.rs
+// This file has the nearest neighbor index, which finds the grains with the most similar features
+// without scanning the grains table. It is a vantage point tree over normalized grain features, and it
+// is stored in a file next to the database.
+
+use crate::grain_extractor::GrainEntry;
+use crate::similarity::{CorpusStats, Similarity, SimilaritySettings};
+use crate::sqlite;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::collections::{BinaryHeap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+// Identifies index files, and the version of their format
+const INDEX_MAGIC: &[u8; 8] = b"GPKNN\0\0\x02";
+
+// The index is rebuilt when the grains added or deleted since the last build are more than this
+// fraction of the grains in the tree
+const REBUILD_FRACTION: f64 = 0.25;
+
+// The index is never rebuilt for fewer changes than this, since a few unsorted grains are fast to scan
+const MIN_REBUILD_CHANGES: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub enum IndexError {
+    Database(String),
+    File(String)
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexError::Database(msg) => write!(f, "{}", msg),
+            IndexError::File(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+/// Gets the path of the index file for a database
+pub fn index_path(db: &str) -> String {
+    format!("{}.knn", db)
+}
+
+/// What to find the nearest grains to: a grain in the database, or feature values
+#[derive(Debug, Clone, PartialEq)]
+pub enum NearestTarget {
+    Grain(i64),
+    /// Feature values by column name. Features that are left out count as the corpus mean.
+    Features(Vec<(String, f64)>)
+}
+
+impl FromStr for NearestTarget {
+    type Err = String;
+
+    /// Parses a grain id, or a list of features such as `spectral_centroid=3000,midi=60`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.trim().parse::<i64>() {
+            return Ok(NearestTarget::Grain(id));
+        }
+        let mut features: Vec<(String, f64)> = Vec::new();
+        for item in s.split(',') {
+            let (column, value) = match item.split_once('=') {
+                Some((column, value)) => (column.trim(), value.trim()),
+                None => return Err(format!("Expected a grain id or a list of features such as spectral_centroid=3000,midi=60, but got {}", s))
+            };
+            if !sqlite::feature_columns().contains(&column) {
+                return Err(format!("Unknown feature {}", column));
+            }
+            match value.parse::<f64>() {
+                Ok(x) => features.push((String::from(column), x)),
+                Err(_) => return Err(format!("Invalid value for feature {}: {}", column, value))
+            }
+        }
+        Ok(NearestTarget::Features(features))
+    }
+}
+
+/// A nearest neighbor index over the grains in a database.
+///
+/// The first `tree_len` grains are stored in vantage point tree order: the grain at the start of each
+/// range is the vantage point, the first half of the rest are the grains within `thresholds` of it, and
+/// the second half are the grains farther away. Grains added since the tree was built are stored after
+/// the tree and are scanned, and grains deleted since then are skipped. Deleted grains are tracked by their
+/// position in the tree rather than their id, so that a grain id can't hide a newer grain with the same id.
+pub struct GrainIndex {
+    /// The settings the index was built with
+    settings: SimilaritySettings,
+    /// The feature normalization, which is fixed when the tree is built so that the stored vectors stay comparable
+    similarity: Similarity,
+    dims: usize,
+    tree_len: usize,
+    ids: Vec<i64>,
+    vectors: Vec<f64>,
+    thresholds: Vec<f64>,
+    /// The positions in the tree of the grains that were deleted
+    deleted: HashSet<usize>,
+}
+
+/// A search result, ordered by distance so that the farthest result is at the top of a max-heap
+#[derive(PartialEq)]
+struct Neighbor {
+    distance: f64,
+    id: i64,
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.distance.total_cmp(&other.distance).then(self.id.cmp(&other.id))
+    }
+}
+
+impl GrainIndex {
+    /// Builds an index over all grains in a database, using the corpus statistics in the database for normalization
+    pub fn build(db: &str, settings: &SimilaritySettings) -> Result<GrainIndex, IndexError> {
+        let stats = match sqlite::load_feature_stats(db, settings) {
+            Ok(x) => x,
+            Err(err) => return Err(IndexError::Database(format!("Error loading feature statistics: {}", err)))
+        };
+        let grains = match sqlite::select_grain_entries_with_ids(db, &sqlite::GrainQuery::default()) {
+            Ok(x) => x,
+            Err(err) => return Err(IndexError::Database(format!("Error loading grains: {}", err)))
+        };
+        Ok(GrainIndex::from_grains(settings, &stats, &grains))
+    }
+
+    /// Builds an index over grains, using corpus statistics for normalization
+    pub fn from_grains(settings: &SimilaritySettings, stats: &CorpusStats, grains: &[(i64, GrainEntry)]) -> GrainIndex {
+        let similarity = Similarity::new(settings, stats);
+        let mut index = GrainIndex{
+            settings: settings.clone(),
+            dims: settings.features().len(),
+            similarity,
+            tree_len: 0,
+            ids: Vec::with_capacity(grains.len()),
+            vectors: Vec::with_capacity(grains.len() * settings.features().len()),
+            thresholds: Vec::new(),
+            deleted: HashSet::new()
+        };
+        for (id, grain) in grains.iter() {
+            index.ids.push(*id);
+            index.vectors.extend(index.similarity.features(grain));
+        }
+        index.build_tree();
+        index
+    }
+
+    /// Gets the feature normalization of the index. Query vectors must be normalized with it.
+    pub fn similarity(&self) -> &Similarity {
+        &self.similarity
+    }
+
+    /// The number of grains that were added since the tree was built, and the number that were deleted
+    pub fn num_changes(&self) -> (usize, usize) {
+        (self.ids.len() - self.tree_len, self.deleted.len())
+    }
+
+    fn vector(&self, i: usize) -> &[f64] {
+        &self.vectors[i * self.dims..(i + 1) * self.dims]
+    }
+
+    /// Arranges all grains into the vantage point tree
+    fn build_tree(&mut self) {
+        let mut order: Vec<usize> = (0..self.ids.len()).collect();
+        let mut thresholds: Vec<f64> = vec![0.0; order.len()];
+        // The vantage points are chosen at random, but the same grains always give the same tree
+        let mut rng = StdRng::seed_from_u64(order.len() as u64);
+        let mut stack: Vec<(usize, usize)> = vec![(0, order.len())];
+        while let Some((lo, hi)) = stack.pop() {
+            if hi - lo < 2 {
+                continue;
+            }
+            order.swap(lo, rng.gen_range(lo..hi));
+            let vantage = self.vector(order[lo]);
+            let mut distances: Vec<(f64, usize)> = order[lo + 1..hi].iter()
+                .map(|&i| (self.similarity.metric_distance(vantage, self.vector(i)), i))
+                .collect();
+            let mid = distances.len() / 2;
+            distances.select_nth_unstable_by(mid, |a, b| a.0.total_cmp(&b.0));
+            thresholds[lo] = distances[mid].0;
+            for (j, (_, i)) in distances.into_iter().enumerate() {
+                order[lo + 1 + j] = i;
+            }
+            stack.push((lo + 1, lo + 1 + mid));
+            stack.push((lo + 1 + mid, hi));
+        }
+
+        self.ids = order.iter().map(|&i| self.ids[i]).collect();
+        self.vectors = order.iter().flat_map(|&i| self.vector(i).to_vec()).collect();
+        self.thresholds = thresholds;
+        self.tree_len = self.ids.len();
+    }
+
+    /// Finds the `k` grains nearest to a normalized feature vector, among the grains for which `filter` is true.
+    /// Returns the grain ids and distances, nearest first.
+    pub fn nearest(&self, target: &[f64], k: usize, filter: impl Fn(i64) -> bool) -> Vec<(i64, f64)> {
+        let mut heap: BinaryHeap<Neighbor> = BinaryHeap::with_capacity(k + 1);
+        if k == 0 || target.len() != self.dims {
+            return Vec::new();
+        }
+        let consider = |heap: &mut BinaryHeap<Neighbor>, i: usize, distance: f64| {
+            let id = self.ids[i];
+            if (i < self.tree_len && self.deleted.contains(&i)) || !filter(id) {
+                return;
+            }
+            heap.push(Neighbor{distance, id});
+            if heap.len() > k {
+                heap.pop();
+            }
+        };
+        let radius = |heap: &BinaryHeap<Neighbor>| -> f64 {
+            if heap.len() < k { f64::INFINITY } else { heap.peek().map(|x| x.distance).unwrap_or(f64::INFINITY) }
+        };
+
+        let mut stack: Vec<(usize, usize)> = vec![(0, self.tree_len)];
+        while let Some((lo, hi)) = stack.pop() {
+            if lo >= hi {
+                continue;
+            }
+            let distance = self.similarity.metric_distance(target, self.vector(lo));
+            consider(&mut heap, lo, distance);
+            if hi - lo < 2 {
+                continue;
+            }
+            let mid = lo + 1 + (hi - lo - 1) / 2;
+            let threshold = self.thresholds[lo];
+            let tau = radius(&heap);
+            // The nearer side is pushed last, so it is searched first and shrinks the radius for the other side
+            if distance < threshold {
+                if distance + tau >= threshold {
+                    stack.push((mid, hi));
+                }
+                stack.push((lo + 1, mid));
+            } else {
+                if distance - tau <= threshold {
+                    stack.push((lo + 1, mid));
+                }
+                stack.push((mid, hi));
+            }
+        }
+        for i in self.tree_len..self.ids.len() {
+            let distance = self.similarity.metric_distance(target, self.vector(i));
+            consider(&mut heap, i, distance);
+        }
+
+        heap.into_sorted_vec().into_iter().map(|x| (x.id, self.similarity.convert_metric_distance(x.distance))).collect()
+    }
+
+    /// Finds the grains within `radius` of a normalized feature vector, among the grains for which `filter` is true.
+    /// The radius is a distance from `Similarity::distance`. Returns the grain ids and distances, nearest first.
+    pub fn within(&self, target: &[f64], radius: f64, filter: impl Fn(i64) -> bool) -> Vec<(i64, f64)> {
+        if target.len() != self.dims {
+            return Vec::new();
+        }
+        let mut found: Vec<Neighbor> = Vec::new();
+        let tau = self.similarity.metric_radius(radius);
+        let mut consider = |i: usize, distance: f64| {
+            let id = self.ids[i];
+            if distance <= tau && !(i < self.tree_len && self.deleted.contains(&i)) && filter(id) {
+                found.push(Neighbor{distance, id});
+            }
+        };
+
+        let mut stack: Vec<(usize, usize)> = vec![(0, self.tree_len)];
+        while let Some((lo, hi)) = stack.pop() {
+            if lo >= hi {
+                continue;
+            }
+            let distance = self.similarity.metric_distance(target, self.vector(lo));
+            consider(lo, distance);
+            if hi - lo < 2 {
+                continue;
+            }
+            let mid = lo + 1 + (hi - lo - 1) / 2;
+            let threshold = self.thresholds[lo];
+            if distance - tau <= threshold {
+                stack.push((lo + 1, mid));
+            }
+            if distance + tau >= threshold {
+                stack.push((mid, hi));
+            }
+        }
+        for i in self.tree_len..self.ids.len() {
+            let distance = self.similarity.metric_distance(target, self.vector(i));
+            consider(i, distance);
+        }
+
+        found.sort();
+        found.into_iter().map(|x| (x.id, self.similarity.convert_metric_distance(x.distance))).collect()
+    }
+
+    /// Loads an index file. Returns `None` if the file doesn't exist.
+    pub fn load(path: &str) -> Result<Option<GrainIndex>, IndexError> {
+        let file = match File::open(path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(IndexError::File(format!("Error opening index {}: {}", path, err)))
+        };
+        match read_index(&mut BufReader::new(file)) {
+            Ok(x) => Ok(Some(x)),
+            Err(err) => Err(IndexError::File(format!("Error reading index {}: {}", path, err)))
+        }
+    }
+
+    /// Saves the index to a file. The index is written to a temporary file first, so an interrupted
+    /// save doesn't leave a damaged index.
+    pub fn save(&self, path: &str) -> Result<(), IndexError> {
+        let temp_path = format!("{}.tmp", path);
+        let result = File::create(&temp_path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write_index(self, &mut writer)?;
+            writer.flush()
+        }).and_then(|_| fs::rename(&temp_path, path));
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(IndexError::File(format!("Error writing index {}: {}", path, err)))
+        }
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads an index. The file has a magic number, the settings and normalization as JSON, and then
+/// the grain ids, feature vectors, tree thresholds and the tree positions of deleted grains, all little-endian.
+fn read_index(reader: &mut impl Read) -> std::io::Result<GrainIndex> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != INDEX_MAGIC {
+        return Err(invalid_data("not a grain index file, or an unsupported version"));
+    }
+    let mut header = vec![0u8; read_u64(reader)? as usize];
+    reader.read_exact(&mut header)?;
+    let (settings, similarity): (SimilaritySettings, Similarity) = match serde_json::from_slice(&header) {
+        Ok(x) => x,
+        Err(err) => return Err(invalid_data(&err.to_string()))
+    };
+    let dims = read_u64(reader)? as usize;
+    let tree_len = read_u64(reader)? as usize;
+    let num_grains = read_u64(reader)? as usize;
+    let num_deleted = read_u64(reader)? as usize;
+    if tree_len > num_grains {
+        return Err(invalid_data("the tree is larger than the index"));
+    }
+    let mut ids: Vec<i64> = Vec::with_capacity(num_grains);
+    for _ in 0..num_grains {
+        ids.push(read_u64(reader)? as i64);
+    }
+    let mut vectors: Vec<f64> = Vec::with_capacity(num_grains * dims);
+    for _ in 0..num_grains * dims {
+        vectors.push(f64::from_bits(read_u64(reader)?));
+    }
+    let mut thresholds: Vec<f64> = Vec::with_capacity(tree_len);
+    for _ in 0..tree_len {
+        thresholds.push(f64::from_bits(read_u64(reader)?));
+    }
+    let mut deleted: HashSet<usize> = HashSet::with_capacity(num_deleted);
+    for _ in 0..num_deleted {
+        let i = read_u64(reader)? as usize;
+        if i >= tree_len {
+            return Err(invalid_data("a deleted grain is outside the tree"));
+        }
+        deleted.insert(i);
+    }
+    Ok(GrainIndex{settings, similarity, dims, tree_len, ids, vectors, thresholds, deleted})
+}
+
+/// Writes an index in the format that `read_index` reads
+fn write_index(index: &GrainIndex, writer: &mut impl Write) -> std::io::Result<()> {
+    let header = match serde_json::to_vec(&(&index.settings, &index.similarity)) {
+        Ok(x) => x,
+        Err(err) => return Err(invalid_data(&err.to_string()))
+    };
+    writer.write_all(INDEX_MAGIC)?;
+    writer.write_all(&(header.len() as u64).to_le_bytes())?;
+    writer.write_all(&header)?;
+    for x in [index.dims, index.tree_len, index.ids.len(), index.deleted.len()] {
+        writer.write_all(&(x as u64).to_le_bytes())?;
+    }
+    for id in index.ids.iter() {
+        writer.write_all(&id.to_le_bytes())?;
+    }
+    for x in index.vectors.iter().chain(index.thresholds.iter()) {
+        writer.write_all(&x.to_bits().to_le_bytes())?;
+    }
+    for i in index.deleted.iter() {
+        writer.write_all(&(*i as u64).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Brings the index of a database up to date with its grains, and returns it. If there is no index yet, or it was
+/// built with different settings, it is built from scratch. Otherwise, grains that were added since the last update
+/// are added to the index and grains that were deleted are marked as deleted, and the tree is only rebuilt once
+/// there are enough changes. The index file is saved if anything changed.
+pub fn update_index(db: &str, settings: &SimilaritySettings) -> Result<GrainIndex, IndexError> {
+    let (index, changed) = refresh_index(db, settings)?;
+    if changed {
+        index.save(&index_path(db))?;
+    }
+    Ok(index)
+}
+
+/// Gets an index that is up to date with the grains of a database in the same way as `update_index`, but
+/// doesn't save it. This is for dry runs, which must not write anything, and for searches with other settings
+/// than the index file, which would otherwise replace it.
+pub fn current_index(db: &str, settings: &SimilaritySettings) -> Result<GrainIndex, IndexError> {
+    let (index, _) = refresh_index(db, settings)?;
+    Ok(index)
+}
+
+/// Loads the index of a database and brings it up to date with its grains. Returns the index, and whether it
+/// differs from the index file.
+fn refresh_index(db: &str, settings: &SimilaritySettings) -> Result<(GrainIndex, bool), IndexError> {
+    let path = index_path(db);
+    let existing = match GrainIndex::load(&path) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}. The index will be rebuilt.", err);
+            None
+        }
+    };
+    let mut index = match existing {
+        Some(x) if serde_json::to_value(&x.settings).ok() == serde_json::to_value(settings).ok() => x,
+        _ => return Ok((GrainIndex::build(db, settings)?, true))
+    };
+
+    let db_ids: HashSet<i64> = match sqlite::select_grain_ids(db, &sqlite::GrainQuery::default()) {
+        Ok(x) => x.into_iter().collect(),
+        Err(err) => return Err(IndexError::Database(format!("Error loading grain ids: {}", err)))
+    };
+    // Grains that were deleted from the tree don't count as indexed, so their ids can be added again
+    let indexed_ids: HashSet<i64> = index.ids.iter().enumerate()
+        .filter(|(i, _)| !index.deleted.contains(i))
+        .map(|(_, id)| *id)
+        .collect();
+    let mut added: Vec<i64> = db_ids.iter().filter(|id| !indexed_ids.contains(id)).copied().collect();
+    added.sort_unstable();
+    let removed: HashSet<i64> = indexed_ids.iter().filter(|id| !db_ids.contains(id)).copied().collect();
+    if added.is_empty() && removed.is_empty() {
+        return Ok((index, false));
+    }
+
+    // Grains are deleted from the unsorted part right away, and marked as deleted in the tree
+    for (i, id) in index.ids[..index.tree_len].iter().enumerate() {
+        if removed.contains(id) {
+            index.deleted.insert(i);
+        }
+    }
+    let mut i = index.tree_len;
+    while i < index.ids.len() {
+        if removed.contains(&index.ids[i]) {
+            index.ids.swap_remove(i);
+            let last = index.ids.len();
+            for d in 0..index.dims {
+                index.vectors.swap(i * index.dims + d, last * index.dims + d);
+            }
+            index.vectors.truncate(last * index.dims);
+        } else {
+            i += 1;
+        }
+    }
+
+    let grains = match sqlite::select_grain_entries_by_id(db, &added) {
+        Ok(x) => x,
+        Err(err) => return Err(IndexError::Database(format!("Error loading grains: {}", err)))
+    };
+    for (id, grain) in grains.iter() {
+        index.ids.push(*id);
+        index.vectors.extend(index.similarity.features(grain));
+    }
+
+    let (num_added, num_deleted) = index.num_changes();
+    let num_changes = num_added + num_deleted;
+    if num_changes > MIN_REBUILD_CHANGES && num_changes as f64 > index.tree_len as f64 * REBUILD_FRACTION {
+        // The corpus has changed enough that the normalization is refreshed too
+        index = GrainIndex::build(db, settings)?;
+    }
+    Ok((index, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::DistanceMetric;
+
+    /// Makes an index over random feature vectors, without a database
+    fn random_index(metric: DistanceMetric, num_grains: usize, seed: u64) -> GrainIndex {
+        let settings = SimilaritySettings{metric, ..Default::default()};
+        let dims = settings.features().len();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut index = GrainIndex{
+            similarity: Similarity::new(&settings, &CorpusStats::default()),
+            settings,
+            dims,
+            tree_len: 0,
+            ids: (0..num_grains as i64).map(|i| i * 3 + 7).collect(),
+            vectors: (0..num_grains * dims).map(|_| rng.gen_range(-2.0..2.0)).collect(),
+            thresholds: Vec::new(),
+            deleted: HashSet::new()
+        };
+        index.build_tree();
+        index
+    }
+
+    fn random_target(index: &GrainIndex, rng: &mut StdRng) -> Vec<f64> {
+        (0..index.dims).map(|_| rng.gen_range(-2.0..2.0)).collect()
+    }
+
+    /// Finds every grain for which `filter` is true by scanning all grains, nearest first
+    fn brute_force(index: &GrainIndex, target: &[f64], filter: impl Fn(i64) -> bool) -> Vec<(i64, f64)> {
+        let mut found: Vec<Neighbor> = (0..index.ids.len())
+            .filter(|i| !(*i < index.tree_len && index.deleted.contains(i)) && filter(index.ids[*i]))
+            .map(|i| Neighbor{distance: index.similarity.metric_distance(target, index.vector(i)), id: index.ids[i]})
+            .collect();
+        found.sort();
+        found.into_iter().map(|x| (x.id, index.similarity.convert_metric_distance(x.distance))).collect()
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for metric in [DistanceMetric::Euclidean, DistanceMetric::Manhattan, DistanceMetric::Cosine, DistanceMetric::Mahalanobis] {
+            let index = random_index(metric, 500, 2);
+            for k in [1, 5, 40] {
+                let target = random_target(&index, &mut rng);
+                let expected: Vec<(i64, f64)> = brute_force(&index, &target, |_| true).into_iter().take(k).collect();
+                assert_eq!(index.nearest(&target, k, |_| true), expected, "{:?}, k = {}", metric, k);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_applies_the_filter() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let index = random_index(DistanceMetric::Euclidean, 500, 4);
+        let target = random_target(&index, &mut rng);
+        let filter = |id: i64| id % 2 == 0;
+        let expected: Vec<(i64, f64)> = brute_force(&index, &target, filter).into_iter().take(10).collect();
+        assert_eq!(index.nearest(&target, 10, filter), expected);
+        assert!(index.nearest(&target, 10, |_| false).is_empty());
+    }
+
+    #[test]
+    fn nearest_returns_every_grain_when_k_is_large() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let index = random_index(DistanceMetric::Euclidean, 20, 6);
+        let target = random_target(&index, &mut rng);
+        assert_eq!(index.nearest(&target, 100, |_| true), brute_force(&index, &target, |_| true));
+        assert!(index.nearest(&target, 0, |_| true).is_empty());
+        assert!(index.nearest(&target[1..], 5, |_| true).is_empty());
+    }
+
+    #[test]
+    fn within_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for metric in [DistanceMetric::Euclidean, DistanceMetric::Manhattan, DistanceMetric::Cosine] {
+            let index = random_index(metric, 500, 8);
+            let target = random_target(&index, &mut rng);
+            let all = brute_force(&index, &target, |_| true);
+            // A radius that takes in about a tenth of the grains
+            let radius = all[all.len() / 10].1;
+            let expected: Vec<(i64, f64)> = all.into_iter().filter(|(_, distance)| *distance <= radius).collect();
+            assert!(!expected.is_empty());
+            assert_eq!(index.within(&target, radius, |_| true), expected, "{:?}", metric);
+        }
+    }
+
+    #[test]
+    fn deleted_and_added_grains_are_searched() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut index = random_index(DistanceMetric::Euclidean, 300, 10);
+        for i in (0..index.tree_len).step_by(4) {
+            index.deleted.insert(i);
+        }
+        // A grain that was deleted and added again keeps its id, but not its features
+        let readded = index.ids[0];
+        index.ids.push(readded);
+        index.vectors.extend((0..index.dims).map(|_| rng.gen_range(-2.0..2.0)));
+        for id in 10000..10050 {
+            index.ids.push(id);
+            index.vectors.extend((0..index.dims).map(|_| rng.gen_range(-2.0..2.0)));
+        }
+        assert_eq!(index.num_changes(), (51, 75));
+
+        for _ in 0..5 {
+            let target = random_target(&index, &mut rng);
+            let expected: Vec<(i64, f64)> = brute_force(&index, &target, |_| true).into_iter().take(15).collect();
+            assert_eq!(index.nearest(&target, 15, |_| true), expected);
+            let radius = expected[expected.len() - 1].1;
+            assert_eq!(index.within(&target, radius, |_| true), expected);
+        }
+        let target = index.vector(index.tree_len).to_vec();
+        assert_eq!(index.nearest(&target, 1, |_| true), vec![(readded, 0.0)]);
+    }
+
+    #[test]
+    fn index_round_trips_through_a_file() {
+        let mut index = random_index(DistanceMetric::Cosine, 100, 11);
+        index.deleted.insert(3);
+        index.ids.push(5000);
+        index.vectors.extend(vec![0.5; index.dims]);
+        let mut bytes: Vec<u8> = Vec::new();
+        write_index(&index, &mut bytes).unwrap();
+        let loaded = read_index(&mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded.dims, index.dims);
+        assert_eq!(loaded.tree_len, index.tree_len);
+        assert_eq!(loaded.ids, index.ids);
+        assert_eq!(loaded.vectors, index.vectors);
+        assert_eq!(loaded.thresholds, index.thresholds);
+        assert_eq!(loaded.deleted, index.deleted);
+        let target = vec![0.25; index.dims];
+        assert_eq!(loaded.nearest(&target, 10, |_| true), index.nearest(&target, 10, |_| true));
+
+        assert!(read_index(&mut &bytes[..bytes.len() - 1]).is_err());
+        bytes[7] = 1;
+        assert!(read_index(&mut bytes.as_slice()).is_err());
+    }
+}
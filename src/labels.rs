@@ -0,0 +1,466 @@
+// File: labels.rs
+// This file has label import. Regions marked in Audacity label tracks, Reaper region exports and
+// WAV cue chunks are read for each source file, and the grains that overlap a region get its label as a tag.
+
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// A labelled region of a source file. A marker at a single point has the same start and end frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub start_frame: usize,
+    pub end_frame: usize,
+    pub label: String,
+}
+
+impl Region {
+    /// Checks whether the region overlaps the frames `start_frame..end_frame`. A marker overlaps
+    /// the frames if it is inside them.
+    pub fn overlaps(&self, start_frame: usize, end_frame: usize) -> bool {
+        if self.end_frame > self.start_frame {
+            start_frame < self.end_frame && self.start_frame < end_frame
+        } else {
+            start_frame <= self.start_frame && self.start_frame < end_frame
+        }
+    }
+}
+
+/// Gets the label files that sit next to an audio file: an Audacity label track export with the same name
+/// and a `.txt` extension, and a Reaper region export with the same name and a `.csv` extension.
+/// Only the files that exist and parse as label files are returned, so that other files which happen to
+/// share the audio file's name, such as notes, are not mistaken for label files.
+pub fn sidecar_paths(audio_path: &str) -> Vec<PathBuf> {
+    ["txt", "csv"].iter()
+        .map(|extension| Path::new(audio_path).with_extension(extension))
+        .filter(|path| path.is_file() && read_sidecar(path, 1).is_ok())
+        .collect()
+}
+
+/// Reads a label file that sits next to an audio file, in the format given by its extension
+fn read_sidecar(path: &Path, sample_rate: u32) -> Result<Vec<Region>, String> {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("txt") => read_audacity_labels(path, sample_rate),
+        Some("csv") => read_reaper_regions(path, sample_rate),
+        _ => Err(format!("{} is not a label file", path.display()))
+    }
+}
+
+/// Converts a time in seconds to a frame
+fn seconds_to_frame(seconds: f64, sample_rate: u32) -> usize {
+    (seconds.max(0.0) * sample_rate as f64).round() as usize
+}
+
+/// Reads an Audacity label track export. Each line has a start time, an end time and a label,
+/// separated by tabs, with times in seconds. The frequency lines of spectral labels are skipped.
+pub fn read_audacity_labels(path: &Path, sample_rate: u32) -> Result<Vec<Region>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("Error reading label file {}: {}", path.display(), err))
+    };
+    let mut regions: Vec<Region> = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('\\') {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(3, '\t').collect();
+        if fields.len() < 2 {
+            return Err(format!("Error in label file {}, line {}: expected a start time, an end time and a label", path.display(), i + 1));
+        }
+        let (start, end) = match (fields[0].trim().parse::<f64>(), fields[1].trim().parse::<f64>()) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return Err(format!("Error in label file {}, line {}: invalid time", path.display(), i + 1))
+        };
+        let label = fields.get(2).map(|x| x.trim()).unwrap_or("");
+        if !label.is_empty() {
+            regions.push(Region{start_frame: seconds_to_frame(start, sample_rate), end_frame: seconds_to_frame(end, sample_rate), label: String::from(label)});
+        }
+    }
+    Ok(regions)
+}
+
+/// Parses a Reaper time. The time can be in seconds (`12.5`), or in minutes and seconds, optionally
+/// with hours (`0:12.500` or `1:02:12.500`). Measures and beats can't be converted without the project tempo.
+fn parse_reaper_time(time: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in time.trim().split(':') {
+        let value: f64 = part.parse().ok()?;
+        seconds = seconds * 60.0 + value;
+    }
+    Some(seconds)
+}
+
+/// Splits a CSV line into fields. Fields can be quoted, with doubled quotes inside them.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c)
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Reads a Reaper region and marker export. The file has a header row with at least the `Name`, `Start`
+/// and `End` columns. Markers have no end, and are read as regions at a single point. The project ruler
+/// must be set to a time format (seconds or minutes:seconds) when the file is exported.
+pub fn read_reaper_regions(path: &Path, sample_rate: u32) -> Result<Vec<Region>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("Error reading region file {}: {}", path.display(), err))
+    };
+    let mut lines = contents.lines();
+    let header = match lines.next() {
+        Some(x) => split_csv_line(x.trim_start_matches('\u{feff}')),
+        None => return Ok(Vec::new())
+    };
+    let column = |name: &str| header.iter().position(|x| x.trim().eq_ignore_ascii_case(name));
+    let (name_col, start_col, end_col) = match (column("Name"), column("Start"), column("End")) {
+        (Some(name), Some(start), Some(end)) => (name, start, end),
+        _ => return Err(format!("Error in region file {}: the header must have Name, Start and End columns", path.display()))
+    };
+
+    let mut regions: Vec<Region> = Vec::new();
+    for (i, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_csv_line(line);
+        let field = |idx: usize| fields.get(idx).map(|x| x.trim()).unwrap_or("");
+        let label = field(name_col);
+        if label.is_empty() {
+            continue;
+        }
+        let start = match parse_reaper_time(field(start_col)) {
+            Some(x) => x,
+            None => return Err(format!("Error in region file {}, line {}: invalid start time {}", path.display(), i + 2, field(start_col)))
+        };
+        let end = if field(end_col).is_empty() {
+            start
+        } else {
+            match parse_reaper_time(field(end_col)) {
+                Some(x) => x,
+                None => return Err(format!("Error in region file {}, line {}: invalid end time {}", path.display(), i + 2, field(end_col)))
+            }
+        };
+        regions.push(Region{start_frame: seconds_to_frame(start, sample_rate), end_frame: seconds_to_frame(end, sample_rate), label: String::from(label)});
+    }
+    Ok(regions)
+}
+
+/// Reads a little-endian u32 from a byte slice
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// Reads text from a RIFF chunk, which ends at the first null byte
+fn read_chunk_text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+/// Reads the cue points of a WAV file, with their labels and lengths from the associated data list.
+/// Cue points without a label are skipped, and cue points without a length are read as markers.
+/// Returns an empty list if the file is not a RIFF WAV file or has no cue chunk.
+pub fn read_wav_cues(path: &Path) -> Result<Vec<Region>, String> {
+    let error = |err: std::io::Error| format!("Error reading cue points in {}: {}", path.display(), err);
+    let file = File::open(path).map_err(error)?;
+    let file_len = file.metadata().map_err(error)?.len();
+    parse_wav_cues(&mut BufReader::new(file), file_len).map_err(error)
+}
+
+/// Reads the cue points of a WAV file from a reader, as in `read_wav_cues`. `file_len` is the length of the file,
+/// which limits how much of a chunk is read, so that a damaged chunk size can't cause a huge allocation.
+fn parse_wav_cues(file: &mut (impl Read + Seek), file_len: u64) -> std::io::Result<Vec<Region>> {
+    let mut header = [0u8; 12];
+    if file.read_exact(&mut header).is_err() || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Ok(Vec::new());
+    }
+
+    // Cue points are (id, frame), and labels and lengths are keyed by cue point id
+    let mut cues: Vec<(u32, usize)> = Vec::new();
+    let mut labels: Vec<(u32, String)> = Vec::new();
+    let mut lengths: Vec<(u32, usize)> = Vec::new();
+    let mut chunk_header = [0u8; 8];
+    while file.read_exact(&mut chunk_header).is_ok() {
+        let size = u32::from_le_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]) as usize;
+        // Chunks are padded to an even length
+        let padded_size = size + size % 2;
+        let id = &chunk_header[0..4];
+        if id != b"cue " && id != b"LIST" {
+            file.seek(SeekFrom::Current(padded_size as i64))?;
+            continue;
+        }
+        // A chunk can't be longer than the rest of the file. The last chunk may also be missing its pad byte.
+        let remaining = file_len.saturating_sub(file.stream_position()?) as usize;
+        let mut data = vec![0u8; usize::min(padded_size, remaining)];
+        if file.read_exact(&mut data).is_err() {
+            break;
+        }
+        let size = usize::min(size, data.len());
+        if id == b"cue " {
+            // Each cue point is 24 bytes, after the number of cue points
+            let num_cues = usize::min(read_u32(&data, 0).unwrap_or(0) as usize, size.saturating_sub(4) / 24);
+            for i in 0..num_cues {
+                let offset = 4 + i * 24;
+                if let (Some(cue_id), Some(sample_offset)) = (read_u32(&data, offset), read_u32(&data, offset + 20)) {
+                    cues.push((cue_id, sample_offset as usize));
+                }
+            }
+        } else if data.get(0..4) == Some(b"adtl") {
+            let mut pos = 4;
+            while pos + 8 <= size {
+                let sub_id = &data[pos..pos + 4];
+                let sub_size = read_u32(&data, pos + 4).unwrap_or(0) as usize;
+                let sub_data = &data[usize::min(pos + 8, size)..usize::min(pos + 8 + sub_size, size)];
+                if let Some(cue_id) = read_u32(sub_data, 0) {
+                    if sub_id == b"labl" {
+                        labels.push((cue_id, read_chunk_text(&sub_data[4..])));
+                    } else if sub_id == b"ltxt" {
+                        if let Some(length) = read_u32(sub_data, 4) {
+                            lengths.push((cue_id, length as usize));
+                        }
+                    }
+                }
+                pos += 8 + sub_size + sub_size % 2;
+            }
+        }
+    }
+
+    let mut regions: Vec<Region> = Vec::new();
+    for (cue_id, start_frame) in cues {
+        let label = match labels.iter().find(|(id, _)| *id == cue_id) {
+            Some((_, label)) if !label.is_empty() => label.clone(),
+            _ => continue
+        };
+        let length = lengths.iter().find(|(id, _)| *id == cue_id).map(|(_, length)| *length).unwrap_or(0);
+        regions.push(Region{start_frame, end_frame: start_frame + length, label});
+    }
+    Ok(regions)
+}
+
+/// Reads all labelled regions of an audio file: the regions in its label files, and the cue points
+/// in the file itself if it is a WAV file. Files that can't be read are reported and skipped.
+pub fn read_regions(audio_path: &str, sample_rate: u32) -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+    let mut results: Vec<Result<Vec<Region>, String>> = Vec::new();
+    for sidecar in sidecar_paths(audio_path) {
+        results.push(read_sidecar(&sidecar, sample_rate));
+    }
+    if audio_path.to_lowercase().ends_with(".wav") {
+        results.push(read_wav_cues(Path::new(audio_path)));
+    }
+    for result in results {
+        match result {
+            Ok(x) => regions.extend(x),
+            Err(err) => eprintln!("{}", err)
+        }
+    }
+    regions
+}
+
+/// Gets the labels of the regions that overlap the frames `start_frame..end_frame`
+pub fn overlapping_labels(regions: &[Region], start_frame: usize, end_frame: usize) -> Vec<String> {
+    let mut labels: Vec<String> = Vec::new();
+    for region in regions.iter() {
+        if region.overlaps(start_frame, end_frame) && !labels.contains(&region.label) {
+            labels.push(region.label.clone());
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+    use std::io::Cursor;
+
+    /// Writes a file in the temporary directory, which is deleted when it is dropped
+    fn temp_file(name: &str, contents: &str) -> TempFile {
+        TempFile::with_contents("labels", name, contents)
+    }
+
+    /// Builds a RIFF chunk with a declared size, padding odd-sized data unless `pad` is false
+    fn chunk(id: &[u8; 4], size: u32, data: &[u8], pad: bool) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend(size.to_le_bytes());
+        bytes.extend(data);
+        if pad && data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn cue_chunk(cues: &[(u32, u32)]) -> Vec<u8> {
+        let mut data = (cues.len() as u32).to_le_bytes().to_vec();
+        for (id, offset) in cues {
+            data.extend(id.to_le_bytes());
+            data.extend([0u8; 16]);
+            data.extend(offset.to_le_bytes());
+        }
+        chunk(b"cue ", data.len() as u32, &data, true)
+    }
+
+    fn adtl_chunk(subchunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"adtl".to_vec();
+        for subchunk in subchunks {
+            data.extend(subchunk);
+        }
+        chunk(b"LIST", data.len() as u32, &data, true)
+    }
+
+    fn labl(id: u32, text: &str) -> Vec<u8> {
+        let mut data = id.to_le_bytes().to_vec();
+        data.extend(text.as_bytes());
+        data.push(0);
+        chunk(b"labl", data.len() as u32, &data, true)
+    }
+
+    fn ltxt(id: u32, length: u32) -> Vec<u8> {
+        let mut data = id.to_le_bytes().to_vec();
+        data.extend(length.to_le_bytes());
+        data.extend(b"rgn \0\0\0\0\0\0\0\0");
+        chunk(b"ltxt", data.len() as u32, &data, true)
+    }
+
+    fn wav(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend((body.len() as u32 + 4).to_le_bytes());
+        bytes.extend(b"WAVE");
+        bytes.extend(body);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<Region> {
+        parse_wav_cues(&mut Cursor::new(bytes), bytes.len() as u64).unwrap()
+    }
+
+    fn region(start_frame: usize, end_frame: usize, label: &str) -> Region {
+        Region{start_frame, end_frame, label: String::from(label)}
+    }
+
+    #[test]
+    fn audacity_labels() {
+        let path = temp_file("audacity.txt", "0.5\t1.0\tkick\n\\\t200.0\t4000.0\n\n1.5\t1.5\tsnare hit \n2.0\t3.0\t\n");
+        let regions = read_audacity_labels(path.path(), 1000);
+        assert_eq!(regions.unwrap(), vec![region(500, 1000, "kick"), region(1500, 1500, "snare hit")]);
+    }
+
+    #[test]
+    fn malformed_audacity_lines_are_errors() {
+        for (i, contents) in ["0.5 1.0 kick\n", "0.5\tlater\tkick\n", "1.0\t2.0\tok\nx\t2.0\tbad\n"].iter().enumerate() {
+            let path = temp_file(&format!("malformed_{}.txt", i), contents);
+            let result = read_audacity_labels(path.path(), 1000);
+            assert!(result.is_err(), "{:?} should not parse", contents);
+        }
+    }
+
+    #[test]
+    fn only_parseable_sidecars_are_label_files() {
+        let notes = temp_file("sidecar_notes.txt", "Recorded in the garden, take 3\n");
+        let regions = temp_file("sidecar_notes.csv", "#,Name,Start,End,Length\nR1,bird,1.0,2.0,1.0\n");
+        let audio_path = notes.path().with_extension("wav").to_string_lossy().into_owned();
+        let sidecars = sidecar_paths(&audio_path);
+        let labelled = read_regions(&audio_path, 1000);
+        assert_eq!(sidecars, vec![regions.path().to_path_buf()]);
+        assert_eq!(labelled, vec![region(1000, 2000, "bird")]);
+    }
+
+    #[test]
+    fn reaper_regions_and_markers() {
+        let contents = "\u{feff}#,Name,Start,End,Length\nR1,\"Verse, \"\"A\"\"\",0:01.500,0:02.000,0:00.500\nM1,drop,3,,\nR2,,4,5,1\nR3,long,1:00:00.5,1:00:01,0.5\n";
+        let path = temp_file("reaper.csv", contents);
+        let regions = read_reaper_regions(path.path(), 1000);
+        assert_eq!(regions.unwrap(), vec![
+            region(1500, 2000, "Verse, \"A\""),
+            region(3000, 3000, "drop"),
+            region(3600500, 3601000, "long")
+        ]);
+    }
+
+    #[test]
+    fn malformed_reaper_files_are_errors() {
+        for (i, contents) in ["#,Name,Start\nR1,a,1\n", "#,Name,Start,End\nR1,a,1.1.1,2\n", "#,Name,Start,End\nR1,a,1,2:x\n"].iter().enumerate() {
+            let path = temp_file(&format!("malformed_{}.csv", i), contents);
+            let result = read_reaper_regions(path.path(), 1000);
+            assert!(result.is_err(), "{:?} should not parse", contents);
+        }
+        let path = temp_file("empty.csv", "");
+        let result = read_reaper_regions(path.path(), 1000);
+        assert_eq!(result.unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn wav_cues_with_labels_and_lengths() {
+        let bytes = wav(&[
+            chunk(b"fmt ", 16, &[0u8; 16], true),
+            cue_chunk(&[(1, 100), (2, 200), (3, 300)]),
+            adtl_chunk(&[labl(1, "one"), ltxt(1, 50), labl(3, "three")]),
+        ]);
+        assert_eq!(parse(&bytes), vec![region(100, 150, "one"), region(300, 300, "three")]);
+    }
+
+    #[test]
+    fn odd_sized_chunks_are_padded() {
+        // An odd-sized chunk before the cue chunk, and an odd-sized label (4 + 2 + 1 bytes)
+        let bytes = wav(&[
+            chunk(b"junk", 3, b"abc", true),
+            cue_chunk(&[(1, 10)]),
+            adtl_chunk(&[labl(1, "ab"), ltxt(1, 5)]),
+        ]);
+        assert_eq!(parse(&bytes), vec![region(10, 15, "ab")]);
+    }
+
+    #[test]
+    fn last_chunk_without_pad_byte() {
+        let mut list = b"adtl".to_vec();
+        list.extend(labl(1, "x"));
+        list.push(b'!');
+        let bytes = wav(&[cue_chunk(&[(1, 10)]), chunk(b"LIST", list.len() as u32, &list, false)]);
+        assert_eq!(parse(&bytes), vec![region(10, 10, "x")]);
+    }
+
+    #[test]
+    fn oversized_chunks_are_capped_at_the_file_length() {
+        // A LIST chunk and a cue chunk that claim to be almost 4 GB, and a cue count that doesn't fit in the chunk
+        let mut cues = cue_chunk(&[(1, 10)]);
+        cues[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut list = adtl_chunk(&[labl(1, "x")]);
+        list[4..8].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert_eq!(parse(&wav(&[cues.clone(), list])), vec![region(10, 10, "x")]);
+        let mut huge_cue = cues;
+        huge_cue[4..8].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert_eq!(parse(&wav(&[huge_cue])), Vec::new());
+    }
+
+    #[test]
+    fn truncated_and_non_wav_files() {
+        assert_eq!(parse(b"RIFF"), Vec::new());
+        assert_eq!(parse(b"RIFX\0\0\0\0WAVEcue "), Vec::new());
+        let bytes = wav(&[cue_chunk(&[(1, 10)]), adtl_chunk(&[labl(1, "x")])]);
+        assert_eq!(parse(&bytes[..bytes.len() - 3]), Vec::new());
+    }
+
+    #[test]
+    fn markers_overlap_grains_that_contain_them() {
+        let marker = region(100, 100, "m");
+        assert!(marker.overlaps(100, 200) && marker.overlaps(50, 101));
+        assert!(!marker.overlaps(101, 200) && !marker.overlaps(0, 100));
+        let span = region(100, 200, "r");
+        assert!(span.overlaps(150, 250) && span.overlaps(0, 101));
+        assert!(!span.overlaps(200, 300) && !span.overlaps(0, 100));
+    }
+}
@@ -1,50 +1,808 @@
+use clap::{Args, Parser, Subcommand};
+use std::collections::HashSet;
 use std::path::Path;
+use std::process::ExitCode;
+mod cluster;
+mod dedupe;
+mod embedding;
+mod envelope;
+mod export;
 mod grain_extractor;
+mod harmonics;
 mod io;
+mod knn;
+mod labels;
+mod mosaic;
+mod onset;
+mod render;
+mod similarity;
 mod sqlite;
+mod stft;
+mod tags;
+#[cfg(test)]
+mod test_util;
 
-// The maximum audio chunk length. Files that are longer will be split up into smaller
-// chunks for more efficient multithreaded processing.
-const MAX_AUDIO_SIZE: usize = 44100 * 120;
+// Exit codes. Invalid command line arguments exit with code 2 (this is handled by clap).
+const EXIT_FAILURE: u8 = 1;
+const EXIT_CONFIG_ERROR: u8 = 3;
+const EXIT_DATABASE_ERROR: u8 = 4;
+const EXIT_AUDIO_ERROR: u8 = 5;
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let mut valid_config = false;
-    if args.len() == 2 {
-        if Path::new(&args[1]).exists() {
-            valid_config = true;
+/// Extracts and analyzes grains from a corpus of audio files, and stores them in a SQLite database
+#[derive(Parser)]
+#[command(name = "grain_processor", version)]
+struct Cli {
+    /// The path to the configuration file
+    #[arg(short, long, global = true, default_value = "config.json")]
+    config: String,
+    /// Overrides the database path in the configuration file
+    #[arg(long, global = true)]
+    db: Option<String>,
+    /// Overrides the maximum number of threads in the configuration file (0 detects the number of CPU cores)
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+    /// Overrides the audio source directory in the configuration file
+    #[arg(long, global = true)]
+    source: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Extracts and analyzes grains from the audio source directory
+    Extract {
+        /// Lists the files that would be processed without processing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Prints the grains that match a query
+    Query {
+        #[command(flatten)]
+        query: QueryArgs,
+        /// The maximum number of grains to print. With --nearest, this is the number of neighbors (10 by default).
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Finds the grains nearest to a grain id, or to features such as spectral_centroid=3000,midi=60
+        #[arg(long, value_name = "GRAIN_ID|FEATURES")]
+        nearest: Option<knn::NearestTarget>,
+    },
+    /// Prints a summary of the database
+    Stats,
+    /// Exports the grains that match a query to a CSV or JSON file
+    Export {
+        /// The output file
+        output: String,
+        /// The file format. If omitted, it is chosen from the file extension.
+        #[arg(long, value_enum)]
+        format: Option<export::ExportFormat>,
+        #[command(flatten)]
+        query: QueryArgs,
+    },
+    /// Adds, removes or lists grain tags
+    Tag {
+        #[command(subcommand)]
+        action: TagAction,
+    },
+    /// Renders granular synthesis from a score file
+    Render {
+        score: String,
+    },
+    /// Resynthesizes a target audio file from the grains in the database
+    Mosaic {
+        spec: String,
+    },
+    /// Groups the grains into clusters of similar grains with k-means, and stores each grain's cluster.
+    /// Grains that are extracted later are assigned to the nearest cluster.
+    Cluster {
+        /// The number of clusters
+        #[arg(short = 'k', long = "clusters", default_value_t = 8)]
+        clusters: usize,
+        /// The features to cluster by, such as energy,spectral_centroid. If omitted, the weighted features in
+        /// the similarity settings of the configuration file are used.
+        #[arg(long, value_delimiter = ',', value_parser = parse_feature_column)]
+        features: Vec<String>,
+        /// Uses mini-batch k-means, which is much faster for large corpora
+        #[arg(long)]
+        mini_batch: bool,
+        /// The number of grains in each mini-batch
+        #[arg(long, default_value_t = 1024)]
+        batch_size: usize,
+        /// The maximum number of iterations
+        #[arg(long, default_value_t = 100)]
+        max_iterations: usize,
+        /// The random seed, for repeatable clusters
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Places the grains in a 2-D or 3-D space with principal component analysis, for browsing the corpus.
+    /// Grains that are extracted later are placed in the same space.
+    Embed {
+        /// The number of dimensions, 2 or 3
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(2..=3))]
+        dimensions: u8,
+        /// The features to embed, such as energy,spectral_centroid. If omitted, the weighted features in
+        /// the similarity settings of the configuration file are used.
+        #[arg(long, value_delimiter = ',', value_parser = parse_feature_column)]
+        features: Vec<String>,
+    },
+    /// Finds near-duplicate grains that match a query, keeps the first grain of each group, and tags the others
+    /// or deletes them
+    Dedupe {
+        /// The largest feature distance between duplicates, with the similarity settings of the configuration file
+        #[arg(long, default_value_t = 0.25)]
+        threshold: f64,
+        /// Finds duplicates across source files, instead of only within each file
+        #[arg(long)]
+        across_files: bool,
+        /// Confirms duplicates by their waveforms, which must have at least this normalized cross-correlation (0 to 1)
+        #[arg(long, value_name = "MIN")]
+        correlation: Option<f64>,
+        /// Deletes the duplicates instead of tagging them
+        #[arg(long)]
+        delete: bool,
+        /// The tag for duplicates, if they are not deleted
+        #[arg(long, default_value = "duplicate")]
+        tag: String,
+        /// Counts the duplicates without tagging or deleting them
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        query: QueryArgs,
+    },
+    /// Deletes grains whose source files no longer exist, and feature and tag rows whose grains no longer exist
+    Prune {
+        /// Lists what would be deleted without deleting it
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum TagAction {
+    /// Adds a tag to the grains that match a query
+    Add {
+        tag: String,
+        /// Counts the grains that match the query without tagging them
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        query: QueryArgs,
+    },
+    /// Removes a tag from the grains that match a query
+    Remove {
+        tag: String,
+        /// Counts the grains that match the query without untagging them
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        query: QueryArgs,
+    },
+    /// Lists the tags of the grains that match a query
+    List {
+        #[command(flatten)]
+        query: QueryArgs,
+    },
+}
+
+/// Grain query options shared by several subcommands
+#[derive(Args)]
+struct QueryArgs {
+    /// A glob pattern that the grain's source file path must match
+    #[arg(long)]
+    file: Option<String>,
+    /// A range condition on a grains column, written as column=min:max. Either bound may be omitted. Can be repeated.
+    #[arg(long = "range", value_name = "COLUMN=MIN:MAX")]
+    ranges: Vec<sqlite::ColumnRange>,
+    /// A range of estimated pitches as MIDI note numbers. Either bound may be omitted.
+    #[arg(long, value_name = "LOW:HIGH", value_parser = parse_pitch_range)]
+    pitch: Option<sqlite::ColumnRange>,
+    /// A combination of tags that the grain must have, such as "drums AND (bright OR NOT noisy)"
+    #[arg(long, value_name = "EXPRESSION")]
+    tags: Option<tags::TagExpr>,
+    /// The cluster that the grain must be in, from the cluster command
+    #[arg(long, value_name = "CLUSTER_ID")]
+    cluster: Option<i64>,
+}
+
+impl QueryArgs {
+    fn to_query(&self) -> sqlite::GrainQuery {
+        let mut ranges = self.ranges.clone();
+        if let Some(pitch) = &self.pitch {
+            ranges.push(pitch.clone());
+        }
+        sqlite::GrainQuery{file: self.file.clone(), ranges, tags: self.tags.clone(), cluster: self.cluster}
+    }
+}
+
+fn parse_pitch_range(s: &str) -> Result<sqlite::ColumnRange, String> {
+    format!("midi={}", s).parse()
+}
+
+fn parse_feature_column(s: &str) -> Result<String, String> {
+    let column = s.trim();
+    if sqlite::feature_columns().contains(&column) {
+        Ok(String::from(column))
+    } else {
+        Err(format!("Unknown feature {}", column))
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut config = match io::read_config(&cli.config) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
+        }
+    };
+    if let Some(db) = &cli.db {
+        config.database_path = db.clone();
+    }
+    if let Some(threads) = cli.threads {
+        config.max_num_threads = threads;
+    }
+    if let Some(source) = &cli.source {
+        config.audio_source_directory = source.clone();
+    }
+
+    // The configuration is validated after the overrides are applied, since they can fix problems in the file
+    let creates_database = matches!(cli.command, Command::Extract{..});
+    let problems = config.validate(creates_database);
+    if !problems.is_empty() {
+        eprintln!("{}", io::ConfigError::Invalid(problems));
+        return ExitCode::from(EXIT_CONFIG_ERROR);
+    }
+    eprintln!("Grain Processor\n--------------------------------------------------------\nDatabase path: {}", config.database_path);
+
+    // Every subcommand other than extract needs an existing database
+    let database_exists = Path::new(&config.database_path).exists();
+    if !creates_database && !database_exists {
+        eprintln!("The database {} does not exist.", config.database_path);
+        return ExitCode::from(EXIT_DATABASE_ERROR);
+    }
+
+    // Create the database or upgrade its schema. Dry runs don't change the database, so they only check
+    // that its schema is supported.
+    let dry_run = matches!(cli.command,
+        Command::Extract{dry_run: true} |
+        Command::Tag{action: TagAction::Add{dry_run: true, ..} | TagAction::Remove{dry_run: true, ..}} |
+        Command::Dedupe{dry_run: true, ..} |
+        Command::Prune{dry_run: true});
+    let schema_result = if dry_run && database_exists {
+        sqlite::check_schema_version(&config.database_path)
+    } else if dry_run {
+        Ok(sqlite::SCHEMA_VERSION)
+    } else {
+        sqlite::migrate(&config.database_path)
+    };
+    match schema_result {
+        Ok(version) if database_exists && version < sqlite::SCHEMA_VERSION => {
+            if dry_run {
+                eprintln!("The database has schema version {}, and will be upgraded to version {} when it is changed.", version, sqlite::SCHEMA_VERSION);
+            } else {
+                eprintln!("Upgraded the database schema from version {} to {}.", version, sqlite::SCHEMA_VERSION);
+            }
+        },
+        Ok(_) => (),
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    }
+
+    match cli.command {
+        Command::Extract{dry_run} => extract(config, dry_run),
+        Command::Query{query, limit, nearest: None} => query_grains(&config, &query.to_query(), limit),
+        Command::Query{query, limit, nearest: Some(target)} => query_nearest(&config, &query.to_query(), &target, limit.unwrap_or(10)),
+        Command::Stats => stats(&config),
+        Command::Export{output, format, query} => export_grains(&config, &query.to_query(), &output, format),
+        Command::Tag{action: TagAction::Add{tag, dry_run, query}} => tag_grains(&config, &query.to_query(), &tag, false, dry_run),
+        Command::Tag{action: TagAction::Remove{tag, dry_run, query}} => tag_grains(&config, &query.to_query(), &tag, true, dry_run),
+        Command::Tag{action: TagAction::List{query}} => list_tags(&config, &query.to_query()),
+        Command::Render{score} => render_score(&config, &score),
+        Command::Mosaic{spec} => create_mosaic(&config, &spec),
+        Command::Cluster{clusters, features, mini_batch, batch_size, max_iterations, seed} => {
+            let settings = cluster::KMeansSettings{k: clusters, mini_batch, batch_size, max_iterations, seed};
+            cluster_grains(&config, &features, &settings)
+        },
+        Command::Embed{dimensions, features} => embed_grains(&config, &features, dimensions as usize),
+        Command::Dedupe{threshold, across_files, correlation, delete, tag, dry_run, query} => {
+            let settings = dedupe::DedupeSettings{threshold, across_files, min_correlation: correlation};
+            dedupe_grains(&config, &query.to_query(), &settings, if delete { None } else { Some(tag.as_str()) }, dry_run)
+        },
+        Command::Prune{dry_run} => prune(&config, dry_run)
+    }
+}
+
+/// Runs grain extraction
+fn extract(mut config: io::GranulatorConfig, dry_run: bool) -> ExitCode {
+    // the number of cpu cores available for the thread pool
+    if config.max_num_threads < 1 {
+        config.max_num_threads = match std::thread::available_parallelism() {
+            Ok(x) => x.get(),
+            Err(_) => 1
+        };
+    }
+
+    eprintln!("Audio path: {}\nMax audio chunk size: {}\nMax threads: {}",
+        config.audio_source_directory, config.chunk_size(), config.max_num_threads);
+    if let Some(warning) = config.memory_budget_warning() {
+        eprintln!("{}", warning);
+    }
+
+    let num_errors = match grain_extractor::process_grains(&config, dry_run) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    if num_errors > 0 {
+        eprintln!("Done with {} errors", num_errors);
+        return ExitCode::from(EXIT_FAILURE);
+    }
+    eprintln!("Done");
+    ExitCode::SUCCESS
+}
+
+/// Formats a database value for printing
+fn format_value(value: &rusqlite::types::Value) -> String {
+    match value {
+        rusqlite::types::Value::Null => String::from("NULL"),
+        rusqlite::types::Value::Integer(x) => x.to_string(),
+        rusqlite::types::Value::Real(x) => x.to_string(),
+        rusqlite::types::Value::Text(x) => x.clone(),
+        rusqlite::types::Value::Blob(_) => String::from("<blob>")
+    }
+}
+
+/// Prints the grains that match a query, one per line with tab-separated columns
+fn query_grains(config: &io::GranulatorConfig, query: &sqlite::GrainQuery, limit: Option<usize>) -> ExitCode {
+    let (columns, rows) = match sqlite::select_grain_rows(&config.database_path, query, limit) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error querying grains: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    println!("{}", columns.join("\t"));
+    for row in rows.iter() {
+        let fields: Vec<String> = row.iter().map(format_value).collect();
+        println!("{}", fields.join("\t"));
+    }
+    eprintln!("{} grains", rows.len());
+    ExitCode::SUCCESS
+}
+
+/// Prints the grains nearest to a target that match a query, nearest first, with their distances.
+/// The nearest neighbor index is built or updated first.
+fn query_nearest(config: &io::GranulatorConfig, query: &sqlite::GrainQuery, target: &knn::NearestTarget, k: usize) -> ExitCode {
+    let index = match knn::update_index(&config.database_path, &config.similarity) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error updating the nearest neighbor index: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    let (target_vector, target_id) = match target {
+        knn::NearestTarget::Features(features) => {
+            let vector = index.similarity().normalize(|column| features.iter().find(|(c, _)| c == column).map(|(_, x)| *x));
+            (vector, None)
+        },
+        knn::NearestTarget::Grain(id) => match sqlite::select_grain_entries_by_id(&config.database_path, &[*id]) {
+            Ok(grains) if !grains.is_empty() => (index.similarity().features(&grains[0].1), Some(*id)),
+            Ok(_) => {
+                eprintln!("There is no grain with id {}.", id);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            },
+            Err(err) => {
+                eprintln!("Error querying grains: {}", err);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            }
+        }
+    };
+
+    // Only the grains that match the query are candidates, if the query has any conditions
+    let candidates: Option<HashSet<i64>> = if query.file.is_none() && query.ranges.is_empty() && query.tags.is_none() && query.cluster.is_none() {
+        None
+    } else {
+        match sqlite::select_grain_ids(&config.database_path, query) {
+            Ok(x) => Some(x.into_iter().collect()),
+            Err(err) => {
+                eprintln!("Error querying grains: {}", err);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            }
+        }
+    };
+    let neighbors = index.nearest(&target_vector, k, |id| {
+        Some(id) != target_id && candidates.as_ref().is_none_or(|x| x.contains(&id))
+    });
+
+    let ids: Vec<i64> = neighbors.iter().map(|(id, _)| *id).collect();
+    let (columns, rows) = match sqlite::select_grain_rows_by_id(&config.database_path, &ids) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error querying grains: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    println!("{}\tdistance", columns.join("\t"));
+    for (row, (_, distance)) in rows.iter().zip(neighbors.iter()) {
+        let fields: Vec<String> = row.iter().map(format_value).collect();
+        println!("{}\t{}", fields.join("\t"), distance);
+    }
+    eprintln!("{} grains", rows.len());
+    ExitCode::SUCCESS
+}
+
+/// Gets the similarity settings for clustering and embedding: the settings in the configuration file with
+/// euclidean distance, or, if `features` lists features, equal weights for those features only
+fn euclidean_settings(config: &io::GranulatorConfig, features: &[String]) -> similarity::SimilaritySettings {
+    let mut settings = config.similarity.clone();
+    settings.metric = similarity::DistanceMetric::Euclidean;
+    if !features.is_empty() {
+        settings.weights = sqlite::feature_columns().iter()
+            .map(|column| (column.to_string(), if features.iter().any(|x| x == column) { 1.0 } else { 0.0 }))
+            .collect();
+    }
+    settings
+}
+
+/// Clusters the grains with k-means over their normalized features, and stores the clusters in the database.
+/// The features are weighted as in the similarity settings, unless `features` lists them, and compared with
+/// euclidean distance, which is what k-means minimizes.
+fn cluster_grains(config: &io::GranulatorConfig, features: &[String], settings: &cluster::KMeansSettings) -> ExitCode {
+    let similarity_settings = euclidean_settings(config, features);
+    if settings.k < 1 || (settings.mini_batch && settings.batch_size < 1) {
+        eprintln!("The number of clusters and the batch size must be at least 1.");
+        return ExitCode::from(EXIT_FAILURE);
+    }
+
+    let stats = match sqlite::load_feature_stats(&config.database_path, &similarity_settings) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error loading feature statistics: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    let grains = match sqlite::select_grain_entries_with_ids(&config.database_path, &sqlite::GrainQuery::default()) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error loading grains: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    if grains.len() < settings.k {
+        eprintln!("There are {} grains, which is fewer than the {} clusters.", grains.len(), settings.k);
+        return ExitCode::from(EXIT_FAILURE);
+    }
+
+    eprintln!("Clustering {} grains into {} clusters...", grains.len(), settings.k);
+    let similarity = similarity::Similarity::new(&similarity_settings, &stats);
+    let vectors: Vec<Vec<f64>> = grains.iter().map(|(_, grain)| similarity.features(grain)).collect();
+    let model = cluster::ClusterModel{centroids: cluster::kmeans(&vectors, settings, &similarity), similarity};
+    let mut sizes: Vec<usize> = vec![0; settings.k];
+    let mut assignments: Vec<(i64, usize, f64)> = Vec::with_capacity(grains.len());
+    for ((id, _), vector) in grains.iter().zip(vectors.iter()) {
+        if let Some((cluster, distance)) = model.assign_vector(vector) {
+            sizes[cluster] += 1;
+            assignments.push((*id, cluster, distance));
+        }
+    }
+    if let Err(err) = sqlite::save_clusters(&config.database_path, &model, &assignments) {
+        eprintln!("Error saving clusters: {}", err);
+        return ExitCode::from(EXIT_DATABASE_ERROR);
+    }
+    for (cluster, size) in sizes.iter().enumerate() {
+        println!("    Cluster {}: {} grains", cluster, size);
+    }
+    eprintln!("Done");
+    ExitCode::SUCCESS
+}
+
+/// Computes the corpus embedding with principal component analysis over the weighted, normalized features,
+/// and stores each grain's coordinates and the projection in the database
+fn embed_grains(config: &io::GranulatorConfig, features: &[String], dimensions: usize) -> ExitCode {
+    let similarity_settings = euclidean_settings(config, features);
+    let stats = match sqlite::load_feature_stats(&config.database_path, &similarity_settings) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error loading feature statistics: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    let grains = match sqlite::select_grain_entries_with_ids(&config.database_path, &sqlite::GrainQuery::default()) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error loading grains: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+
+    eprintln!("Embedding {} grains in {} dimensions...", grains.len(), dimensions);
+    let similarity = similarity::Similarity::new(&similarity_settings, &stats);
+    let vectors: Vec<Vec<f64>> = grains.iter().map(|(_, grain)| similarity.weighted(&similarity.features(grain))).collect();
+    let projection = match embedding::Projection::fit(&vectors, dimensions, &similarity) {
+        Some(x) => x,
+        None => {
+            eprintln!("There are no grains to embed.");
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    let coordinates: Vec<(i64, Vec<f64>)> = grains.iter().zip(vectors.iter())
+        .map(|((id, _), vector)| (*id, projection.project_vector(vector)))
+        .collect();
+    if let Err(err) = sqlite::save_embedding(&config.database_path, &projection, &coordinates) {
+        eprintln!("Error saving the embedding: {}", err);
+        return ExitCode::from(EXIT_DATABASE_ERROR);
+    }
+    for (i, variance) in projection.explained_variance.iter().enumerate() {
+        println!("    Component {}: {:.1}% of the variance", i + 1, variance * 100.0);
+    }
+    eprintln!("Done");
+    ExitCode::SUCCESS
+}
+
+/// Prints a summary of the database
+fn stats(config: &io::GranulatorConfig) -> ExitCode {
+    let stats = match sqlite::get_stats(&config.database_path) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error reading database: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    println!("Grains: {}\nSource files: {}\nIncompletely processed file records: {}", stats.num_grains, stats.num_source_files, stats.num_incomplete_files);
+    println!("Grains per profile:");
+    for (profile, count) in stats.grains_per_profile.iter() {
+        println!("    {}: {}", profile.as_deref().unwrap_or("(legacy)"), count);
+    }
+    println!("Rejected grains:");
+    for (reason, count) in stats.rejected_per_reason.iter() {
+        println!("    {}: {}", reason, count);
+    }
+    println!("Tags:");
+    for (tag, count) in stats.grains_per_tag.iter() {
+        println!("    {}: {}", tag, count);
+    }
+    ExitCode::SUCCESS
+}
+
+/// Exports the grains that match a query
+fn export_grains(config: &io::GranulatorConfig, query: &sqlite::GrainQuery, output: &str, format: Option<export::ExportFormat>) -> ExitCode {
+    let (columns, rows) = match sqlite::select_grain_rows(&config.database_path, query, None) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error querying grains: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    let format = format.unwrap_or(export::ExportFormat::from_path(output));
+    match export::export_rows(output, format, &columns, &rows) {
+        Ok(_) => {
+            eprintln!("Exported {} grains to {}", rows.len(), output);
+            ExitCode::SUCCESS
+        },
+        Err(err) => {
+            eprintln!("Error writing {}: {}", output, err);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+/// Adds a tag to the grains that match a query, or removes it
+fn tag_grains(config: &io::GranulatorConfig, query: &sqlite::GrainQuery, tag: &str, remove: bool, dry_run: bool) -> ExitCode {
+    let result = if dry_run {
+        sqlite::count_grains(&config.database_path, query)
+    } else if remove {
+        sqlite::remove_tag(&config.database_path, query, tag)
+    } else {
+        sqlite::add_tag(&config.database_path, query, tag)
+    };
+    match result {
+        Ok(count) if dry_run => println!("{} grains match the query", count),
+        Ok(count) if remove => println!("Removed tag {} from {} grains", tag, count),
+        Ok(count) => println!("Added tag {} to {} grains", tag, count),
+        Err(err) => {
+            eprintln!("Error tagging grains: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Prints the tags of the grains that match a query
+fn list_tags(config: &io::GranulatorConfig, query: &sqlite::GrainQuery) -> ExitCode {
+    match sqlite::list_tags(&config.database_path, query) {
+        Ok(tags) => {
+            for (tag, count) in tags.iter() {
+                println!("{}\t{}", tag, count);
+            }
+            eprintln!("{} tags", tags.len());
+            ExitCode::SUCCESS
+        },
+        Err(err) => {
+            eprintln!("Error listing tags: {}", err);
+            ExitCode::from(EXIT_DATABASE_ERROR)
+        }
+    }
+}
+
+/// Renders a score
+fn render_score(config: &io::GranulatorConfig, score_path: &str) -> ExitCode {
+    let score = match render::read_score(score_path) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
+        }
+    };
+    eprintln!("Rendering to {}", score.output_path);
+    match render::render(&config.database_path, &score) {
+        Ok(_) => {
+            eprintln!("Done");
+            ExitCode::SUCCESS
+        },
+        Err(err) => {
+            eprintln!("Error rendering score: {}", err);
+            match err {
+                render::RenderError::Score(_) => ExitCode::from(EXIT_CONFIG_ERROR),
+                render::RenderError::Database(_) => ExitCode::from(EXIT_DATABASE_ERROR),
+                render::RenderError::Audio(_) => ExitCode::from(EXIT_AUDIO_ERROR)
+            }
+        }
+    }
+}
+
+/// Creates a mosaic
+fn create_mosaic(config: &io::GranulatorConfig, spec_path: &str) -> ExitCode {
+    let spec = match mosaic::read_mosaic_spec(spec_path) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
+        }
+    };
+    eprintln!("Mosaicing {} to {}", spec.target_path, spec.output_path);
+    match mosaic::mosaic(&config.database_path, &spec, config.num_mel_bands, config.num_mfccs) {
+        Ok(_) => {
+            eprintln!("Done");
+            ExitCode::SUCCESS
+        },
+        Err(err) => {
+            eprintln!("Error creating mosaic: {}", err);
+            match err {
+                mosaic::MosaicError::Spec(_) => ExitCode::from(EXIT_CONFIG_ERROR),
+                mosaic::MosaicError::Database(_) => ExitCode::from(EXIT_DATABASE_ERROR),
+                mosaic::MosaicError::Audio(_) => ExitCode::from(EXIT_AUDIO_ERROR)
+            }
         }
     }
-    
-    if !valid_config {
-        println!("Grain Processor\n--------------------------------------------------------\nUsage: grain_processor path_to_config.json");
+}
+
+/// Finds near-duplicate grains among the grains that match a query, and tags the duplicates with `tag`,
+/// or deletes them if `tag` is `None`. The first grain of each group is kept.
+fn dedupe_grains(config: &io::GranulatorConfig, query: &sqlite::GrainQuery, settings: &dedupe::DedupeSettings, tag: Option<&str>, dry_run: bool) -> ExitCode {
+    if settings.min_correlation.is_some_and(|x| !(0.0..=1.0).contains(&x)) {
+        eprintln!("The minimum correlation must be between 0 and 1.");
+        return ExitCode::from(EXIT_FAILURE);
+    }
+    // A dry run uses an up-to-date index without saving it
+    let index_result = if dry_run {
+        knn::current_index(&config.database_path, &config.similarity)
     } else {
-        let mut config = io::read_config(&args[1]);
-        
-        // the number of cpu cores available for the thread pool
-        if config.max_num_threads < 1 {
-            config.max_num_threads = match std::thread::available_parallelism() {
-                Ok(x) => x.get(),
-                Err(_) => 1
-            };
-        }
-
-        println!("Grain Processor\n--------------------------------------------------------\nDatabase path: {}\nAudio path: {}\nMax audio chunk size: {}\nMax threads: {}", 
-            config.database_path, config.audio_source_directory, config.max_audio_chunk_size, config.max_num_threads);
-
-        // Create the database if it doesn't exist
-        if !Path::new(&config.database_path).exists() {
-            match sqlite::create_schema(&config.database_path) {
-                Ok(_) => (),
+        knn::update_index(&config.database_path, &config.similarity)
+    };
+    let index = match index_result {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error updating the nearest neighbor index: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    let grains = match sqlite::select_grain_entries_with_ids(&config.database_path, query) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error loading grains: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+
+    eprintln!("Looking for near-duplicates among {} grains...", grains.len());
+    let groups = dedupe::find_duplicates(&grains, &index, settings);
+    let duplicates: Vec<i64> = groups.iter().flat_map(|group| group.duplicates.iter().copied()).collect();
+    if dry_run {
+        for group in groups.iter() {
+            let ids: Vec<String> = group.duplicates.iter().map(|id| id.to_string()).collect();
+            println!("Grain {} has near-duplicates {}", group.representative, ids.join(", "));
+        }
+    }
+    println!("Found {} near-duplicates of {} grains", duplicates.len(), groups.len());
+    if dry_run || duplicates.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+
+    match tag {
+        Some(tag) => match sqlite::add_tag_by_id(&config.database_path, &duplicates, tag) {
+            Ok(count) => println!("Tagged {} grains as {}", count, tag),
+            Err(err) => {
+                eprintln!("Error tagging grains: {}", err);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            }
+        },
+        None => {
+            match sqlite::delete_grains(&config.database_path, &duplicates) {
+                Ok(count) => println!("Deleted {} grains", count),
                 Err(err) => {
-                    println!("Error creating database schema: {}", err.to_string());
-                    return;
+                    eprintln!("Error deleting grains: {}", err);
+                    return ExitCode::from(EXIT_DATABASE_ERROR);
                 }
             }
+            if let Err(err) = sqlite::clear_feature_stats(&config.database_path) {
+                eprintln!("Error clearing feature statistics: {}", err);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            }
+            if let Err(err) = knn::update_index(&config.database_path, &config.similarity) {
+                eprintln!("Error updating the nearest neighbor index: {}", err);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            }
         }
+    }
+    ExitCode::SUCCESS
+}
 
-        grain_extractor::process_grains(&config, MAX_AUDIO_SIZE);
-
-        println!("Done");
+/// Deletes grains whose source files no longer exist, and rows whose grains no longer exist
+fn prune(config: &io::GranulatorConfig, dry_run: bool) -> ExitCode {
+    let source_files = match sqlite::list_source_files(&config.database_path) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error reading database: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    let mut num_grains = 0;
+    for (path, count) in source_files.iter().filter(|(path, _)| !Path::new(path).exists()) {
+        if dry_run {
+            println!("Would delete {} grains from missing file {}", count, path);
+            num_grains += count;
+            continue;
+        }
+        match sqlite::delete_source_file(&config.database_path, path) {
+            Ok(x) => {
+                println!("Deleted {} grains from missing file {}", x, path);
+                num_grains += x;
+            },
+            Err(err) => {
+                eprintln!("Error deleting grains from {}: {}", path, err);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            }
+        }
+    }
+    let num_orphans = match sqlite::delete_orphans(&config.database_path, dry_run) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error deleting orphaned rows: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+    };
+    if dry_run {
+        println!("Would delete {} grains and {} orphaned feature and tag rows", num_grains, num_orphans);
+        return ExitCode::SUCCESS;
+    }
+    println!("Deleted {} grains and {} orphaned feature and tag rows", num_grains, num_orphans);
+    if num_grains > 0 {
+        if let Err(err) = sqlite::clear_feature_stats(&config.database_path) {
+            eprintln!("Error clearing feature statistics: {}", err);
+            return ExitCode::from(EXIT_DATABASE_ERROR);
+        }
+        if Path::new(&knn::index_path(&config.database_path)).exists() {
+            if let Err(err) = knn::update_index(&config.database_path, &config.similarity) {
+                eprintln!("Error updating the nearest neighbor index: {}", err);
+                return ExitCode::from(EXIT_DATABASE_ERROR);
+            }
+        }
     }
+    ExitCode::SUCCESS
 }
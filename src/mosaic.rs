@@ -0,0 +1,146 @@
+// File: mosaic.rs
+// This file has concatenative target-matching synthesis (audio mosaicing). A target file is segmented
+// and analyzed like a corpus file, and each target grain is replaced with the most similar grain
+// in the database.
+
+use crate::grain_extractor::GrainEntry;
+use crate::{grain_extractor, io, knn, render, similarity, sqlite};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+#[derive(Debug, Clone)]
+pub enum MosaicError {
+    Spec(String),
+    Database(String),
+    Audio(String)
+}
+
+impl fmt::Display for MosaicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MosaicError::Spec(msg) => write!(f, "{}", msg),
+            MosaicError::Database(msg) => write!(f, "{}", msg),
+            MosaicError::Audio(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+/// The settings for a mosaic
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MosaicSpec {
+    pub target_path: String,
+    pub output_path: String,
+    /// How the target is segmented. This works the same way as a grain profile in the configuration file.
+    pub profile: io::GrainProfile,
+    /// Restricts the corpus grains that can be used
+    #[serde(default)]
+    pub corpus: sqlite::GrainQuery,
+    /// Whether to scale each corpus grain to the RMS level of the target grain it replaces
+    #[serde(default = "default_match_loudness")]
+    pub match_loudness: bool,
+    #[serde(default)]
+    pub window: io::WindowShape,
+    /// How target grains are compared with corpus grains
+    #[serde(default)]
+    pub similarity: similarity::SimilaritySettings,
+}
+
+fn default_match_loudness() -> bool {
+    true
+}
+
+/// Reads a mosaic specification file
+pub fn read_mosaic_spec(spec_file_path: &str) -> Result<MosaicSpec, MosaicError> {
+    let spec_contents = match fs::read_to_string(spec_file_path) {
+        Ok(x) => x,
+        Err(err) => return Err(MosaicError::Spec(format!("Error reading mosaic file: {}", err)))
+    };
+    let spec: MosaicSpec = match serde_json::from_str(&spec_contents) {
+        Ok(x) => x,
+        Err(err) => return Err(MosaicError::Spec(format!("Error parsing mosaic file: {}", err)))
+    };
+    let mut problems = spec.profile.validate();
+    problems.extend(spec.similarity.validate());
+    if !problems.is_empty() {
+        return Err(MosaicError::Spec(format!("Invalid mosaic file: {}", problems.join(" "))));
+    }
+    Ok(spec)
+}
+
+/// Resynthesizes a target audio file from corpus grains in the database
+pub fn mosaic(db: &str, spec: &MosaicSpec, num_mel_bands: usize, num_mfccs: usize) -> Result<(), MosaicError> {
+    let corpus: HashMap<i64, GrainEntry> = match sqlite::select_grain_entries_with_ids(db, &spec.corpus) {
+        Ok(x) => x.into_iter().collect(),
+        Err(err) => return Err(MosaicError::Database(format!("Error loading corpus grains: {}", err)))
+    };
+    if corpus.is_empty() {
+        return Err(MosaicError::Database(String::from("No corpus grains match the query.")));
+    }
+    eprintln!("Loaded {} corpus grains.", corpus.len());
+    // The index file is used if it has the similarity settings of the mosaic, and otherwise an index is built
+    // for the mosaic. Either way, the index file is left alone.
+    let index = match knn::current_index(db, &spec.similarity) {
+        Ok(x) => x,
+        Err(err) => return Err(MosaicError::Database(format!("Error loading the nearest neighbor index: {}", err)))
+    };
+
+    // Segment and analyze the target the same way corpus files are processed
+    let (target_sample_rate, target_audio) = match io::read_mono(&spec.target_path) {
+        Ok(x) => x,
+        Err(err) => return Err(MosaicError::Audio(err))
+    };
+    let frames = grain_extractor::segment_grain_frames(&target_audio, &spec.profile, spec.profile.initial_offset.unwrap_or(0));
+    let fft_size = spec.profile.analysis_fft_size();
+    let target_grains = match grain_extractor::analyze_grains(&spec.target_path, &target_audio, frames, spec.profile.window_type(), 5000, target_sample_rate, fft_size, num_mel_bands, num_mfccs, 0, &spec.profile.pitch_settings(), spec.profile.stft_settings().as_ref()) {
+        Ok((grains, _)) => grains,
+        Err(err) => return Err(MosaicError::Audio(format!("Error analyzing {}: {}", spec.target_path, err)))
+    };
+    eprintln!("Matching {} target grains.", target_grains.len());
+
+    let mut output = vec![0.0; target_audio.len()];
+    let mut cache = render::SourceCache::new();
+    for target_grain in target_grains.iter() {
+        let target = index.similarity().features(target_grain);
+        let best = match index.nearest(&target, 1, |id| corpus.contains_key(&id)).first() {
+            Some((id, _)) => &corpus[id],
+            None => continue
+        };
+        let location = sqlite::GrainLocation{file: best.file.clone(), start_frame: best.start_frame, end_frame: best.end_frame};
+        let mut grain_audio = match render::load_grain(&location, &mut cache) {
+            Some((sample_rate, audio)) => render::resample(&audio, sample_rate, target_sample_rate),
+            None => continue
+        };
+        if grain_audio.is_empty() {
+            continue;
+        }
+
+        let window = aus::generate_window(spec.window.window_type(), grain_audio.len());
+        grain_extractor::apply_window(&mut grain_audio, &window);
+        if spec.match_loudness {
+            let target_rms = aus::operations::rms(&target_audio[target_grain.start_frame..target_grain.end_frame]);
+            let grain_rms = aus::operations::rms(&grain_audio);
+            if grain_rms > 0.0 {
+                grain_audio.iter_mut().for_each(|x| *x *= target_rms / grain_rms);
+            }
+        }
+        for (out, sample) in output[target_grain.start_frame..].iter_mut().zip(grain_audio.iter()) {
+            *out += sample;
+        }
+    }
+
+    // Prevent clipping
+    let peak = output.iter().fold(0.0, |a: f64, &b| a.max(b.abs()));
+    if peak > 1.0 {
+        eprintln!("The mosaic peaked at {:.2}, so it was scaled down to avoid clipping.", peak);
+        output.iter_mut().for_each(|x| *x /= peak);
+    }
+
+    let output_file = aus::AudioFile::new_mono(aus::AudioFormat::S24, target_sample_rate, output);
+    match aus::write(&spec.output_path, &output_file) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(MosaicError::Audio(format!("Error writing {}: {:?}", spec.output_path, err)))
+    }
+}
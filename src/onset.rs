@@ -0,0 +1,205 @@
+// File: onset.rs
+// This file contains onset detection for onset-driven grain segmentation.
+
+use aus::spectrum::{rstft, complex_to_polar_rstft};
+
+// The STFT parameters for the novelty functions. A 1024-sample hop at 44.1 kHz
+// would smear transients too much, so we use a short frame with 50% overlap.
+const ONSET_FFT_SIZE: usize = 1024;
+const ONSET_HOP_SIZE: usize = 512;
+
+// The number of novelty frames on either side of a candidate onset that are used
+// for the local maximum check and the adaptive threshold.
+const PEAK_PICK_RADIUS: usize = 3;
+const ADAPTIVE_THRESHOLD_RADIUS: usize = 10;
+
+// The block size used to locate an onset more precisely inside its novelty frame
+const REFINEMENT_BLOCK_SIZE: usize = 64;
+
+/// The novelty function used to detect onsets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoveltyFunction {
+    SpectralFlux,
+    Energy
+}
+
+/// Computes a half-wave rectified spectral flux novelty curve.
+/// The magnitude spectrum is log-compressed first so that quiet transients are not swamped by loud ones.
+/// Returns one novelty value per STFT frame.
+pub fn spectral_flux_novelty(audio: &[f64]) -> Vec<f64> {
+    let spectrogram = rstft(audio, ONSET_FFT_SIZE, ONSET_HOP_SIZE, aus::WindowType::Hanning);
+    let (magnitude_spectrogram, _) = complex_to_polar_rstft(&spectrogram);
+    let mut novelty = vec![0.0; magnitude_spectrogram.len()];
+    for i in 1..magnitude_spectrogram.len() {
+        let mut flux = 0.0;
+        for (current, previous) in magnitude_spectrogram[i].iter().zip(magnitude_spectrogram[i - 1].iter()) {
+            let diff = (1.0 + 100.0 * current).ln() - (1.0 + 100.0 * previous).ln();
+            if diff > 0.0 {
+                flux += diff;
+            }
+        }
+        novelty[i] = flux;
+    }
+    novelty
+}
+
+/// Computes a half-wave rectified energy novelty curve (the increase in log energy between frames).
+/// Returns one novelty value per hop.
+pub fn energy_novelty(audio: &[f64]) -> Vec<f64> {
+    let mut log_energy: Vec<f64> = Vec::with_capacity(audio.len() / ONSET_HOP_SIZE + 1);
+    let mut start_idx = 0;
+    while start_idx < audio.len() {
+        let end_idx = usize::min(start_idx + ONSET_FFT_SIZE, audio.len());
+        let energy: f64 = audio[start_idx..end_idx].iter().map(|x| x * x).sum::<f64>() / (end_idx - start_idx) as f64;
+        log_energy.push((1.0 + 1000.0 * energy).ln());
+        start_idx += ONSET_HOP_SIZE;
+    }
+    let mut novelty = vec![0.0; log_energy.len()];
+    for i in 1..log_energy.len() {
+        novelty[i] = f64::max(log_energy[i] - log_energy[i - 1], 0.0);
+    }
+    novelty
+}
+
+/// Picks peaks in a novelty curve. A frame is a peak if it is the local maximum within
+/// `PEAK_PICK_RADIUS` frames, and if it exceeds the local mean by `threshold` (the novelty
+/// curve is normalized to a maximum of 1.0 first). Peaks closer than `min_gap` frames to
+/// the previous peak are discarded.
+/// Returns the frame indices of the peaks.
+pub fn pick_peaks(novelty: &[f64], threshold: f64, min_gap: usize) -> Vec<usize> {
+    let mut peaks: Vec<usize> = Vec::new();
+    let max_novelty = novelty.iter().fold(0.0, |a: f64, &b| a.max(b));
+    if max_novelty <= 0.0 {
+        return peaks;
+    }
+    let normalized: Vec<f64> = novelty.iter().map(|x| x / max_novelty).collect();
+
+    for i in 0..normalized.len() {
+        let peak_start = i.saturating_sub(PEAK_PICK_RADIUS);
+        let peak_end = usize::min(i + PEAK_PICK_RADIUS + 1, normalized.len());
+        let local_max = normalized[peak_start..peak_end].iter().fold(0.0, |a: f64, &b| a.max(b));
+        if normalized[i] < local_max || normalized[i] <= 0.0 {
+            continue;
+        }
+
+        let mean_start = i.saturating_sub(ADAPTIVE_THRESHOLD_RADIUS);
+        let mean_end = usize::min(i + ADAPTIVE_THRESHOLD_RADIUS + 1, normalized.len());
+        let local_mean = normalized[mean_start..mean_end].iter().sum::<f64>() / (mean_end - mean_start) as f64;
+        if normalized[i] < local_mean + threshold {
+            continue;
+        }
+
+        match peaks.last() {
+            Some(&last) if i - last < min_gap => (),
+            _ => peaks.push(i)
+        }
+    }
+    peaks
+}
+
+/// Refines an onset position found at novelty frame resolution. The novelty frame starting at
+/// `frame_start` is split into small blocks, and the onset is moved to the start of the block
+/// with the largest increase in energy over the preceding block.
+pub fn refine_onset(audio: &[f64], frame_start: usize) -> usize {
+    let frame_end = usize::min(frame_start + ONSET_FFT_SIZE, audio.len());
+    let mut onset = frame_start;
+    let mut max_increase = 0.0;
+    let mut prev_energy: f64 = match frame_start.checked_sub(REFINEMENT_BLOCK_SIZE) {
+        Some(start) => audio[start..frame_start].iter().map(|x| x * x).sum(),
+        None => 0.0
+    };
+    let mut block_start = frame_start;
+    while block_start + REFINEMENT_BLOCK_SIZE <= frame_end {
+        let energy: f64 = audio[block_start..block_start + REFINEMENT_BLOCK_SIZE].iter().map(|x| x * x).sum();
+        if energy - prev_energy > max_increase {
+            max_increase = energy - prev_energy;
+            onset = block_start;
+        }
+        prev_energy = energy;
+        block_start += REFINEMENT_BLOCK_SIZE;
+    }
+    onset
+}
+
+/// Detects onsets in an audio sequence.
+/// The `threshold` is relative to the normalized novelty curve (0.0 to 1.0), and `min_spacing`
+/// is the minimum number of samples between consecutive onsets.
+/// Returns the onset positions in samples.
+pub fn detect_onsets(audio: &[f64], novelty_function: NoveltyFunction, threshold: f64, min_spacing: usize) -> Vec<usize> {
+    let novelty = match novelty_function {
+        NoveltyFunction::SpectralFlux => spectral_flux_novelty(audio),
+        NoveltyFunction::Energy => energy_novelty(audio)
+    };
+    let min_gap = min_spacing.div_ceil(ONSET_HOP_SIZE);
+    pick_peaks(&novelty, threshold, min_gap).iter().map(|frame| refine_onset(audio, frame * ONSET_HOP_SIZE)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    const CLICKS: [usize; 4] = [10000, 30000, 50000, 70000];
+
+    /// Makes a train of short decaying noise bursts at the `CLICKS` positions
+    fn click_train() -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut audio = vec![0.0; 88200];
+        for &click in CLICKS.iter() {
+            for i in 0..256 {
+                audio[click + i] = rng.gen_range(-1.0..1.0) * (-(i as f64) / 50.0).exp();
+            }
+        }
+        audio
+    }
+
+    fn assert_onsets_at_clicks(onsets: &[usize]) {
+        assert_eq!(onsets.len(), CLICKS.len(), "onsets {:?}", onsets);
+        for (&onset, &click) in onsets.iter().zip(CLICKS.iter()) {
+            assert!(onset.abs_diff(click) <= ONSET_HOP_SIZE, "onset {} is too far from the click at {}", onset, click);
+        }
+    }
+
+    #[test]
+    fn spectral_flux_finds_clicks() {
+        assert_onsets_at_clicks(&detect_onsets(&click_train(), NoveltyFunction::SpectralFlux, 0.3, 4410));
+    }
+
+    #[test]
+    fn energy_novelty_finds_clicks() {
+        assert_onsets_at_clicks(&detect_onsets(&click_train(), NoveltyFunction::Energy, 0.3, 4410));
+    }
+
+    #[test]
+    fn silence_has_no_onsets() {
+        let audio = vec![0.0; 44100];
+        assert!(detect_onsets(&audio, NoveltyFunction::SpectralFlux, 0.3, 4410).is_empty());
+        assert!(detect_onsets(&audio, NoveltyFunction::Energy, 0.3, 4410).is_empty());
+    }
+
+    #[test]
+    fn peaks_are_local_maxima_above_the_local_mean() {
+        let mut novelty = vec![0.0; 40];
+        novelty[5] = 1.0;
+        novelty[7] = 0.8;
+        novelty[20] = 0.5;
+        novelty[30] = 0.05;
+        // 7 is within the peak picking radius of the larger peak at 5, and 30 is below the threshold
+        assert_eq!(pick_peaks(&novelty, 0.1, 1), vec![5, 20]);
+        // 20 is too close to 5
+        assert_eq!(pick_peaks(&novelty, 0.1, 16), vec![5]);
+        assert!(pick_peaks(&[0.0; 10], 0.1, 1).is_empty());
+    }
+
+    #[test]
+    fn onsets_are_refined_to_the_block_where_energy_rises() {
+        let mut audio = vec![0.0; 4096];
+        for x in audio[768..].iter_mut() {
+            *x = 0.5;
+        }
+        assert_eq!(refine_onset(&audio, 512), 768);
+        assert_eq!(refine_onset(&audio, 0), 768);
+        // A frame with no rise in energy keeps its position
+        assert_eq!(refine_onset(&audio, 2048), 2048);
+    }
+}
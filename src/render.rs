@@ -0,0 +1,391 @@
+// File: render.rs
+// This file has offline granular synthesis. It reads grains from the database and renders them
+// according to a JSON score.
+
+use crate::{grain_extractor, io, sqlite};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Serialize, Deserialize};
+use std::f64::consts::FRAC_PI_2;
+use std::fmt;
+use std::fs;
+
+#[derive(Debug, Clone)]
+pub enum RenderError {
+    Score(String),
+    Database(String),
+    Audio(String)
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Score(msg) => write!(f, "{}", msg),
+            RenderError::Database(msg) => write!(f, "{}", msg),
+            RenderError::Audio(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+/// An envelope over time. It can be a constant number, or a list of [time, value] breakpoints
+/// with times in seconds. Values between breakpoints are interpolated linearly, and values
+/// outside the breakpoints hold the first or last value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Envelope {
+    Constant(f64),
+    Breakpoints(Vec<(f64, f64)>)
+}
+
+impl Envelope {
+    /// Gets the envelope value at a time in seconds
+    pub fn value_at(&self, time: f64) -> f64 {
+        match self {
+            Envelope::Constant(value) => *value,
+            Envelope::Breakpoints(points) => {
+                if points.is_empty() {
+                    return 0.0;
+                }
+                if time <= points[0].0 {
+                    return points[0].1;
+                }
+                for pair in points.windows(2) {
+                    let (t1, v1) = pair[0];
+                    let (t2, v2) = pair[1];
+                    if time <= t2 {
+                        if t2 <= t1 {
+                            return v2;
+                        }
+                        return v1 + (v2 - v1) * (time - t1) / (t2 - t1);
+                    }
+                }
+                points[points.len() - 1].1
+            }
+        }
+    }
+}
+
+/// A grain selection for a section of the score. While the selection is active, grains are drawn
+/// from the grains that match its query. If several selections are active at once, one of them is
+/// chosen at random for each grain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Selection {
+    /// The time in seconds when the selection becomes active
+    #[serde(default)]
+    pub start: f64,
+    /// The time in seconds when the selection stops being active. If omitted, it stays active until the end.
+    #[serde(default)]
+    pub end: Option<f64>,
+    #[serde(flatten)]
+    pub query: sqlite::GrainQuery,
+}
+
+/// A score for the granular synthesis renderer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Score {
+    pub output_path: String,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+    /// The duration in seconds
+    pub duration: f64,
+    /// The number of grains per second
+    pub density: Envelope,
+    /// The grain amplitude, from 0.0 to 1.0
+    #[serde(default = "default_amplitude")]
+    pub amplitude: Envelope,
+    /// The grain pan position, from 0.0 (left) to 1.0 (right)
+    #[serde(default = "default_pan")]
+    pub pan: Envelope,
+    pub selections: Vec<Selection>,
+    #[serde(default)]
+    pub window: io::WindowShape,
+    /// The maximum length of the grain window. If the grain is longer, the window is split to fade in and out
+    /// the grain edges. If omitted, the window covers the whole grain.
+    #[serde(default)]
+    pub max_window_length: Option<usize>,
+    /// Whether to scale each grain to a peak of 1.0 before applying the amplitude envelope
+    #[serde(default = "default_normalize_grains")]
+    pub normalize_grains: bool,
+    /// The random seed. If omitted, every render is different.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+fn default_sample_rate() -> u32 {
+    44100
+}
+
+fn default_amplitude() -> Envelope {
+    Envelope::Constant(0.5)
+}
+
+fn default_pan() -> Envelope {
+    Envelope::Constant(0.5)
+}
+
+fn default_normalize_grains() -> bool {
+    true
+}
+
+/// Reads a score file
+pub fn read_score(score_file_path: &str) -> Result<Score, RenderError> {
+    let score_contents = match fs::read_to_string(score_file_path) {
+        Ok(x) => x,
+        Err(err) => return Err(RenderError::Score(format!("Error reading score file: {}", err)))
+    };
+    match serde_json::from_str(&score_contents) {
+        Ok(x) => Ok(x),
+        Err(err) => Err(RenderError::Score(format!("Error parsing score file: {}", err)))
+    }
+}
+
+/// Resamples audio with linear interpolation
+pub fn resample(audio: &[f64], source_rate: u32, target_rate: u32) -> Vec<f64> {
+    if source_rate == target_rate || audio.is_empty() {
+        return audio.to_vec();
+    }
+    let ratio = source_rate as f64 / target_rate as f64;
+    let output_len = (audio.len() as f64 / ratio).floor() as usize;
+    let mut output: Vec<f64> = Vec::with_capacity(output_len);
+    for i in 0..output_len {
+        let pos = i as f64 * ratio;
+        let idx = pos.floor() as usize;
+        let frac = pos - idx as f64;
+        let next = if idx + 1 < audio.len() { audio[idx + 1] } else { audio[idx] };
+        output.push(audio[idx] * (1.0 - frac) + next * frac);
+    }
+    output
+}
+
+// The most audio frames that the source file cache keeps, which is about 20 minutes of audio at 44.1 kHz
+const MAX_CACHED_FRAMES: usize = 44100 * 60 * 20;
+
+/// A decoded source file: its path, and its sample rate and samples if it could be decoded
+type CachedFile = (String, Option<(u32, Vec<f64>)>);
+
+/// A cache of decoded source files, mixed to mono. When the files in it are longer than `MAX_CACHED_FRAMES`
+/// altogether, the least recently used files are dropped, so only the most recent file can be longer.
+/// Files that can't be decoded are cached as `None` so that they aren't decoded again.
+pub struct SourceCache {
+    /// The cached files, least recently used first
+    files: Vec<CachedFile>,
+    num_frames: usize,
+    max_frames: usize,
+}
+
+impl Default for SourceCache {
+    fn default() -> SourceCache {
+        SourceCache{files: Vec::new(), num_frames: 0, max_frames: MAX_CACHED_FRAMES}
+    }
+}
+
+impl SourceCache {
+    pub fn new() -> SourceCache {
+        SourceCache::default()
+    }
+
+    /// Gets the sample rate and samples of a source file, decoding it if it isn't cached
+    fn get(&mut self, path: &str) -> Option<&(u32, Vec<f64>)> {
+        match self.files.iter().position(|(file, _)| file == path) {
+            Some(i) => {
+                let entry = self.files.remove(i);
+                self.files.push(entry);
+            },
+            None => {
+                let audio = match io::read_mono(path) {
+                    Ok(x) => Some(x),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        None
+                    }
+                };
+                self.num_frames += audio.as_ref().map_or(0, |(_, x)| x.len());
+                self.files.push((String::from(path), audio));
+                while self.files.len() > 1 && self.num_frames > self.max_frames {
+                    let (_, evicted) = self.files.remove(0);
+                    self.num_frames -= evicted.as_ref().map_or(0, |(_, x)| x.len());
+                }
+            }
+        }
+        self.files.last().and_then(|(_, audio)| audio.as_ref())
+    }
+}
+
+/// Loads the audio of a grain from its source file. Source files are mixed to mono and cached,
+/// since a render usually draws many grains from the same file.
+pub fn load_grain(grain: &sqlite::GrainLocation, cache: &mut SourceCache) -> Option<(u32, Vec<f64>)> {
+    match cache.get(&grain.file) {
+        Some((sample_rate, audio)) if grain.start_frame < grain.end_frame && grain.end_frame <= audio.len() => {
+            Some((*sample_rate, audio[grain.start_frame..grain.end_frame].to_vec()))
+        },
+        _ => None
+    }
+}
+
+/// Renders a score to a stereo audio file, using grains from the database
+pub fn render(db: &str, score: &Score) -> Result<(), RenderError> {
+    if score.duration <= 0.0 || score.sample_rate == 0 {
+        return Err(RenderError::Score(String::from("The score duration and sample rate must be greater than 0.")));
+    }
+
+    // Find the candidate grains for each selection
+    let mut candidates: Vec<Vec<sqlite::GrainLocation>> = Vec::with_capacity(score.selections.len());
+    for (i, selection) in score.selections.iter().enumerate() {
+        let grains = match sqlite::select_grains(db, &selection.query) {
+            Ok(x) => x,
+            Err(err) => return Err(RenderError::Database(format!("Error in selection {}: {}", i, err)))
+        };
+        eprintln!("Selection {} matches {} grains.", i, grains.len());
+        candidates.push(grains);
+    }
+
+    let mut channels = render_channels(score, &candidates, &mut SourceCache::new());
+
+    // Prevent clipping
+    let peak = channels.iter().flatten().fold(0.0, |a: f64, &b| a.max(b.abs()));
+    if peak > 1.0 {
+        eprintln!("The render peaked at {:.2}, so it was scaled down to avoid clipping.", peak);
+        channels.iter_mut().flatten().for_each(|x| *x /= peak);
+    }
+
+    let output = aus::AudioFile::new(aus::AudioFormat::S24, score.sample_rate, channels);
+    match aus::write(&score.output_path, &output) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(RenderError::Audio(format!("Error writing {}: {:?}", score.output_path, err)))
+    }
+}
+
+/// Gets the left and right gains of a grain with constant power panning, so that the power of the
+/// two channels together is the same at every pan position
+fn pan_gains(amplitude: f64, pan: f64) -> [f64; 2] {
+    let pan = pan.clamp(0.0, 1.0);
+    [amplitude * (pan * FRAC_PI_2).cos(), amplitude * (pan * FRAC_PI_2).sin()]
+}
+
+/// Renders the grains of a score to two channels, drawing each grain from the candidates of an active selection
+fn render_channels(score: &Score, candidates: &[Vec<sqlite::GrainLocation>], cache: &mut SourceCache) -> Vec<Vec<f64>> {
+    let mut rng = match score.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+    };
+    let num_frames = (score.duration * score.sample_rate as f64).ceil() as usize;
+    let mut channels: Vec<Vec<f64>> = vec![vec![0.0; num_frames]; 2];
+    let mut num_grains: usize = 0;
+
+    // Grain onsets follow a Poisson process whose rate is the density envelope
+    let mut time = 0.0;
+    loop {
+        let density = f64::max(score.density.value_at(time), 1e-6);
+        time += -(1.0 - rng.gen::<f64>()).ln() / density;
+        if time >= score.duration {
+            break;
+        }
+
+        let active: Vec<usize> = (0..score.selections.len()).filter(|&i| {
+            let selection = &score.selections[i];
+            selection.start <= time && time < selection.end.unwrap_or(f64::INFINITY) && !candidates[i].is_empty()
+        }).collect();
+        if active.is_empty() {
+            continue;
+        }
+        let selection_candidates = &candidates[active[rng.gen_range(0..active.len())]];
+        let grain = &selection_candidates[rng.gen_range(0..selection_candidates.len())];
+        let mut grain_audio = match load_grain(grain, cache) {
+            Some((sample_rate, audio)) => resample(&audio, sample_rate, score.sample_rate),
+            None => continue
+        };
+        if grain_audio.is_empty() {
+            continue;
+        }
+
+        if score.normalize_grains {
+            let peak = grain_audio.iter().fold(0.0, |a: f64, &b| a.max(b.abs()));
+            if peak > 0.0 {
+                grain_audio.iter_mut().for_each(|x| *x /= peak);
+            }
+        }
+        let window_length = match score.max_window_length {
+            Some(x) => usize::min(x, grain_audio.len()),
+            None => grain_audio.len()
+        };
+        let window = aus::generate_window(score.window.window_type(), window_length);
+        grain_extractor::apply_window(&mut grain_audio, &window);
+
+        let gains = pan_gains(score.amplitude.value_at(time), score.pan.value_at(time));
+        let onset = (time * score.sample_rate as f64) as usize;
+        for (channel, gain) in channels.iter_mut().zip(gains.iter()) {
+            for (out, sample) in channel[onset..].iter_mut().zip(grain_audio.iter()) {
+                *out += sample * gain;
+            }
+        }
+        num_grains += 1;
+    }
+    eprintln!("Rendered {} grains.", num_grains);
+    channels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    /// Writes a mono WAV file in the temporary directory with constant samples. The file is deleted when it is dropped.
+    fn constant_wav(name: &str, num_frames: usize, value: f64) -> TempFile {
+        let file = TempFile::new("render", &format!("{}.wav", name));
+        aus::write(&file.0, &aus::AudioFile::new(aus::AudioFormat::S24, 44100, vec![vec![value; num_frames]])).unwrap();
+        file
+    }
+
+    fn location(file: &str, num_frames: usize) -> sqlite::GrainLocation {
+        sqlite::GrainLocation{file: String::from(file), start_frame: 0, end_frame: num_frames}
+    }
+
+    #[test]
+    fn constant_power_pan_has_unit_power() {
+        for i in 0..=10 {
+            let [left, right] = pan_gains(1.0, i as f64 / 10.0);
+            assert!((left * left + right * right - 1.0).abs() < 1e-12);
+        }
+        assert_eq!(pan_gains(0.5, 0.0), [0.5, 0.0]);
+        // Pan positions outside 0.0 to 1.0 are clamped
+        assert_eq!(pan_gains(1.0, -1.0), pan_gains(1.0, 0.0));
+    }
+
+    #[test]
+    fn one_grain_renders_at_its_onset() {
+        let file = constant_wav("one_grain", 1000, 0.5);
+        // The first onset is drawn the same way as the renderer draws it, and the score ends just after that grain
+        let (seed, density) = (7, 2.0);
+        let onset_time = -(1.0 - StdRng::seed_from_u64(seed).gen::<f64>()).ln() / density;
+        let score: Score = serde_json::from_str(&format!(r#"{{
+            "output_path": "unused.wav", "duration": {}, "density": {}, "amplitude": 1.0, "pan": 0.0,
+            "selections": [{{}}], "window": "rectangular", "normalize_grains": false, "seed": {}
+        }}"#, onset_time + 1100.0 / 44100.0, density, seed)).unwrap();
+        let channels = render_channels(&score, &[vec![location(&file.0, 1000)]], &mut SourceCache::new());
+
+        let onset = (onset_time * 44100.0) as usize;
+        for (i, (left, right)) in channels[0].iter().zip(channels[1].iter()).enumerate() {
+            let expected = if (onset..onset + 1000).contains(&i) { 0.5 } else { 0.0 };
+            assert!((left - expected).abs() < 1e-6, "left sample {} is {}, not {}", i, left, expected);
+            assert_eq!(*right, 0.0);
+        }
+    }
+
+    #[test]
+    fn cache_evicts_the_least_recently_used_files() {
+        let files: Vec<TempFile> = (0..3).map(|i| constant_wav(&format!("cache_{}", i), 400, 0.25)).collect();
+        let mut cache = SourceCache{files: Vec::new(), num_frames: 0, max_frames: 1000};
+        for file in files.iter() {
+            assert!(cache.get(&file.0).is_some());
+        }
+        // The first file is dropped when the third would bring the cache past its limit
+        let cached: Vec<&String> = cache.files.iter().map(|(file, _)| file).collect();
+        assert_eq!(cached, vec![&files[1].0, &files[2].0]);
+        assert_eq!(cache.num_frames, 800);
+
+        // Using a file moves it to the end, so the other one is dropped next
+        assert!(cache.get(&files[1].0).is_some());
+        assert!(cache.get(&files[0].0).is_some());
+        let cached: Vec<&String> = cache.files.iter().map(|(file, _)| file).collect();
+        assert_eq!(cached, vec![&files[1].0, &files[0].0]);
+    }
+}
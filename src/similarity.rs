@@ -0,0 +1,562 @@
+// File: similarity.rs
+// This file has grain similarity. Grain features are normalized with statistics of the whole corpus,
+// which are stored in the database, and compared with a weighted distance metric.
+
+use crate::grain_extractor::GrainEntry;
+use crate::sqlite;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+/// The features that are compared by default
+pub const DEFAULT_FEATURES: [&str; 16] = [
+    "energy",
+    "midi",
+    "spectral_centroid",
+    "spectral_entropy",
+    "spectral_flatness",
+    "spectral_kurtosis",
+    "spectral_roll_off_50",
+    "spectral_roll_off_75",
+    "spectral_roll_off_90",
+    "spectral_roll_off_95",
+    "spectral_skewness",
+    "spectral_slope",
+    "spectral_slope_0_1_khz",
+    "spectral_slope_1_5_khz",
+    "spectral_slope_0_5_khz",
+    "spectral_variance",
+];
+
+// Added to the diagonal of the covariance matrix before it is inverted, so that features
+// that are constant or perfectly correlated don't make it singular
+const COVARIANCE_REGULARIZATION: f64 = 1e-6;
+
+/// How features are scaled before they are compared
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalization {
+    /// Subtract the corpus mean and divide by the corpus standard deviation
+    #[default]
+    ZScore,
+    /// Scale the corpus range to 0.0 to 1.0
+    MinMax
+}
+
+/// How the distance between two normalized feature vectors is measured
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DistanceMetric {
+    #[default]
+    Euclidean,
+    /// One minus the cosine similarity
+    Cosine,
+    Manhattan,
+    /// The Euclidean distance after the features are decorrelated with the corpus covariance
+    Mahalanobis
+}
+
+/// The settings for comparing grains
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SimilaritySettings {
+    #[serde(default)]
+    pub normalization: Normalization,
+    #[serde(default)]
+    pub metric: DistanceMetric,
+    /// The weight of each feature. Features that are not listed have a weight of 1.0 if they are
+    /// in `DEFAULT_FEATURES`, and 0.0 otherwise. A feature with a weight of 0.0 is ignored.
+    #[serde(default)]
+    pub weights: BTreeMap<String, f64>,
+}
+
+impl SimilaritySettings {
+    /// Checks the settings. Returns a description of every problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        for (feature, weight) in self.weights.iter() {
+            if !sqlite::feature_columns().contains(&feature.as_str()) {
+                problems.push(format!("The similarity weight for {} is not for a known feature.", feature));
+            }
+            if !weight.is_finite() || *weight < 0.0 {
+                problems.push(format!("The similarity weight for {} is not a number of at least 0.", feature));
+            }
+        }
+        if self.features().is_empty() {
+            problems.push(String::from("All similarity weights are 0."));
+        }
+        problems
+    }
+
+    /// Gets the features that are compared, with their weights
+    pub fn features(&self) -> Vec<(String, f64)> {
+        let mut features: Vec<(String, f64)> = Vec::new();
+        for column in sqlite::feature_columns().iter() {
+            let weight = match self.weights.get(*column) {
+                Some(x) => *x,
+                None if DEFAULT_FEATURES.contains(column) => 1.0,
+                None => 0.0
+            };
+            if weight > 0.0 {
+                features.push((String::from(*column), weight));
+            }
+        }
+        features
+    }
+}
+
+/// Statistics of one feature over all grains in the corpus
+#[derive(Debug, Clone, Default)]
+pub struct FeatureStats {
+    /// The number of grains that have the feature
+    pub count: usize,
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Statistics of the grain features over the whole corpus
+#[derive(Debug, Clone, Default)]
+pub struct CorpusStats {
+    pub features: BTreeMap<String, FeatureStats>,
+    /// The covariance of each pair of features, keyed by the feature names in alphabetical order
+    pub covariance: BTreeMap<(String, String), f64>,
+}
+
+impl CorpusStats {
+    /// Gets the covariance of two features. Returns 0.0 if it is not known.
+    pub fn covariance(&self, a: &str, b: &str) -> f64 {
+        let key = if a <= b { (String::from(a), String::from(b)) } else { (String::from(b), String::from(a)) };
+        self.covariance.get(&key).copied().unwrap_or(0.0)
+    }
+}
+
+/// Computes corpus statistics one grain at a time. Missing (NaN) features are left out, and each
+/// covariance is computed over the grains that have both features. The covariances take time quadratic
+/// in the number of features, so they are only computed if they are asked for.
+pub struct StatsAccumulator {
+    columns: Vec<String>,
+    // The first value of each feature. The values are shifted by it to keep the sums small and precise.
+    shift: Vec<Option<f64>>,
+    count: Vec<usize>,
+    sum: Vec<f64>,
+    sum_squares: Vec<f64>,
+    min: Vec<f64>,
+    max: Vec<f64>,
+    // For each pair (i, j) with i < j: the number of grains with both features, the sums of each, and the sum of products.
+    // This is empty if the covariances are not computed.
+    pair_sums: Vec<Vec<(usize, f64, f64, f64)>>,
+}
+
+impl StatsAccumulator {
+    pub fn new(columns: &[&str], with_covariance: bool) -> StatsAccumulator {
+        let n = columns.len();
+        StatsAccumulator{
+            columns: columns.iter().map(|x| String::from(*x)).collect(),
+            shift: vec![None; n],
+            count: vec![0; n],
+            sum: vec![0.0; n],
+            sum_squares: vec![0.0; n],
+            min: vec![f64::INFINITY; n],
+            max: vec![f64::NEG_INFINITY; n],
+            pair_sums: if with_covariance { (0..n).map(|i| vec![(0, 0.0, 0.0, 0.0); n - i - 1]).collect() } else { Vec::new() },
+        }
+    }
+
+    /// Adds the features of a grain, in the same order as the columns
+    pub fn add(&mut self, values: &[f64]) {
+        let mut shifted: Vec<Option<f64>> = Vec::with_capacity(values.len());
+        for (i, &value) in values.iter().enumerate() {
+            if !value.is_finite() {
+                shifted.push(None);
+                continue;
+            }
+            let shift = *self.shift[i].get_or_insert(value);
+            let x = value - shift;
+            self.count[i] += 1;
+            self.sum[i] += x;
+            self.sum_squares[i] += x * x;
+            self.min[i] = f64::min(self.min[i], value);
+            self.max[i] = f64::max(self.max[i], value);
+            shifted.push(Some(x));
+        }
+        for (i, pairs) in self.pair_sums.iter_mut().enumerate() {
+            if let Some(a) = shifted[i] {
+                for (j, sums) in pairs.iter_mut().enumerate() {
+                    if let Some(b) = shifted[i + j + 1] {
+                        sums.0 += 1;
+                        sums.1 += a;
+                        sums.2 += b;
+                        sums.3 += a * b;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Computes the statistics. Features that no grain has are left out, and so are the covariances of
+    /// features that no grain has both of.
+    pub fn finish(&self) -> CorpusStats {
+        let mut stats = CorpusStats::default();
+        for (i, column) in self.columns.iter().enumerate() {
+            if self.count[i] == 0 {
+                continue;
+            }
+            let n = self.count[i] as f64;
+            let mean = self.sum[i] / n;
+            let variance = f64::max(self.sum_squares[i] / n - mean * mean, 0.0);
+            stats.features.insert(column.clone(), FeatureStats{
+                count: self.count[i],
+                mean: mean + self.shift[i].unwrap_or(0.0),
+                std: variance.sqrt(),
+                min: self.min[i],
+                max: self.max[i]
+            });
+            let pairs = match self.pair_sums.get(i) {
+                Some(x) => x,
+                None => continue
+            };
+            stats.covariance.insert((column.clone(), column.clone()), variance);
+            for (j, &(count, sum_a, sum_b, sum_ab)) in pairs.iter().enumerate() {
+                if count == 0 {
+                    continue;
+                }
+                let n = count as f64;
+                let other = &self.columns[i + j + 1];
+                let key = if column <= other { (column.clone(), other.clone()) } else { (other.clone(), column.clone()) };
+                stats.covariance.insert(key, sum_ab / n - (sum_a / n) * (sum_b / n));
+            }
+        }
+        stats
+    }
+}
+
+/// Inverts a square matrix with Gauss-Jordan elimination. Returns `None` if it is singular.
+fn invert(matrix: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = matrix.len();
+    let mut a: Vec<Vec<f64>> = matrix.to_vec();
+    let mut inverse: Vec<Vec<f64>> = (0..n).map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect()).collect();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&x, &y| a[x][col].abs().total_cmp(&a[y][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = a[col][col];
+        for j in 0..n {
+            a[col][j] /= scale;
+            inverse[col][j] /= scale;
+        }
+        for row in 0..n {
+            if row != col {
+                let factor = a[row][col];
+                if factor != 0.0 {
+                    for j in 0..n {
+                        a[row][j] -= factor * a[col][j];
+                        inverse[row][j] -= factor * inverse[col][j];
+                    }
+                }
+            }
+        }
+    }
+    Some(inverse)
+}
+
+/// Compares grains. It is built from similarity settings and corpus statistics, and turns grains into
+/// normalized feature vectors, which can then be compared with the distance metric.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Similarity {
+    columns: Vec<String>,
+    weights: Vec<f64>,
+    offsets: Vec<f64>,
+    scales: Vec<f64>,
+    metric: DistanceMetric,
+    /// The inverse covariance matrix of the normalized features, for the Mahalanobis distance
+    inverse_covariance: Vec<Vec<f64>>,
+}
+
+impl Similarity {
+    pub fn new(settings: &SimilaritySettings, stats: &CorpusStats) -> Similarity {
+        let features = settings.features();
+        let mut similarity = Similarity{
+            columns: features.iter().map(|(column, _)| column.clone()).collect(),
+            weights: features.iter().map(|(_, weight)| *weight).collect(),
+            offsets: Vec::with_capacity(features.len()),
+            scales: Vec::with_capacity(features.len()),
+            metric: settings.metric,
+            inverse_covariance: Vec::new()
+        };
+        // A feature that doesn't vary in the corpus can't be scaled, so it is only centered
+        for (column, _) in features.iter() {
+            let (offset, range) = match (stats.features.get(column), settings.normalization) {
+                (Some(x), Normalization::ZScore) => (x.mean, x.std),
+                (Some(x), Normalization::MinMax) => (x.min, x.max - x.min),
+                (None, _) => (0.0, 0.0)
+            };
+            similarity.offsets.push(offset);
+            similarity.scales.push(if range > 0.0 && range.is_finite() { 1.0 / range } else { 0.0 });
+        }
+
+        if settings.metric == DistanceMetric::Mahalanobis {
+            let n = similarity.columns.len();
+            let covariance: Vec<Vec<f64>> = (0..n).map(|i| (0..n).map(|j| {
+                let value = stats.covariance(&similarity.columns[i], &similarity.columns[j]) * similarity.scales[i] * similarity.scales[j];
+                if i == j { value + COVARIANCE_REGULARIZATION } else { value }
+            }).collect()).collect();
+            similarity.inverse_covariance = match invert(&covariance) {
+                Some(x) => x,
+                // Fall back to treating the features as uncorrelated
+                None => (0..n).map(|i| (0..n).map(|j| if i == j { 1.0 / covariance[i][i] } else { 0.0 }).collect()).collect()
+            };
+        }
+        similarity
+    }
+
+    /// Gets the normalized feature vector of a grain. A missing (NaN) feature is replaced with the corpus mean.
+    pub fn features(&self, grain: &GrainEntry) -> Vec<f64> {
+        self.normalize(|column| grain.feature(column))
+    }
+
+    /// Scales a normalized feature vector by the square root of the feature weights, so that the unweighted
+    /// euclidean distance between scaled vectors is the weighted euclidean distance between the vectors
+    pub fn weighted(&self, vector: &[f64]) -> Vec<f64> {
+        vector.iter().zip(self.weights.iter()).map(|(x, w)| x * w.sqrt()).collect()
+    }
+
+    /// Converts a normalized feature vector back to feature values, with their column names.
+    /// A feature that doesn't vary in the corpus gets its corpus value.
+    pub fn denormalize(&self, vector: &[f64]) -> Vec<(String, f64)> {
+        self.columns.iter().enumerate().map(|(i, column)| {
+            let value = if self.scales[i] > 0.0 { self.offsets[i] + vector[i] / self.scales[i] } else { self.offsets[i] };
+            (column.clone(), value)
+        }).collect()
+    }
+
+    /// Gets a normalized feature vector from feature values, which are looked up by column name.
+    /// A missing (`None` or NaN) feature is replaced with the corpus mean.
+    pub fn normalize(&self, feature: impl Fn(&str) -> Option<f64>) -> Vec<f64> {
+        self.columns.iter().enumerate().map(|(i, column)| {
+            match feature(column) {
+                Some(x) if x.is_finite() => (x - self.offsets[i]) * self.scales[i],
+                _ => 0.0
+            }
+        }).collect()
+    }
+
+    /// Gets the distance between two normalized feature vectors as a true metric, which obeys the triangle inequality,
+    /// so that it can be used for searching. This is the same as `distance`, except for the cosine distance, which is
+    /// converted to the Euclidean distance between the feature vectors scaled to unit length.
+    pub fn metric_distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self.metric {
+            DistanceMetric::Cosine => (2.0 * self.distance(a, b)).sqrt(),
+            _ => self.distance(a, b)
+        }
+    }
+
+    /// Converts a distance from `distance` to a distance from `metric_distance`
+    pub fn metric_radius(&self, distance: f64) -> f64 {
+        match self.metric {
+            DistanceMetric::Cosine => (2.0 * distance.max(0.0)).sqrt(),
+            _ => distance
+        }
+    }
+
+    /// Converts a distance from `metric_distance` back to a distance from `distance`
+    pub fn convert_metric_distance(&self, distance: f64) -> f64 {
+        match self.metric {
+            DistanceMetric::Cosine => distance * distance / 2.0,
+            _ => distance
+        }
+    }
+
+    /// Gets the distance between two normalized feature vectors. The distance is 0.0 for identical vectors,
+    /// and the same in both directions.
+    pub fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self.metric {
+            DistanceMetric::Euclidean => {
+                a.iter().zip(b.iter()).zip(self.weights.iter()).map(|((x, y), w)| w * (x - y) * (x - y)).sum::<f64>().sqrt()
+            },
+            DistanceMetric::Manhattan => {
+                a.iter().zip(b.iter()).zip(self.weights.iter()).map(|((x, y), w)| w * (x - y).abs()).sum()
+            },
+            DistanceMetric::Cosine => {
+                let mut dot = 0.0;
+                let mut norm_a = 0.0;
+                let mut norm_b = 0.0;
+                for ((x, y), w) in a.iter().zip(b.iter()).zip(self.weights.iter()) {
+                    dot += w * x * y;
+                    norm_a += w * x * x;
+                    norm_b += w * y * y;
+                }
+                if norm_a == 0.0 || norm_b == 0.0 {
+                    // A grain at the corpus center has no direction, so it is only identical to another one
+                    return if norm_a == norm_b { 0.0 } else { 1.0 };
+                }
+                f64::max(1.0 - dot / (norm_a.sqrt() * norm_b.sqrt()), 0.0)
+            },
+            DistanceMetric::Mahalanobis => {
+                // The weights scale the differences, so they still apply after decorrelation
+                let diff: Vec<f64> = a.iter().zip(b.iter()).zip(self.weights.iter()).map(|((x, y), w)| w.sqrt() * (x - y)).collect();
+                let mut sum = 0.0;
+                for (i, row) in self.inverse_covariance.iter().enumerate() {
+                    sum += diff[i] * row.iter().zip(diff.iter()).map(|(p, d)| p * d).sum::<f64>();
+                }
+                f64::max(sum, 0.0).sqrt()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    /// Makes settings that compare only the given features, with their weights
+    fn settings(metric: DistanceMetric, normalization: Normalization, weights: &[(&str, f64)]) -> SimilaritySettings {
+        let mut all_weights: BTreeMap<String, f64> = DEFAULT_FEATURES.iter().map(|x| (String::from(*x), 0.0)).collect();
+        for (feature, weight) in weights.iter() {
+            all_weights.insert(String::from(*feature), *weight);
+        }
+        SimilaritySettings{normalization, metric, weights: all_weights}
+    }
+
+    fn feature_stats(mean: f64, std: f64, min: f64, max: f64) -> FeatureStats {
+        FeatureStats{count: 10, mean, std, min, max}
+    }
+
+    /// Corpus statistics of the energy and the spectral centroid, which have a correlation of 0.5
+    fn correlated_stats() -> CorpusStats {
+        let mut stats = CorpusStats::default();
+        stats.features.insert(String::from("energy"), feature_stats(1.0, 2.0, -3.0, 5.0));
+        stats.features.insert(String::from("spectral_centroid"), feature_stats(1000.0, 500.0, 0.0, 2000.0));
+        stats.covariance.insert((String::from("energy"), String::from("energy")), 4.0);
+        stats.covariance.insert((String::from("spectral_centroid"), String::from("spectral_centroid")), 250000.0);
+        stats.covariance.insert((String::from("energy"), String::from("spectral_centroid")), 500.0);
+        stats
+    }
+
+    /// Gets the normalized feature vector of an energy and a spectral centroid
+    fn vector(similarity: &Similarity, energy: f64, spectral_centroid: f64) -> Vec<f64> {
+        similarity.normalize(|column| match column {
+            "energy" => Some(energy),
+            "spectral_centroid" => Some(spectral_centroid),
+            _ => None
+        })
+    }
+
+    #[test]
+    fn metrics_match_hand_computed_distances() {
+        // Normalized, the grains are (1, 1) and (0, -1), so the differences are 1 and 2
+        let stats = correlated_stats();
+        let weights = [("energy", 1.0), ("spectral_centroid", 4.0)];
+        let expected = [
+            (DistanceMetric::Euclidean, 17.0f64.sqrt()),
+            (DistanceMetric::Manhattan, 9.0),
+            // The dot product is -4 and the squared norms are 5 and 4
+            (DistanceMetric::Cosine, 1.0 + 4.0 / (5.0f64.sqrt() * 2.0)),
+            // The weighted differences are (1, 4), and the inverse covariance is [[1, -0.5], [-0.5, 1]] / 0.75
+            (DistanceMetric::Mahalanobis, (13.0f64 / 0.75).sqrt()),
+        ];
+        for (metric, distance) in expected {
+            let similarity = Similarity::new(&settings(metric, Normalization::ZScore, &weights), &stats);
+            let a = vector(&similarity, 3.0, 1500.0);
+            let b = vector(&similarity, 1.0, 500.0);
+            assert!((similarity.distance(&a, &b) - distance).abs() < 1e-4, "{:?}: {} instead of {}", metric, similarity.distance(&a, &b), distance);
+            assert!((similarity.convert_metric_distance(similarity.metric_distance(&a, &b)) - similarity.distance(&a, &b)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn min_max_normalization_scales_the_corpus_range() {
+        let similarity = Similarity::new(&settings(DistanceMetric::Euclidean, Normalization::MinMax, &[("energy", 1.0), ("spectral_centroid", 1.0)]), &correlated_stats());
+        let mut values = vector(&similarity, 5.0, 500.0);
+        values.sort_unstable_by(|a, b| a.total_cmp(b));
+        assert_eq!(values, vec![0.25, 1.0]);
+        let denormalized: BTreeMap<String, f64> = similarity.denormalize(&vector(&similarity, -1.0, 1500.0)).into_iter().collect();
+        assert_eq!(denormalized["energy"], -1.0);
+        assert_eq!(denormalized["spectral_centroid"], 1500.0);
+    }
+
+    #[test]
+    fn distances_are_symmetric() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for metric in [DistanceMetric::Euclidean, DistanceMetric::Cosine, DistanceMetric::Manhattan, DistanceMetric::Mahalanobis] {
+            let similarity = Similarity::new(&settings(metric, Normalization::ZScore, &[("energy", 0.5), ("spectral_centroid", 2.0)]), &correlated_stats());
+            for _ in 0..20 {
+                let a = vector(&similarity, rng.gen_range(-5.0..5.0), rng.gen_range(0.0..2000.0));
+                let b = vector(&similarity, rng.gen_range(-5.0..5.0), rng.gen_range(0.0..2000.0));
+                assert_eq!(similarity.distance(&a, &b), similarity.distance(&b, &a), "{:?}", metric);
+                assert!(similarity.distance(&a, &a).abs() < 1e-9, "{:?}", metric);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_and_zero_features_give_finite_distances() {
+        // Both features are the same in every grain, and one of them is 0
+        let mut stats = CorpusStats::default();
+        stats.features.insert(String::from("energy"), feature_stats(0.0, 0.0, 0.0, 0.0));
+        stats.features.insert(String::from("spectral_centroid"), feature_stats(440.0, 0.0, 440.0, 440.0));
+        stats.covariance.insert((String::from("energy"), String::from("energy")), 0.0);
+        stats.covariance.insert((String::from("spectral_centroid"), String::from("spectral_centroid")), 0.0);
+        for metric in [DistanceMetric::Euclidean, DistanceMetric::Cosine, DistanceMetric::Manhattan, DistanceMetric::Mahalanobis] {
+            for normalization in [Normalization::ZScore, Normalization::MinMax] {
+                let similarity = Similarity::new(&settings(metric, normalization, &[("energy", 1.0), ("spectral_centroid", 1.0)]), &stats);
+                let zero = vector(&similarity, 0.0, 0.0);
+                let corpus = vector(&similarity, 0.0, 440.0);
+                let missing = similarity.normalize(|_| None);
+                for (a, b) in [(&zero, &corpus), (&corpus, &corpus), (&missing, &zero), (&missing, &missing)] {
+                    assert!(a.iter().chain(b.iter()).all(|x| x.is_finite()), "{:?}, {:?}", metric, normalization);
+                    assert!(similarity.distance(a, b).is_finite(), "{:?}, {:?}", metric, normalization);
+                    assert!(similarity.metric_distance(a, b).is_finite(), "{:?}, {:?}", metric, normalization);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invert_finds_the_inverse() {
+        let inverse = invert(&[vec![4.0, 7.0], vec![2.0, 6.0]]).unwrap();
+        let expected = [[0.6, -0.7], [-0.2, 0.4]];
+        for i in 0..2 {
+            for j in 0..2 {
+                assert!((inverse[i][j] - expected[i][j]).abs() < 1e-12);
+            }
+        }
+        // The first pivot is 0, so the rows have to be swapped
+        let inverse = invert(&[vec![0.0, 1.0], vec![2.0, 0.0]]).unwrap();
+        assert_eq!(inverse, vec![vec![0.0, 0.5], vec![1.0, 0.0]]);
+    }
+
+    #[test]
+    fn invert_rejects_singular_matrices() {
+        assert!(invert(&[vec![1.0, 2.0], vec![2.0, 4.0]]).is_none());
+        assert!(invert(&[vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0], vec![5.0, 7.0, 9.0]]).is_none());
+        assert!(invert(&vec![vec![0.0; 3]; 3]).is_none());
+    }
+
+    #[test]
+    fn accumulator_computes_covariance_only_when_asked() {
+        let columns = ["energy", "midi", "spectral_centroid"];
+        let rows = [[1.0, 60.0, 100.0], [2.0, f64::NAN, 300.0], [3.0, 62.0, 200.0]];
+        let mut with = StatsAccumulator::new(&columns, true);
+        let mut without = StatsAccumulator::new(&columns, false);
+        for row in rows.iter() {
+            with.add(row);
+            without.add(row);
+        }
+        let (with, without) = (with.finish(), without.finish());
+        assert!(without.covariance.is_empty());
+        for stats in [&with, &without] {
+            assert_eq!(stats.features["energy"].mean, 2.0);
+            assert_eq!(stats.features["midi"].count, 2);
+            assert_eq!(stats.features["midi"].mean, 61.0);
+            assert_eq!(stats.features["spectral_centroid"].max, 300.0);
+        }
+        assert!((with.covariance("energy", "energy") - 2.0 / 3.0).abs() < 1e-12);
+        assert!((with.covariance("spectral_centroid", "energy") - 100.0 / 3.0).abs() < 1e-9);
+        // The pitch is only compared over the grains that have one
+        assert!((with.covariance("energy", "midi") - 1.0).abs() < 1e-12);
+    }
+}
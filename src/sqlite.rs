@@ -1,104 +1,1128 @@
 // File: sqlite.rs
 // This file has database operations.
 
-use rusqlite::{Connection, Result, params};
-use crate::grain_extractor::GrainEntry;
+use rusqlite::{Connection, OptionalExtension, Result, params, params_from_iter, ToSql, types::Value};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use crate::cluster::ClusterModel;
+use crate::embedding::Projection;
+use crate::grain_extractor::{GrainEntry, RejectedGrain};
+use crate::io::FileStat;
+use crate::similarity::{CorpusStats, DistanceMetric, FeatureStats, Similarity, SimilaritySettings, StatsAccumulator};
+use crate::stft;
+use crate::tags::TagExpr;
 
-/// Inserts a batch of grains into the SQLite database
-pub fn insert_grains(db: &str, grains: &Vec<GrainEntry>) -> Result<(), rusqlite::Error> {
-    let mut conn = match Connection::open(&db) {
-        Ok(x) => x,
-        Err(err) => return Err(err)
+/// The numeric columns of the grains table that can be used in grain queries
+pub const GRAIN_COLUMNS: [&str; 81] = [
+    "start_frame",
+    "end_frame",
+    "length",
+    "sample_rate",
+    "grain_duration",
+    "frequency",
+    "midi",
+    "energy",
+    "spectral_centroid",
+    "spectral_entropy",
+    "spectral_flatness",
+    "spectral_kurtosis",
+    "spectral_roll_off_50",
+    "spectral_roll_off_75",
+    "spectral_roll_off_90",
+    "spectral_roll_off_95",
+    "spectral_skewness",
+    "spectral_slope",
+    "spectral_slope_0_1_khz",
+    "spectral_slope_1_5_khz",
+    "spectral_slope_0_5_khz",
+    "spectral_variance",
+    "voicing_probability",
+    "hnr",
+    "inharmonicity",
+    "odd_even_ratio",
+    "tristimulus_1",
+    "tristimulus_2",
+    "tristimulus_3",
+    "rms",
+    "peak",
+    "crest_factor",
+    "zero_crossing_rate",
+    "attack_time",
+    "decay_slope",
+    "temporal_centroid",
+    "envelope_flatness",
+    "spectral_centroid_mean",
+    "spectral_centroid_std",
+    "spectral_centroid_delta",
+    "spectral_entropy_mean",
+    "spectral_entropy_std",
+    "spectral_entropy_delta",
+    "spectral_flatness_mean",
+    "spectral_flatness_std",
+    "spectral_flatness_delta",
+    "spectral_kurtosis_mean",
+    "spectral_kurtosis_std",
+    "spectral_kurtosis_delta",
+    "spectral_roll_off_50_mean",
+    "spectral_roll_off_50_std",
+    "spectral_roll_off_50_delta",
+    "spectral_roll_off_75_mean",
+    "spectral_roll_off_75_std",
+    "spectral_roll_off_75_delta",
+    "spectral_roll_off_90_mean",
+    "spectral_roll_off_90_std",
+    "spectral_roll_off_90_delta",
+    "spectral_roll_off_95_mean",
+    "spectral_roll_off_95_std",
+    "spectral_roll_off_95_delta",
+    "spectral_skewness_mean",
+    "spectral_skewness_std",
+    "spectral_skewness_delta",
+    "spectral_slope_mean",
+    "spectral_slope_std",
+    "spectral_slope_delta",
+    "spectral_slope_0_1_khz_mean",
+    "spectral_slope_0_1_khz_std",
+    "spectral_slope_0_1_khz_delta",
+    "spectral_slope_1_5_khz_mean",
+    "spectral_slope_1_5_khz_std",
+    "spectral_slope_1_5_khz_delta",
+    "spectral_slope_0_5_khz_mean",
+    "spectral_slope_0_5_khz_std",
+    "spectral_slope_0_5_khz_delta",
+    "spectral_variance_mean",
+    "spectral_variance_std",
+    "spectral_variance_delta",
+    "spectral_flux_mean",
+    "spectral_flux_max"
+];
+
+/// The tables with rows that belong to a grain, keyed by `grain_id`. They are deleted along with their grains.
+const GRAIN_CHILD_TABLES: [&str; 4] = ["mel_bands", "mfccs", "spectral_peaks", "tags"];
+
+/// The columns of `GRAIN_COLUMNS` that describe the sound of a grain, rather than its position in the source file
+pub fn feature_columns() -> &'static [&'static str] {
+    &GRAIN_COLUMNS[4..]
+}
+
+/// A range condition on a column of the grains table. Either bound may be omitted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ColumnRange {
+    pub column: String,
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+impl std::str::FromStr for ColumnRange {
+    type Err = String;
+
+    /// Parses a range written as `column=min:max`. Either bound may be left empty, as in `frequency=:440`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (column, bounds) = match s.split_once('=') {
+            Some(x) => x,
+            None => return Err(format!("Expected a range like column=min:max, but got {}", s))
+        };
+        let (min, max) = match bounds.split_once(':') {
+            Some(x) => x,
+            None => return Err(format!("Expected a range like column=min:max, but got {}", s))
+        };
+        let parse_bound = |bound: &str| -> std::result::Result<Option<f64>, String> {
+            if bound.trim().is_empty() {
+                return Ok(None);
+            }
+            match bound.trim().parse::<f64>() {
+                Ok(x) => Ok(Some(x)),
+                Err(_) => Err(format!("Invalid range bound {} in {}", bound, s))
+            }
+        };
+        Ok(ColumnRange{column: String::from(column.trim()), min: parse_bound(min)?, max: parse_bound(max)?})
+    }
+}
+
+/// A query that selects grains. All conditions must match.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GrainQuery {
+    /// A SQLite GLOB pattern that the grain's file path must match
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub ranges: Vec<ColumnRange>,
+    /// A combination of tags that the grain must have, such as `drums AND NOT noisy`
+    #[serde(default)]
+    pub tags: Option<TagExpr>,
+    /// The cluster that the grain must be in
+    #[serde(default)]
+    pub cluster: Option<i64>,
+}
+
+/// The location of a grain in its source file
+#[derive(Debug, Clone)]
+pub struct GrainLocation {
+    pub file: String,
+    pub start_frame: usize,
+    pub end_frame: usize,
+}
+
+/// A row in the `files` table, recording a source file that was processed with a grain profile
+#[derive(Debug, Clone)]
+pub struct FileRecord {
+    pub id: i64,
+    pub size: u64,
+    pub mtime: i64,
+    pub hash: String,
+    /// The analysis settings the file was processed with, from `GranulatorConfig::analysis_key`
+    pub analysis: String,
+    /// Whether all chunks of the file were processed and inserted
+    pub complete: bool,
+}
+
+// The statement that inserts a grain. It is prepared once per writer connection and cached.
+const INSERT_GRAIN_SQL: &str = "INSERT INTO grains (
+        file_id,
+        file,
+        start_frame,
+        end_frame,
+        length,
+        sample_rate,
+        grain_duration,
+        frequency,
+        midi,
+        energy,
+        spectral_centroid,
+        spectral_entropy,
+        spectral_flatness,
+        spectral_kurtosis,
+        spectral_roll_off_50,
+        spectral_roll_off_75,
+        spectral_roll_off_90,
+        spectral_roll_off_95,
+        spectral_skewness,
+        spectral_slope,
+        spectral_slope_0_1_khz,
+        spectral_slope_1_5_khz,
+        spectral_slope_0_5_khz,
+        spectral_variance,
+        cluster_id,
+        cluster_distance,
+        embedding_x,
+        embedding_y,
+        embedding_z,
+        voicing_probability,
+        hnr,
+        inharmonicity,
+        odd_even_ratio,
+        tristimulus_1,
+        tristimulus_2,
+        tristimulus_3,
+        rms,
+        peak,
+        crest_factor,
+        zero_crossing_rate,
+        attack_time,
+        decay_slope,
+        temporal_centroid,
+        envelope_flatness,
+        spectral_centroid_mean,
+        spectral_centroid_std,
+        spectral_centroid_delta,
+        spectral_entropy_mean,
+        spectral_entropy_std,
+        spectral_entropy_delta,
+        spectral_flatness_mean,
+        spectral_flatness_std,
+        spectral_flatness_delta,
+        spectral_kurtosis_mean,
+        spectral_kurtosis_std,
+        spectral_kurtosis_delta,
+        spectral_roll_off_50_mean,
+        spectral_roll_off_50_std,
+        spectral_roll_off_50_delta,
+        spectral_roll_off_75_mean,
+        spectral_roll_off_75_std,
+        spectral_roll_off_75_delta,
+        spectral_roll_off_90_mean,
+        spectral_roll_off_90_std,
+        spectral_roll_off_90_delta,
+        spectral_roll_off_95_mean,
+        spectral_roll_off_95_std,
+        spectral_roll_off_95_delta,
+        spectral_skewness_mean,
+        spectral_skewness_std,
+        spectral_skewness_delta,
+        spectral_slope_mean,
+        spectral_slope_std,
+        spectral_slope_delta,
+        spectral_slope_0_1_khz_mean,
+        spectral_slope_0_1_khz_std,
+        spectral_slope_0_1_khz_delta,
+        spectral_slope_1_5_khz_mean,
+        spectral_slope_1_5_khz_std,
+        spectral_slope_1_5_khz_delta,
+        spectral_slope_0_5_khz_mean,
+        spectral_slope_0_5_khz_std,
+        spectral_slope_0_5_khz_delta,
+        spectral_variance_mean,
+        spectral_variance_std,
+        spectral_variance_delta,
+        spectral_flux_mean,
+        spectral_flux_max
+    )
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30,
+        ?31, ?32, ?33, ?34, ?35, ?36, ?37, ?38, ?39, ?40, ?41, ?42, ?43, ?44, ?45, ?46, ?47, ?48, ?49, ?50, ?51, ?52, ?53, ?54, ?55, ?56, ?57, ?58, ?59, ?60,
+        ?61, ?62, ?63, ?64, ?65, ?66, ?67, ?68, ?69, ?70, ?71, ?72, ?73, ?74, ?75, ?76, ?77, ?78, ?79, ?80, ?81, ?82, ?83, ?84, ?85, ?86, ?87, ?88)";
+
+/// A persistent connection for writing extracted grains. The database is switched to WAL mode, so that
+/// other connections can keep reading while grains are written.
+pub struct GrainWriter {
+    conn: Connection,
+    /// The corpus clusters, if the corpus has been clustered. New grains are assigned to the nearest cluster.
+    clusters: Option<ClusterModel>,
+    /// The corpus embedding, if one has been computed. New grains are projected with it.
+    projection: Option<Projection>,
+}
+
+impl GrainWriter {
+    /// Opens a writer connection to the database
+    pub fn open(db: &str) -> Result<GrainWriter, rusqlite::Error> {
+        let conn = Connection::open(db)?;
+        let _: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        conn.execute_batch("PRAGMA synchronous = NORMAL;")?;
+        let clusters = load_cluster_model(&conn)?;
+        let projection = load_projection(&conn)?;
+        Ok(GrainWriter{conn, clusters, projection})
+    }
+
+    /// Inserts a batch of grains into the database with their tags, clusters and embedding coordinates, along with
+    /// the grains that were screened out during analysis. The grains are linked to the `files` row `file_id`.
+    pub fn insert_grains(&mut self, grains: &[GrainEntry], rejected: &[RejectedGrain], file_id: i64) -> Result<(), rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert_grain = tx.prepare_cached(INSERT_GRAIN_SQL)?;
+            let mut insert_mel_band = tx.prepare_cached("INSERT INTO mel_bands (grain_id, band, value) VALUES (?1, ?2, ?3)")?;
+            let mut insert_mfcc = tx.prepare_cached("INSERT INTO mfccs (grain_id, coefficient, value) VALUES (?1, ?2, ?3)")?;
+            let mut insert_spectral_peak = tx.prepare_cached("INSERT INTO spectral_peaks (grain_id, peak, frequency, amplitude) VALUES (?1, ?2, ?3, ?4)")?;
+            let mut insert_rejected = tx.prepare_cached("INSERT INTO rejected_grains (file_id, file, start_frame, end_frame, reason) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+            let mut insert_tag = tx.prepare_cached("INSERT OR IGNORE INTO tags (grain_id, tag) VALUES (?1, ?2)")?;
+            for grain in grains.iter() {
+                let cluster = self.clusters.as_ref().and_then(|model| model.assign(grain));
+                let coordinates = self.projection.as_ref().map(|projection| projection.project(grain));
+                let coordinate = |i: usize| coordinates.as_ref().and_then(|x| x.get(i).copied());
+                let length = grain.end_frame - grain.start_frame;
+                let (cluster_id, cluster_distance) = (cluster.map(|(id, _)| id), cluster.map(|(_, distance)| distance));
+                let (embedding_x, embedding_y, embedding_z) = (coordinate(0), coordinate(1), coordinate(2));
+                let frame_statistics = grain.frame_statistics.values();
+                let mut values: Vec<&dyn ToSql> = params![
+                    file_id,
+                    grain.file,
+                    grain.start_frame,
+                    grain.end_frame,
+                    length,
+                    grain.sample_rate,
+                    grain.grain_duration,
+                    grain.pitch_estimation,
+                    grain.midi,
+                    grain.energy,
+                    grain.spectral_centroid,
+                    grain.spectral_entropy,
+                    grain.spectral_flatness,
+                    grain.spectral_kurtosis,
+                    grain.spectral_roll_off_50,
+                    grain.spectral_roll_off_75,
+                    grain.spectral_roll_off_90,
+                    grain.spectral_roll_off_95,
+                    grain.spectral_skewness,
+                    grain.spectral_slope,
+                    grain.spectral_slope_0_1_khz,
+                    grain.spectral_slope_1_5_khz,
+                    grain.spectral_slope_0_5_khz,
+                    grain.spectral_variance,
+                    cluster_id,
+                    cluster_distance,
+                    embedding_x,
+                    embedding_y,
+                    embedding_z,
+                    grain.voicing_probability,
+                    grain.hnr,
+                    grain.inharmonicity,
+                    grain.odd_even_ratio,
+                    grain.tristimulus_1,
+                    grain.tristimulus_2,
+                    grain.tristimulus_3,
+                    grain.rms,
+                    grain.peak,
+                    grain.crest_factor,
+                    grain.zero_crossing_rate,
+                    grain.attack_time,
+                    grain.decay_slope,
+                    grain.temporal_centroid,
+                    grain.envelope_flatness
+                ].to_vec();
+                // The frame statistics are the last columns, in the same order as in INSERT_GRAIN_SQL
+                values.extend(frame_statistics.iter().map(|x| x as &dyn ToSql));
+                let grain_id = insert_grain.insert(values.as_slice())?;
+                for (band, value) in grain.mel_bands.iter().enumerate() {
+                    insert_mel_band.execute(params![grain_id, band, value])?;
+                }
+                for (coefficient, value) in grain.mfccs.iter().enumerate() {
+                    insert_mfcc.execute(params![grain_id, coefficient, value])?;
+                }
+                for (peak, (frequency, amplitude)) in grain.spectral_peaks.iter().enumerate() {
+                    insert_spectral_peak.execute(params![grain_id, peak, frequency, amplitude])?;
+                }
+                for tag in grain.tags.iter() {
+                    insert_tag.execute(params![grain_id, tag])?;
+                }
+            }
+            for grain in rejected.iter() {
+                insert_rejected.execute(params![file_id, &grain.file, grain.start_frame, grain.end_frame, grain.reason.as_str()])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Registers a source file for processing with a grain profile. Any grains previously extracted from
+    /// the file with this profile are deleted, since they are about to be replaced. The file is marked
+    /// incomplete until `complete_file` is called.
+    /// 
+    /// Grains inserted before the `files` table existed have no file record, and their positions are relative
+    /// to the audio chunk they were extracted from rather than the file. When a file is registered for the first
+    /// time, any such legacy grains from the file are deleted too, so that rerunning the grain processor on an old
+    /// database replaces them with grains at the correct positions. Their tags are kept in the `legacy_tags` table
+    /// until `complete_file` carries them over to the new grains.
+    /// Returns the id of the file record.
+    pub fn begin_file(&mut self, path: &str, profile: &str, stat: &FileStat, hash: &str, analysis: &str) -> Result<i64, rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        let existing: Option<i64> = tx.query_row(
+            "SELECT id FROM files WHERE path = ?1 AND profile = ?2",
+            params![path, profile],
+            |row| row.get(0)
+        ).optional()?;
+
+        let file_id = match existing {
+            Some(id) => {
+                delete_file_grains(&tx, id)?;
+                tx.execute(
+                    "UPDATE files SET size = ?1, mtime = ?2, hash = ?3, analysis = ?4, complete = 0 WHERE id = ?5",
+                    params![stat.size, stat.mtime, hash, analysis, id]
+                )?;
+                id
+            },
+            None => {
+                delete_legacy_grains(&tx, path)?;
+                tx.execute(
+                    "INSERT INTO files (path, profile, size, mtime, hash, analysis, complete) VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0)",
+                    params![path, profile, stat.size, stat.mtime, hash, analysis]
+                )?;
+                tx.last_insert_rowid()
+            }
+        };
+        tx.commit()?;
+        Ok(file_id)
+    }
+
+    /// Counts the grains from a file that have no file record, which `begin_file` will replace
+    pub fn count_legacy_grains(&self, path: &str) -> Result<usize, rusqlite::Error> {
+        self.conn.query_row("SELECT COUNT(*) FROM grains WHERE file_id IS NULL AND file = ?1", params![path], |row| row.get(0))
+    }
+
+    /// Marks a source file as completely processed. The tags of any legacy grains that were replaced are added
+    /// to the new grains that overlap them, and once the file is complete for every grain profile, the legacy
+    /// tags are dropped.
+    pub fn complete_file(&mut self, file_id: i64) -> Result<(), rusqlite::Error> {
+        let tx = self.conn.transaction()?;
+        tx.execute("UPDATE files SET complete = 1 WHERE id = ?1", params![file_id])?;
+        tx.execute(
+            "INSERT OR IGNORE INTO tags (grain_id, tag)
+            SELECT grains.id, legacy_tags.tag FROM grains
+            JOIN legacy_tags ON legacy_tags.file = grains.file
+                AND grains.start_frame < legacy_tags.end_frame AND legacy_tags.start_frame < grains.end_frame
+            WHERE grains.file_id = ?1",
+            params![file_id]
+        )?;
+        tx.execute(
+            "DELETE FROM legacy_tags WHERE file = (SELECT path FROM files WHERE id = ?1)
+            AND NOT EXISTS (SELECT 1 FROM files WHERE path = legacy_tags.file AND complete = 0)",
+            params![file_id]
+        )?;
+        tx.commit()
+    }
+}
+
+/// Builds the WHERE clause and its parameters for a grain query.
+/// Column names are checked against `GRAIN_COLUMNS`, since they can't be passed as parameters.
+fn build_where_clause(query: &GrainQuery) -> Result<(String, Vec<Value>), rusqlite::Error> {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut values: Vec<Value> = Vec::new();
+    if let Some(pattern) = &query.file {
+        values.push(Value::Text(pattern.clone()));
+        conditions.push(format!("file GLOB ?{}", values.len()));
+    }
+    for range in query.ranges.iter() {
+        if !GRAIN_COLUMNS.contains(&range.column.as_str()) {
+            return Err(rusqlite::Error::InvalidColumnName(range.column.clone()));
+        }
+        if let Some(min) = range.min {
+            values.push(Value::Real(min));
+            conditions.push(format!("{} >= ?{}", range.column, values.len()));
+        }
+        if let Some(max) = range.max {
+            values.push(Value::Real(max));
+            conditions.push(format!("{} <= ?{}", range.column, values.len()));
+        }
+    }
+    if let Some(cluster) = query.cluster {
+        values.push(Value::Integer(cluster));
+        conditions.push(format!("cluster_id = ?{}", values.len()));
+    }
+    if let Some(tags) = &query.tags {
+        conditions.push(tags.to_sql(&mut values));
+    }
+    if conditions.is_empty() {
+        Ok((String::from("1"), values))
+    } else {
+        Ok((conditions.join(" AND "), values))
+    }
+}
+
+/// Finds the grains that match a query
+pub fn select_grains(db: &str, query: &GrainQuery) -> Result<Vec<GrainLocation>, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, values) = build_where_clause(query)?;
+    let mut stmt = conn.prepare(&format!("SELECT file, start_frame, end_frame FROM grains WHERE {}", where_clause))?;
+    let grains = stmt.query_map(params_from_iter(values.iter()), |row| {
+        Ok(GrainLocation{file: row.get(0)?, start_frame: row.get(1)?, end_frame: row.get(2)?})
+    })?.collect::<Result<Vec<GrainLocation>, rusqlite::Error>>()?;
+    Ok(grains)
+}
+
+/// The columns that `grain_entry_from_row` reads, after the grain id
+fn grain_entry_columns() -> String {
+    format!("file, start_frame, end_frame, sample_rate, grain_duration, energy, frequency, midi, {}", GRAIN_COLUMNS[8..].join(", "))
+}
+
+/// Reads a grain from a row with the id followed by the columns of `grain_entry_columns`.
+/// Features that are NULL in the database are loaded as NaN.
+fn grain_entry_from_row(row: &rusqlite::Row) -> Result<(i64, GrainEntry), rusqlite::Error> {
+    let real = |idx: usize| -> Result<f64, rusqlite::Error> {
+        Ok(row.get::<usize, Option<f64>>(idx)?.unwrap_or(f64::NAN))
     };
+    Ok((row.get(0)?, GrainEntry{
+        file: row.get(1)?,
+        start_frame: row.get(2)?,
+        end_frame: row.get(3)?,
+        sample_rate: row.get(4)?,
+        grain_duration: real(5)?,
+        energy: real(6)?,
+        pitch_estimation: real(7)?,
+        midi: real(8)?,
+        spectral_centroid: real(9)?,
+        spectral_entropy: real(10)?,
+        spectral_flatness: real(11)?,
+        spectral_kurtosis: real(12)?,
+        spectral_roll_off_50: real(13)?,
+        spectral_roll_off_75: real(14)?,
+        spectral_roll_off_90: real(15)?,
+        spectral_roll_off_95: real(16)?,
+        spectral_skewness: real(17)?,
+        spectral_slope: real(18)?,
+        spectral_slope_0_1_khz: real(19)?,
+        spectral_slope_1_5_khz: real(20)?,
+        spectral_slope_0_5_khz: real(21)?,
+        spectral_variance: real(22)?,
+        voicing_probability: real(23)?,
+        hnr: real(24)?,
+        inharmonicity: real(25)?,
+        odd_even_ratio: real(26)?,
+        tristimulus_1: real(27)?,
+        tristimulus_2: real(28)?,
+        tristimulus_3: real(29)?,
+        rms: real(30)?,
+        peak: real(31)?,
+        crest_factor: real(32)?,
+        zero_crossing_rate: real(33)?,
+        attack_time: real(34)?,
+        decay_slope: real(35)?,
+        temporal_centroid: real(36)?,
+        envelope_flatness: real(37)?,
+        frame_statistics: stft::FrameStatistics::from_values(&(38..82).map(real).collect::<Result<Vec<f64>, rusqlite::Error>>()?),
+        mel_bands: Vec::new(),
+        mfccs: Vec::new(),
+        spectral_peaks: Vec::new(),
+        tags: Vec::new()
+    }))
+}
 
-    let tx = match conn.transaction() {
-        Ok(x) => x,
-        Err(err) => return Err(err)
+/// Loads the grains that match a query, with their ids and analysis features.
+/// The mel bands and MFCCs are not loaded. Features that are NULL in the database are loaded as NaN.
+pub fn select_grain_entries_with_ids(db: &str, query: &GrainQuery) -> Result<Vec<(i64, GrainEntry)>, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, values) = build_where_clause(query)?;
+    let mut stmt = conn.prepare(&format!("SELECT id, {} FROM grains WHERE {}", grain_entry_columns(), where_clause))?;
+    let grains = stmt.query_map(params_from_iter(values.iter()), grain_entry_from_row)?
+        .collect::<Result<Vec<(i64, GrainEntry)>, rusqlite::Error>>()?;
+    Ok(grains)
+}
+
+/// Loads grains by id, with their analysis features. Ids that are not in the database are skipped.
+pub fn select_grain_entries_by_id(db: &str, ids: &[i64]) -> Result<Vec<(i64, GrainEntry)>, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let mut stmt = conn.prepare(&format!("SELECT id, {} FROM grains WHERE id = ?1", grain_entry_columns()))?;
+    let mut grains: Vec<(i64, GrainEntry)> = Vec::with_capacity(ids.len());
+    for id in ids.iter() {
+        if let Some(grain) = stmt.query_row(params![id], grain_entry_from_row).optional()? {
+            grains.push(grain);
+        }
+    }
+    Ok(grains)
+}
+
+/// Gets the ids of the grains that match a query
+pub fn select_grain_ids(db: &str, query: &GrainQuery) -> Result<Vec<i64>, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, values) = build_where_clause(query)?;
+    let mut stmt = conn.prepare(&format!("SELECT id FROM grains WHERE {}", where_clause))?;
+    let ids = stmt.query_map(params_from_iter(values.iter()), |row| row.get(0))?
+        .collect::<Result<Vec<i64>, rusqlite::Error>>()?;
+    Ok(ids)
+}
+
+/// Returns the column names and the rows.
+pub fn select_grain_rows(db: &str, query: &GrainQuery, limit: Option<usize>) -> Result<(Vec<String>, Vec<Vec<Value>>), rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, values) = build_where_clause(query)?;
+    let limit_clause = match limit {
+        Some(x) => format!(" LIMIT {}", x),
+        None => String::new()
     };
+    let mut stmt = conn.prepare(&format!("SELECT * FROM grains WHERE {} ORDER BY id{}", where_clause, limit_clause))?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let num_columns = columns.len();
+    let rows = stmt.query_map(params_from_iter(values.iter()), |row| {
+        (0..num_columns).map(|i| row.get::<usize, Value>(i)).collect::<Result<Vec<Value>, rusqlite::Error>>()
+    })?.collect::<Result<Vec<Vec<Value>>, rusqlite::Error>>()?;
+    Ok((columns, rows))
+}
 
-    for i in 0..grains.len() {
-        // we only add grains with an energy above 0.0. this is because we might have taken a grain of silence.
-        if grains[i].energy > 0.0 {
-            match tx.execute(
-                "INSERT INTO grains (
-                    file,
-                    start_frame,
-                    end_frame,
-                    length,
-                    sample_rate,
-                    grain_duration,
-                    frequency,
-                    midi,
-                    energy,
-                    spectral_centroid,
-                    spectral_entropy,
-                    spectral_flatness,
-                    spectral_kurtosis,
-                    spectral_roll_off_50,
-                    spectral_roll_off_75,
-                    spectral_roll_off_90,
-                    spectral_roll_off_95,
-                    spectral_skewness,
-                    spectral_slope,
-                    spectral_slope_0_1_khz,
-                    spectral_slope_1_5_khz,
-                    spectral_slope_0_5_khz,
-                    spectral_variance
-                ) 
-                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)", 
-                params![
-                    &grains[i].file.clone(),
-                    &grains[i].start_frame,
-                    &grains[i].end_frame,
-                    grains[i].end_frame - grains[i].start_frame,
-                    &grains[i].sample_rate,
-                    &grains[i].grain_duration,
-                    &grains[i].pitch_estimation,
-                    &grains[i].midi,
-                    &grains[i].energy,
-                    &grains[i].spectral_centroid,
-                    &grains[i].spectral_entropy,
-                    &grains[i].spectral_flatness,
-                    &grains[i].spectral_kurtosis,
-                    &grains[i].spectral_roll_off_50,
-                    &grains[i].spectral_roll_off_75,
-                    &grains[i].spectral_roll_off_90,
-                    &grains[i].spectral_roll_off_95,
-                    &grains[i].spectral_skewness,
-                    &grains[i].spectral_slope,
-                    &grains[i].spectral_slope_0_1_khz,
-                    &grains[i].spectral_slope_1_5_khz,
-                    &grains[i].spectral_slope_0_5_khz,
-                    &grains[i].spectral_variance
-                ],) {
-                Ok(_) => (),
-                Err(err) => return Err(err)
+/// Gets the column names, and the values of the grains with the given ids, in the same order as the ids.
+/// Ids that are not in the database are skipped.
+pub fn select_grain_rows_by_id(db: &str, ids: &[i64]) -> Result<(Vec<String>, Vec<Vec<Value>>), rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let mut stmt = conn.prepare("SELECT * FROM grains WHERE id = ?1")?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let num_columns = columns.len();
+    let mut rows: Vec<Vec<Value>> = Vec::with_capacity(ids.len());
+    for id in ids.iter() {
+        let row = stmt.query_row(params![id], |row| {
+            (0..num_columns).map(|i| row.get::<usize, Value>(i)).collect::<Result<Vec<Value>, rusqlite::Error>>()
+        }).optional()?;
+        rows.extend(row);
+    }
+    Ok((columns, rows))
+}
+
+/// Counts the grains that match a query
+pub fn count_grains(db: &str, query: &GrainQuery) -> Result<usize, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, values) = build_where_clause(query)?;
+    conn.query_row(&format!("SELECT COUNT(*) FROM grains WHERE {}", where_clause), params_from_iter(values.iter()), |row| row.get(0))
+}
+
+/// Adds a tag to the grains that match a query. Grains that already have the tag are left alone.
+/// Returns the number of grains that were tagged.
+pub fn add_tag(db: &str, query: &GrainQuery, tag: &str) -> Result<usize, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, mut values) = build_where_clause(query)?;
+    values.push(Value::Text(String::from(tag)));
+    conn.execute(
+        &format!("INSERT OR IGNORE INTO tags (grain_id, tag) SELECT id, ?{} FROM grains WHERE {}", values.len(), where_clause),
+        params_from_iter(values.iter())
+    )
+}
+
+/// Removes a tag from the grains that match a query.
+/// Returns the number of grains that were untagged.
+pub fn remove_tag(db: &str, query: &GrainQuery, tag: &str) -> Result<usize, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, mut values) = build_where_clause(query)?;
+    values.push(Value::Text(String::from(tag)));
+    conn.execute(
+        &format!("DELETE FROM tags WHERE tag = ?{} AND grain_id IN (SELECT id FROM grains WHERE {})", values.len(), where_clause),
+        params_from_iter(values.iter())
+    )
+}
+
+/// Adds a tag to grains by id. Grains that already have the tag are left alone.
+/// Returns the number of grains that were tagged.
+pub fn add_tag_by_id(db: &str, ids: &[i64], tag: &str) -> Result<usize, rusqlite::Error> {
+    let mut conn = Connection::open(db)?;
+    let tx = conn.transaction()?;
+    let mut num_tagged = 0;
+    {
+        let mut insert_tag = tx.prepare("INSERT OR IGNORE INTO tags (grain_id, tag) SELECT id, ?2 FROM grains WHERE id = ?1")?;
+        for id in ids.iter() {
+            num_tagged += insert_tag.execute(params![id, tag])?;
+        }
+    }
+    tx.commit()?;
+    Ok(num_tagged)
+}
+
+/// Lists the tags of the grains that match a query, with the number of matching grains that have each tag
+pub fn list_tags(db: &str, query: &GrainQuery) -> Result<Vec<(String, usize)>, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let (where_clause, values) = build_where_clause(query)?;
+    let mut stmt = conn.prepare(&format!("SELECT tag, COUNT(*) FROM tags WHERE grain_id IN (SELECT id FROM grains WHERE {}) GROUP BY tag ORDER BY tag", where_clause))?;
+    let tags = stmt.query_map(params_from_iter(values.iter()), |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<Vec<(String, usize)>, rusqlite::Error>>()?;
+    Ok(tags)
+}
+
+/// A summary of the contents of the database
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseStats {
+    pub num_grains: usize,
+    pub num_source_files: usize,
+    /// The number of file records that were not completely processed
+    pub num_incomplete_files: usize,
+    /// The number of grains for each grain profile key. Legacy grains have no profile.
+    pub grains_per_profile: Vec<(Option<String>, usize)>,
+    /// The number of rejected grains for each rejection reason
+    pub rejected_per_reason: Vec<(String, usize)>,
+    /// The number of grains with each tag
+    pub grains_per_tag: Vec<(String, usize)>,
+}
+
+/// Summarizes the contents of the database
+pub fn get_stats(db: &str) -> Result<DatabaseStats, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let count = |sql: &str| -> Result<usize, rusqlite::Error> {
+        conn.query_row(sql, [], |row| row.get(0))
+    };
+    let mut stats = DatabaseStats{
+        num_grains: count("SELECT COUNT(*) FROM grains")?,
+        num_source_files: count("SELECT COUNT(DISTINCT file) FROM grains")?,
+        num_incomplete_files: count("SELECT COUNT(*) FROM files WHERE complete = 0")?,
+        ..Default::default()
+    };
+
+    let mut stmt = conn.prepare("SELECT files.profile, COUNT(*) FROM grains LEFT JOIN files ON grains.file_id = files.id GROUP BY files.profile ORDER BY files.profile")?;
+    stats.grains_per_profile = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+    let mut stmt = conn.prepare("SELECT reason, COUNT(*) FROM rejected_grains GROUP BY reason ORDER BY reason")?;
+    stats.rejected_per_reason = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+    let mut stmt = conn.prepare("SELECT tag, COUNT(*) FROM tags GROUP BY tag ORDER BY tag")?;
+    stats.grains_per_tag = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+    Ok(stats)
+}
+
+/// Computes corpus statistics of the grain features from all grains in the database. The covariances
+/// are only computed if `with_covariance` is set.
+fn compute_feature_stats(conn: &Connection, columns: &[&str], with_covariance: bool) -> Result<CorpusStats, rusqlite::Error> {
+    let mut accumulator = StatsAccumulator::new(columns, with_covariance);
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM grains", columns.join(", ")))?;
+    let mut rows = stmt.query([])?;
+    let mut values: Vec<f64> = vec![0.0; columns.len()];
+    while let Some(row) = rows.next()? {
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = row.get::<usize, Option<f64>>(i)?.unwrap_or(f64::NAN);
+        }
+        accumulator.add(&values);
+    }
+    Ok(accumulator.finish())
+}
+
+/// Recomputes the corpus statistics of each grain feature from all grains in the database.
+/// The covariances are cleared, and are computed by `load_feature_stats` when a distance metric needs them.
+fn update_feature_stats(conn: &mut Connection) -> Result<CorpusStats, rusqlite::Error> {
+    let stats = compute_feature_stats(conn, feature_columns(), false)?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM feature_stats", [])?;
+    tx.execute("DELETE FROM feature_covariance", [])?;
+    {
+        let mut insert_stats = tx.prepare("INSERT INTO feature_stats (feature, count, mean, std, min, max) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        for (feature, x) in stats.features.iter() {
+            insert_stats.execute(params![feature, x.count, x.mean, x.std, x.min, x.max])?;
+        }
+    }
+    tx.commit()?;
+    Ok(stats)
+}
+
+/// Clears the corpus statistics of the grain features after the grains have changed.
+/// They are recomputed the next time they are loaded.
+pub fn clear_feature_stats(db: &str) -> Result<(), rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    conn.execute_batch("
+        DELETE FROM feature_stats;
+        DELETE FROM feature_covariance;
+    ")
+}
+
+/// Loads the corpus statistics of the grain features that are needed to compare grains with similarity settings.
+/// If they haven't been computed since the grains last changed, they are computed now. The covariances are only
+/// loaded for the Mahalanobis distance, and only for the features it compares.
+pub fn load_feature_stats(db: &str, settings: &SimilaritySettings) -> Result<CorpusStats, rusqlite::Error> {
+    let mut conn = Connection::open(db)?;
+    let mut stats = CorpusStats::default();
+    {
+        let mut stmt = conn.prepare("SELECT feature, count, mean, std, min, max FROM feature_stats")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<usize, String>(0)?, FeatureStats{count: row.get(1)?, mean: row.get(2)?, std: row.get(3)?, min: row.get(4)?, max: row.get(5)?}))
+        })?;
+        for row in rows {
+            let (feature, x) = row?;
+            stats.features.insert(feature, x);
+        }
+    }
+    if stats.features.is_empty() {
+        stats = update_feature_stats(&mut conn)?;
+    }
+    if settings.metric != DistanceMetric::Mahalanobis {
+        return Ok(stats);
+    }
+
+    // Only the features that some grain has have covariances
+    let columns: Vec<String> = settings.features().into_iter()
+        .map(|(column, _)| column)
+        .filter(|column| stats.features.contains_key(column))
+        .collect();
+    {
+        let mut stmt = conn.prepare("SELECT feature_a, feature_b, covariance FROM feature_covariance")?;
+        let rows = stmt.query_map([], |row| Ok(((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?), row.get::<usize, f64>(2)?)))?;
+        for row in rows {
+            let ((a, b), covariance) = row?;
+            if columns.contains(&a) && columns.contains(&b) {
+                stats.covariance.insert((a, b), covariance);
             }
         }
     }
+    // Every feature has a variance once its covariances have been computed
+    if columns.iter().all(|column| stats.covariance.contains_key(&(column.clone(), column.clone()))) {
+        return Ok(stats);
+    }
+    let column_names: Vec<&str> = columns.iter().map(|x| x.as_str()).collect();
+    stats.covariance = compute_feature_stats(&conn, &column_names, true)?.covariance;
+    let tx = conn.transaction()?;
+    {
+        let mut insert_covariance = tx.prepare("INSERT OR REPLACE INTO feature_covariance (feature_a, feature_b, covariance) VALUES (?1, ?2, ?3)")?;
+        for ((a, b), covariance) in stats.covariance.iter() {
+            insert_covariance.execute(params![a, b, covariance])?;
+        }
+    }
+    tx.commit()?;
+    Ok(stats)
+}
 
-    match tx.commit() {
-        Ok(_) => (),
-        Err(err) => return Err(err)
+/// Loads the corpus clusters. Returns `None` if the corpus hasn't been clustered.
+fn load_cluster_model(conn: &Connection) -> Result<Option<ClusterModel>, rusqlite::Error> {
+    let similarity: Option<String> = conn.query_row("SELECT similarity FROM cluster_model WHERE id = 1", [], |row| row.get(0)).optional()?;
+    let similarity: Similarity = match similarity {
+        Some(x) => serde_json::from_str(&x).map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err)))?,
+        None => return Ok(None)
     };
+    let mut stmt = conn.prepare("SELECT centroid FROM clusters ORDER BY id")?;
+    let centroids = stmt.query_map([], |row| {
+        let centroid: String = row.get(0)?;
+        let values: BTreeMap<String, f64> = serde_json::from_str(&centroid).map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err)))?;
+        Ok(similarity.normalize(|column| values.get(column).copied()))
+    })?.collect::<Result<Vec<Vec<f64>>, rusqlite::Error>>()?;
+    if centroids.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(ClusterModel{similarity, centroids}))
+}
+
+/// Loads the corpus embedding projection. Returns `None` if no embedding has been computed.
+fn load_projection(conn: &Connection) -> Result<Option<Projection>, rusqlite::Error> {
+    let projection: Option<String> = conn.query_row("SELECT projection FROM embedding WHERE id = 1", [], |row| row.get(0)).optional()?;
+    match projection {
+        Some(x) => serde_json::from_str(&x).map(Some).map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))),
+        None => Ok(None)
+    }
+}
+
+/// Replaces the corpus embedding projection, and sets the coordinates of each grain. `coordinates` has the grain id
+/// and its coordinates for each grain. Grains with only two coordinates get a NULL `embedding_z`.
+pub fn save_embedding(db: &str, projection: &Projection, coordinates: &[(i64, Vec<f64>)]) -> Result<(), rusqlite::Error> {
+    let mut conn = Connection::open(db)?;
+    let projection = serde_json::to_string(projection).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+    let tx = conn.transaction()?;
+    tx.execute("INSERT OR REPLACE INTO embedding (id, projection) VALUES (1, ?1)", params![projection])?;
+    tx.execute("UPDATE grains SET embedding_x = NULL, embedding_y = NULL, embedding_z = NULL", [])?;
+    {
+        let mut update_grain = tx.prepare("UPDATE grains SET embedding_x = ?1, embedding_y = ?2, embedding_z = ?3 WHERE id = ?4")?;
+        for (grain_id, point) in coordinates.iter() {
+            update_grain.execute(params![point.first(), point.get(1), point.get(2), grain_id])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Replaces the corpus clusters, and sets the cluster of each grain. `assignments` has the grain id, cluster id,
+/// and distance to the cluster centroid for each grain. The centroids are stored as JSON objects with the feature
+/// values of the centroid.
+pub fn save_clusters(db: &str, model: &ClusterModel, assignments: &[(i64, usize, f64)]) -> Result<(), rusqlite::Error> {
+    let mut conn = Connection::open(db)?;
+    let to_json = |err: serde_json::Error| rusqlite::Error::ToSqlConversionFailure(Box::new(err));
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM clusters", [])?;
+    tx.execute("INSERT OR REPLACE INTO cluster_model (id, similarity) VALUES (1, ?1)", params![serde_json::to_string(&model.similarity).map_err(to_json)?])?;
+    tx.execute("UPDATE grains SET cluster_id = NULL, cluster_distance = NULL", [])?;
+    {
+        let mut insert_cluster = tx.prepare("INSERT INTO clusters (id, centroid) VALUES (?1, ?2)")?;
+        for (id, centroid) in model.centroids.iter().enumerate() {
+            let values: BTreeMap<String, f64> = model.similarity.denormalize(centroid).into_iter().collect();
+            insert_cluster.execute(params![id, serde_json::to_string(&values).map_err(to_json)?])?;
+        }
+        let mut update_grain = tx.prepare("UPDATE grains SET cluster_id = ?1, cluster_distance = ?2 WHERE id = ?3")?;
+        for (grain_id, cluster, distance) in assignments.iter() {
+            update_grain.execute(params![cluster, distance, grain_id])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Lists every source file path that has grains, rejected grains or a file record in the database,
+/// with the number of grains from each file
+pub fn list_source_files(db: &str) -> Result<Vec<(String, usize)>, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let mut stmt = conn.prepare("
+        SELECT path, (SELECT COUNT(*) FROM grains WHERE grains.file = path)
+        FROM (SELECT file AS path FROM grains UNION SELECT file FROM rejected_grains UNION SELECT path FROM files)
+        ORDER BY path
+    ")?;
+    let paths = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<Vec<(String, usize)>, rusqlite::Error>>()?;
+    Ok(paths)
+}
 
+/// Deletes everything in the database that came from a source file: its grains with their features
+/// and tags, its rejected grains, and its file records.
+/// Returns the number of grains that were deleted.
+pub fn delete_source_file(db: &str, path: &str) -> Result<usize, rusqlite::Error> {
+    let mut conn = Connection::open(db)?;
+    let tx = conn.transaction()?;
+    for table in GRAIN_CHILD_TABLES {
+        tx.execute(&format!("DELETE FROM {} WHERE grain_id IN (SELECT id FROM grains WHERE file = ?1)", table), params![path])?;
+    }
+    let num_grains = tx.execute("DELETE FROM grains WHERE file = ?1", params![path])?;
+    tx.execute("DELETE FROM rejected_grains WHERE file = ?1", params![path])?;
+    tx.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+    tx.commit()?;
     match conn.close() {
         Ok(_) => (),
         Err((_, err)) => return Err(err)
     }
+    Ok(num_grains)
+}
+
+/// Deletes grains by id, with their features and tags.
+/// Returns the number of grains that were deleted.
+pub fn delete_grains(db: &str, ids: &[i64]) -> Result<usize, rusqlite::Error> {
+    let mut conn = Connection::open(db)?;
+    let tx = conn.transaction()?;
+    let mut num_grains = 0;
+    {
+        let mut delete_children = GRAIN_CHILD_TABLES.iter()
+            .map(|table| tx.prepare(&format!("DELETE FROM {} WHERE grain_id = ?1", table)))
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        let mut delete_grain = tx.prepare("DELETE FROM grains WHERE id = ?1")?;
+        for id in ids.iter() {
+            for delete_child in delete_children.iter_mut() {
+                delete_child.execute(params![id])?;
+            }
+            num_grains += delete_grain.execute(params![id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(num_grains)
+}
+
+/// Deletes mel bands, MFCCs and tags whose grain no longer exists.
+/// Returns the number of rows that were deleted (or would be deleted, if `dry_run` is true).
+pub fn delete_orphans(db: &str, dry_run: bool) -> Result<usize, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let mut num_rows = 0;
+    for table in GRAIN_CHILD_TABLES {
+        // A dry run doesn't upgrade the schema, so an old database may not have every table yet
+        let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)", params![table], |row| row.get(0))?;
+        if !exists {
+            continue;
+        }
+        num_rows += if dry_run {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {} WHERE grain_id NOT IN (SELECT id FROM grains)", table), [], |row| row.get::<usize, usize>(0))?
+        } else {
+            conn.execute(&format!("DELETE FROM {} WHERE grain_id NOT IN (SELECT id FROM grains)", table), [])?
+        };
+    }
+    Ok(num_rows)
+}
+
+/// Loads all file records, keyed by source file path and grain profile key
+pub fn get_file_records(db: &str) -> Result<HashMap<(String, String), FileRecord>, rusqlite::Error> {
+    let conn = Connection::open(db)?;
+    let mut stmt = conn.prepare("SELECT path, profile, id, size, mtime, hash, analysis, complete FROM files")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(((row.get(0)?, row.get(1)?), FileRecord{id: row.get(2)?, size: row.get(3)?, mtime: row.get(4)?, hash: row.get(5)?, analysis: row.get(6)?, complete: row.get(7)?}))
+    })?;
+    rows.collect()
+}
+
+/// Updates the size and modification time of file records whose contents have not changed
+pub fn touch_files(db: &str, files: &[(i64, FileStat)]) -> Result<(), rusqlite::Error> {
+    let mut conn = Connection::open(db)?;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE files SET size = ?1, mtime = ?2 WHERE id = ?3")?;
+        for (file_id, stat) in files.iter() {
+            stmt.execute(params![stat.size, stat.mtime, file_id])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Deletes all grains linked to a file record, along with their features, tags and rejected grains
+fn delete_file_grains(conn: &Connection, file_id: i64) -> Result<(), rusqlite::Error> {
+    for table in GRAIN_CHILD_TABLES {
+        conn.execute(&format!("DELETE FROM {} WHERE grain_id IN (SELECT id FROM grains WHERE file_id = ?1)", table), params![file_id])?;
+    }
+    conn.execute("DELETE FROM grains WHERE file_id = ?1", params![file_id])?;
+    conn.execute("DELETE FROM rejected_grains WHERE file_id = ?1", params![file_id])?;
     Ok(())
 }
 
-/// Creates the SQLite database schema
-pub fn create_schema(db: &str) -> Result<(), rusqlite::Error> {
-    let mut conn = match Connection::open(&db) {
+/// Deletes all grains from a file that have no file record, along with their features and tags.
+/// The tags are copied to the `legacy_tags` table first, with the positions of their grains.
+fn delete_legacy_grains(conn: &Connection, path: &str) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO legacy_tags (file, start_frame, end_frame, tag)
+        SELECT grains.file, grains.start_frame, grains.end_frame, tags.tag FROM tags
+        JOIN grains ON grains.id = tags.grain_id
+        WHERE grains.file_id IS NULL AND grains.file = ?1",
+        params![path]
+    )?;
+    for table in GRAIN_CHILD_TABLES {
+        conn.execute(&format!("DELETE FROM {} WHERE grain_id IN (SELECT id FROM grains WHERE file_id IS NULL AND file = ?1)", table), params![path])?;
+    }
+    conn.execute("DELETE FROM grains WHERE file_id IS NULL AND file = ?1", params![path])?;
+    Ok(())
+}
+
+/// Adds a column to a table if the table doesn't have it yet
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, declaration: &str) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns: Vec<String> = stmt.query_map([], |row| row.get(1))?.collect::<Result<_, _>>()?;
+    if !columns.iter().any(|c| c == column) {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, declaration))?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub enum SchemaError {
+    Database(String),
+    /// The database was created by a newer version of the grain processor
+    Version(String)
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Database(msg) => write!(f, "{}", msg),
+            SchemaError::Version(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+/// A schema migration. It runs inside a transaction.
+type Migration = fn(&Connection) -> Result<(), rusqlite::Error>;
+
+/// The schema migrations, in order. Migration `i` upgrades a database from schema version `i` to `i + 1`.
+/// Databases created before schema versions existed have version 0. To change the schema, add a migration
+/// to the end of this list. Never change a migration that has already been released.
+const MIGRATIONS: &[Migration] = &[
+    migrate_base_schema,
+    migrate_spectral_centroid_not_null,
+    migrate_lookup_indexes,
+    migrate_unique_tags,
+    migrate_feature_stats,
+    migrate_grain_autoincrement,
+    migrate_clusters,
+    migrate_embedding,
+    migrate_voicing,
+    migrate_harmonics,
+    migrate_temporal_features,
+    migrate_frame_statistics,
+    migrate_file_analysis,
+    migrate_legacy_tags,
+];
+
+/// The schema version that this version of the grain processor creates and understands
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Gets the schema version of a database
+fn get_schema_version(conn: &Connection) -> Result<u32, rusqlite::Error> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Checks that a database can be used by this version of the grain processor, without changing it.
+/// Returns the schema version of the database.
+pub fn check_schema_version(db: &str) -> Result<u32, SchemaError> {
+    let conn = match Connection::open(db) {
+        Ok(x) => x,
+        Err(err) => return Err(SchemaError::Database(format!("Error opening database {}: {}", db, err)))
+    };
+    let version = match get_schema_version(&conn) {
+        Ok(x) => x,
+        Err(err) => return Err(SchemaError::Database(format!("Error reading the schema version of {}: {}", db, err)))
+    };
+    if version > SCHEMA_VERSION {
+        return Err(SchemaError::Version(format!(
+            "The database {} has schema version {}, but this grain processor only supports up to version {}. Please use a newer grain processor.",
+            db, version, SCHEMA_VERSION)));
+    }
+    Ok(version)
+}
+
+/// Creates the database if it doesn't exist, and upgrades its schema to `SCHEMA_VERSION`.
+/// Each migration runs in its own transaction, so an interrupted upgrade leaves the database at the last
+/// version that was completed. Databases with a newer schema than this grain processor are refused.
+/// Returns the schema version the database had before it was upgraded.
+pub fn migrate(db: &str) -> Result<u32, SchemaError> {
+    let version = check_schema_version(db)?;
+    let mut conn = match Connection::open(db) {
         Ok(x) => x,
-        Err(err) => return Err(err)
+        Err(err) => return Err(SchemaError::Database(format!("Error opening database {}: {}", db, err)))
     };
+    // Migrations may rebuild tables that other tables refer to, which foreign key enforcement would prevent.
+    // This can't be changed inside a transaction.
+    if let Err(err) = conn.execute_batch("PRAGMA foreign_keys = OFF;") {
+        return Err(SchemaError::Database(format!("Error opening database {}: {}", db, err)));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let result = conn.transaction().and_then(|tx| {
+            migration(&tx)?;
+            tx.pragma_update(None, "user_version", i as u32 + 1)?;
+            tx.commit()
+        });
+        if let Err(err) = result {
+            return Err(SchemaError::Database(format!("Error upgrading database {} to schema version {}: {}", db, i + 1, err)));
+        }
+    }
+    if let Err((_, err)) = conn.close() {
+        return Err(SchemaError::Database(format!("Error closing database {}: {}", db, err)));
+    }
+    Ok(version)
+}
 
-    match conn.execute("
-        CREATE TABLE grains (
+/// Schema version 1: the grains, files, rejected grains, tags, mel band and MFCC tables.
+/// Databases from before schema versions existed may have any subset of these tables, so tables that
+/// already exist are left alone and missing columns are added.
+fn migrate_base_schema(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS grains (
             id INTEGER PRIMARY KEY,
+            file_id INTEGER REFERENCES files(id),
             file TEXT NOT NULL,
             start_frame INTEGER NOT NULL,
             end_frame INTEGER NOT NULL,
@@ -124,21 +1148,496 @@ pub fn create_schema(db: &str) -> Result<(), rusqlite::Error> {
             spectral_variance REAL NOT NULL
         );
 
-        CREATE TABLE tags (
+        CREATE TABLE IF NOT EXISTS files (
+            id INTEGER PRIMARY KEY,
+            path TEXT NOT NULL,
+            profile TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            complete INTEGER NOT NULL DEFAULT 0,
+            UNIQUE (path, profile)
+        );
+
+        CREATE TABLE IF NOT EXISTS rejected_grains (
+            id INTEGER PRIMARY KEY,
+            file_id INTEGER REFERENCES files(id),
+            file TEXT NOT NULL,
+            start_frame INTEGER NOT NULL,
+            end_frame INTEGER NOT NULL,
+            reason TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS tags (
             id INTEGER PRIMARY KEY,
             grain_id INTEGER NOT NULL,
             tag TEXT NOT NULL,
             FOREIGN KEY (grain_id) REFERENCES grains(id)
         );
-    ", ()) {
-        Ok(_) => (),
-        Err(err) => return Err(err)
+
+        CREATE TABLE IF NOT EXISTS mel_bands (
+            grain_id INTEGER NOT NULL,
+            band INTEGER NOT NULL,
+            value REAL NOT NULL,
+            PRIMARY KEY (grain_id, band),
+            FOREIGN KEY (grain_id) REFERENCES grains(id)
+        );
+
+        CREATE TABLE IF NOT EXISTS mfccs (
+            grain_id INTEGER NOT NULL,
+            coefficient INTEGER NOT NULL,
+            value REAL NOT NULL,
+            PRIMARY KEY (grain_id, coefficient),
+            FOREIGN KEY (grain_id) REFERENCES grains(id)
+        );
+    ")?;
+
+    // Databases created before the files table existed have no file_id column
+    add_column_if_missing(conn, "grains", "file_id", "INTEGER REFERENCES files(id)")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS grains_file_id ON grains (file_id);")?;
+    Ok(())
+}
+
+// The numeric columns of the grains table in schema version 1. Migrations must not depend on
+// `GRAIN_COLUMNS`, since it changes when columns are added.
+const GRAIN_COLUMNS_V1: [&str; 22] = [
+    "start_frame", "end_frame", "length", "sample_rate", "grain_duration", "frequency", "midi", "energy",
+    "spectral_centroid", "spectral_entropy", "spectral_flatness", "spectral_kurtosis",
+    "spectral_roll_off_50", "spectral_roll_off_75", "spectral_roll_off_90", "spectral_roll_off_95",
+    "spectral_skewness", "spectral_slope", "spectral_slope_0_1_khz", "spectral_slope_1_5_khz",
+    "spectral_slope_0_5_khz", "spectral_variance"
+];
+
+/// Schema version 2: `spectral_centroid` is NOT NULL like the other spectral columns.
+/// SQLite can't change a column constraint, so the grains table is rebuilt with the same grain ids.
+/// Grains without a spectral centroid can't be kept, so they are moved to the rejected grains with
+/// the reason `missing_features`.
+fn migrate_spectral_centroid_not_null(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(&format!("
+        INSERT INTO rejected_grains (file_id, file, start_frame, end_frame, reason)
+            SELECT file_id, file, start_frame, end_frame, 'missing_features' FROM grains WHERE spectral_centroid IS NULL;
+        DELETE FROM mel_bands WHERE grain_id IN (SELECT id FROM grains WHERE spectral_centroid IS NULL);
+        DELETE FROM mfccs WHERE grain_id IN (SELECT id FROM grains WHERE spectral_centroid IS NULL);
+        DELETE FROM tags WHERE grain_id IN (SELECT id FROM grains WHERE spectral_centroid IS NULL);
+
+        CREATE TABLE grains_new (
+            id INTEGER PRIMARY KEY,
+            file_id INTEGER REFERENCES files(id),
+            file TEXT NOT NULL,
+            start_frame INTEGER NOT NULL,
+            end_frame INTEGER NOT NULL,
+            length INTEGER NOT NULL,
+            sample_rate INTEGER NOT NULL,
+            grain_duration REAL NOT NULL,
+            frequency REAL,
+            midi REAL,
+            energy REAL,
+            spectral_centroid REAL NOT NULL,
+            spectral_entropy REAL NOT NULL,
+            spectral_flatness REAL NOT NULL,
+            spectral_kurtosis REAL NOT NULL,
+            spectral_roll_off_50 REAL NOT NULL,
+            spectral_roll_off_75 REAL NOT NULL,
+            spectral_roll_off_90 REAL NOT NULL,
+            spectral_roll_off_95 REAL NOT NULL,
+            spectral_skewness REAL NOT NULL,
+            spectral_slope REAL NOT NULL,
+            spectral_slope_0_1_khz REAL NOT NULL,
+            spectral_slope_1_5_khz REAL NOT NULL,
+            spectral_slope_0_5_khz REAL NOT NULL,
+            spectral_variance REAL NOT NULL
+        );
+        INSERT INTO grains_new (id, file_id, file, {0}) SELECT id, file_id, file, {0} FROM grains WHERE spectral_centroid IS NOT NULL;
+        DROP TABLE grains;
+        ALTER TABLE grains_new RENAME TO grains;
+        CREATE INDEX grains_file_id ON grains (file_id);
+    ", GRAIN_COLUMNS_V1.join(", ")))
+}
+
+/// Schema version 3: indexes for looking up grains and rejected grains by source file
+fn migrate_lookup_indexes(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        CREATE INDEX IF NOT EXISTS grains_file ON grains (file);
+        CREATE INDEX IF NOT EXISTS rejected_grains_file_id ON rejected_grains (file_id);
+        CREATE INDEX IF NOT EXISTS rejected_grains_file ON rejected_grains (file);
+    ")
+}
+
+/// Schema version 4: a grain can have each tag only once, and tags can be looked up quickly.
+/// Duplicate tags are removed first.
+fn migrate_unique_tags(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        DELETE FROM tags WHERE id NOT IN (SELECT MIN(id) FROM tags GROUP BY grain_id, tag);
+        CREATE UNIQUE INDEX tags_grain_id_tag ON tags (grain_id, tag);
+        CREATE INDEX tags_tag ON tags (tag);
+    ")
+}
+
+/// Schema version 5: corpus statistics of the grain features, which are used to normalize features for similarity
+fn migrate_feature_stats(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        CREATE TABLE feature_stats (
+            feature TEXT PRIMARY KEY,
+            count INTEGER NOT NULL,
+            mean REAL NOT NULL,
+            std REAL NOT NULL,
+            min REAL NOT NULL,
+            max REAL NOT NULL
+        );
+        CREATE TABLE feature_covariance (
+            feature_a TEXT NOT NULL,
+            feature_b TEXT NOT NULL,
+            covariance REAL NOT NULL,
+            PRIMARY KEY (feature_a, feature_b)
+        );
+    ")
+}
+
+/// Schema version 6: grain ids are never reused. Without AUTOINCREMENT, SQLite gives new grains the ids of the
+/// grains that were deleted last, such as when the last file is extracted again, and the nearest neighbor index
+/// can't tell the new grains from the old ones. The grains table is rebuilt with the same grain ids.
+fn migrate_grain_autoincrement(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(&format!("
+        CREATE TABLE grains_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_id INTEGER REFERENCES files(id),
+            file TEXT NOT NULL,
+            start_frame INTEGER NOT NULL,
+            end_frame INTEGER NOT NULL,
+            length INTEGER NOT NULL,
+            sample_rate INTEGER NOT NULL,
+            grain_duration REAL NOT NULL,
+            frequency REAL,
+            midi REAL,
+            energy REAL,
+            spectral_centroid REAL NOT NULL,
+            spectral_entropy REAL NOT NULL,
+            spectral_flatness REAL NOT NULL,
+            spectral_kurtosis REAL NOT NULL,
+            spectral_roll_off_50 REAL NOT NULL,
+            spectral_roll_off_75 REAL NOT NULL,
+            spectral_roll_off_90 REAL NOT NULL,
+            spectral_roll_off_95 REAL NOT NULL,
+            spectral_skewness REAL NOT NULL,
+            spectral_slope REAL NOT NULL,
+            spectral_slope_0_1_khz REAL NOT NULL,
+            spectral_slope_1_5_khz REAL NOT NULL,
+            spectral_slope_0_5_khz REAL NOT NULL,
+            spectral_variance REAL NOT NULL
+        );
+        INSERT INTO grains_new (id, file_id, file, {0}) SELECT id, file_id, file, {0} FROM grains;
+        DROP TABLE grains;
+        ALTER TABLE grains_new RENAME TO grains;
+        CREATE INDEX grains_file_id ON grains (file_id);
+        CREATE INDEX grains_file ON grains (file);
+    ", GRAIN_COLUMNS_V1.join(", ")))
+}
+
+/// Schema version 7: corpus clusters. Each grain can be assigned to a cluster, with its distance to the cluster centroid.
+fn migrate_clusters(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        CREATE TABLE clusters (
+            id INTEGER PRIMARY KEY,
+            centroid TEXT NOT NULL
+        );
+        CREATE TABLE cluster_model (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            similarity TEXT NOT NULL
+        );
+        ALTER TABLE grains ADD COLUMN cluster_id INTEGER;
+        ALTER TABLE grains ADD COLUMN cluster_distance REAL;
+        CREATE INDEX grains_cluster_id ON grains (cluster_id);
+    ")
+}
+
+/// Schema version 8: the corpus embedding. Each grain can have 2-D or 3-D coordinates, and the projection is stored
+/// so that new grains can be placed in the same space.
+fn migrate_embedding(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        CREATE TABLE embedding (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            projection TEXT NOT NULL
+        );
+        ALTER TABLE grains ADD COLUMN embedding_x REAL;
+        ALTER TABLE grains ADD COLUMN embedding_y REAL;
+        ALTER TABLE grains ADD COLUMN embedding_z REAL;
+    ")
+}
+
+/// Schema version 9: the voicing probability from pitch estimation. Grains from older versions have no voicing
+/// probability, and keep their pitch even if they are unpitched.
+fn migrate_voicing(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("ALTER TABLE grains ADD COLUMN voicing_probability REAL;")
+}
+
+/// Schema version 10: harmonic descriptors, and the strongest spectral peaks of each grain
+fn migrate_harmonics(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        ALTER TABLE grains ADD COLUMN hnr REAL;
+        ALTER TABLE grains ADD COLUMN inharmonicity REAL;
+        ALTER TABLE grains ADD COLUMN odd_even_ratio REAL;
+        ALTER TABLE grains ADD COLUMN tristimulus_1 REAL;
+        ALTER TABLE grains ADD COLUMN tristimulus_2 REAL;
+        ALTER TABLE grains ADD COLUMN tristimulus_3 REAL;
+        CREATE TABLE spectral_peaks (
+            grain_id INTEGER NOT NULL,
+            peak INTEGER NOT NULL,
+            frequency REAL NOT NULL,
+            amplitude REAL NOT NULL,
+            PRIMARY KEY (grain_id, peak),
+            FOREIGN KEY (grain_id) REFERENCES grains(id)
+        );
+    ")
+}
+
+/// Schema version 11: time-domain descriptors of the grain and its amplitude envelope
+fn migrate_temporal_features(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        ALTER TABLE grains ADD COLUMN rms REAL;
+        ALTER TABLE grains ADD COLUMN peak REAL;
+        ALTER TABLE grains ADD COLUMN crest_factor REAL;
+        ALTER TABLE grains ADD COLUMN zero_crossing_rate REAL;
+        ALTER TABLE grains ADD COLUMN attack_time REAL;
+        ALTER TABLE grains ADD COLUMN decay_slope REAL;
+        ALTER TABLE grains ADD COLUMN temporal_centroid REAL;
+        ALTER TABLE grains ADD COLUMN envelope_flatness REAL;
+    ")
+}
+
+/// Schema version 12: statistics of the spectral descriptors over the STFT frames of each grain
+fn migrate_frame_statistics(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        ALTER TABLE grains ADD COLUMN spectral_centroid_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_centroid_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_centroid_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_entropy_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_entropy_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_entropy_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_flatness_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_flatness_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_flatness_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_kurtosis_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_kurtosis_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_kurtosis_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_50_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_50_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_50_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_75_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_75_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_75_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_90_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_90_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_90_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_95_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_95_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_roll_off_95_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_skewness_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_skewness_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_skewness_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_0_1_khz_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_0_1_khz_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_0_1_khz_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_1_5_khz_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_1_5_khz_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_1_5_khz_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_0_5_khz_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_0_5_khz_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_slope_0_5_khz_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_variance_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_variance_std REAL;
+        ALTER TABLE grains ADD COLUMN spectral_variance_delta REAL;
+        ALTER TABLE grains ADD COLUMN spectral_flux_mean REAL;
+        ALTER TABLE grains ADD COLUMN spectral_flux_max REAL;
+    ")
+}
+
+/// Schema version 13: the analysis settings that each file was processed with. Files from older versions have
+/// no analysis settings, so they are processed again the next time grains are extracted.
+fn migrate_file_analysis(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("ALTER TABLE files ADD COLUMN analysis TEXT NOT NULL DEFAULT '';")
+}
+
+/// Schema version 14: the tags of legacy grains that have been replaced, which are carried over to the new grains
+/// that overlap them once their file has been processed
+fn migrate_legacy_tags(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("
+        CREATE TABLE legacy_tags (
+            file TEXT NOT NULL,
+            start_frame INTEGER NOT NULL,
+            end_frame INTEGER NOT NULL,
+            tag TEXT NOT NULL
+        );
+        CREATE INDEX legacy_tags_file ON legacy_tags (file);
+    ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    /// Makes a database path in the temporary directory, whose file is deleted when it is dropped
+    fn temp_db(name: &str) -> TempFile {
+        TempFile::new("sqlite", &format!("{}.sqlite3", name))
     }
 
-    match conn.close() {
-        Ok(_) => (),
-        Err((_, err)) => return Err(err)
+    /// Creates a database at a schema version by running the first `version` migrations
+    fn create_at_version(db: &str, version: usize) {
+        let mut conn = Connection::open(db).unwrap();
+        for migration in MIGRATIONS[..version].iter() {
+            let tx = conn.transaction().unwrap();
+            migration(&tx).unwrap();
+            tx.commit().unwrap();
+        }
+        conn.pragma_update(None, "user_version", version as u32).unwrap();
     }
 
-    Ok(())
+    /// Inserts a grain with the columns of schema version 1, and returns its id
+    fn insert_v1_grain(db: &str) -> i64 {
+        let conn = Connection::open(db).unwrap();
+        let placeholders: Vec<String> = (0..GRAIN_COLUMNS_V1.len()).map(|i| format!("?{}", i + 2)).collect();
+        let mut values: Vec<Value> = vec![Value::Text(String::from("a.wav"))];
+        values.extend((0..GRAIN_COLUMNS_V1.len()).map(|i| Value::Integer(i as i64 + 1)));
+        conn.execute(&format!("INSERT INTO grains (file, {}) VALUES (?1, {})", GRAIN_COLUMNS_V1.join(", "), placeholders.join(", ")),
+            params_from_iter(values.iter())).unwrap();
+        conn.last_insert_rowid()
+    }
+
+    /// Gets the tables and indexes of a database, with the columns of each table
+    fn schema(db: &str) -> Vec<(String, Vec<String>)> {
+        let conn = Connection::open(db).unwrap();
+        let mut stmt = conn.prepare("SELECT type, name FROM sqlite_master WHERE name NOT LIKE 'sqlite_%' ORDER BY type, name").unwrap();
+        let objects: Vec<(String, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().collect::<Result<_, _>>().unwrap();
+        objects.into_iter().map(|(kind, name)| {
+            let columns: Vec<String> = if kind == "table" {
+                // Each column is described by its name, type and NOT NULL constraint
+                let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", name)).unwrap();
+                let columns = stmt.query_map([], |row| Ok(format!("{} {} {}", row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, bool>(3)?)))
+                    .unwrap().collect::<Result<_, _>>();
+                columns.unwrap()
+            } else {
+                Vec::new()
+            };
+            (format!("{} {}", kind, name), columns)
+        }).collect()
+    }
+
+    fn version(db: &str) -> u32 {
+        get_schema_version(&Connection::open(db).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fresh_database_reaches_the_current_version() {
+        let db = temp_db("fresh");
+        assert_eq!(migrate(&db.0).unwrap(), 0);
+        assert_eq!(version(&db.0), SCHEMA_VERSION);
+        // Migrating again does nothing
+        assert_eq!(migrate(&db.0).unwrap(), SCHEMA_VERSION);
+        let conn = Connection::open(&db.0).unwrap();
+        let columns: Vec<String> = conn.prepare("PRAGMA table_info(grains)").unwrap()
+            .query_map([], |row| row.get(1)).unwrap().collect::<Result<_, _>>().unwrap();
+        for column in GRAIN_COLUMNS.iter() {
+            assert!(columns.iter().any(|c| c == column), "the grains table has no column {}", column);
+        }
+    }
+
+    #[test]
+    fn every_older_version_reaches_the_current_schema() {
+        let fresh = temp_db("reference");
+        migrate(&fresh.0).unwrap();
+        let expected = schema(&fresh.0);
+        for old_version in 1..SCHEMA_VERSION as usize {
+            let db = temp_db(&format!("v{}", old_version));
+            create_at_version(&db.0, old_version);
+            let id = insert_v1_grain(&db.0);
+            assert_eq!(migrate(&db.0).unwrap(), old_version as u32);
+            assert_eq!(version(&db.0), SCHEMA_VERSION);
+            assert_eq!(schema(&db.0), expected, "upgrading from version {} gives a different schema", old_version);
+            let count: i64 = Connection::open(&db.0).unwrap()
+                .query_row("SELECT COUNT(*) FROM grains WHERE id = ?1 AND spectral_variance = 22", [id], |row| row.get(0)).unwrap();
+            assert_eq!(count, 1, "upgrading from version {} lost a grain", old_version);
+        }
+    }
+
+    #[test]
+    fn legacy_database_reaches_the_current_schema() {
+        // The schema from before schema versions and the files table existed
+        let db = temp_db("legacy");
+        let columns: Vec<String> = GRAIN_COLUMNS_V1.iter().map(|c| format!("{} REAL", c)).collect();
+        Connection::open(&db.0).unwrap().execute_batch(&format!("
+            CREATE TABLE grains (id INTEGER PRIMARY KEY, file TEXT NOT NULL, {});
+            CREATE TABLE tags (id INTEGER PRIMARY KEY, grain_id INTEGER NOT NULL, tag TEXT NOT NULL);
+        ", columns.join(", "))).unwrap();
+        let kept = insert_v1_grain(&db.0);
+        let conn = Connection::open(&db.0).unwrap();
+        conn.execute("INSERT INTO grains (file, start_frame, end_frame, length, sample_rate, grain_duration, spectral_entropy, spectral_flatness,
+            spectral_kurtosis, spectral_roll_off_50, spectral_roll_off_75, spectral_roll_off_90, spectral_roll_off_95, spectral_skewness,
+            spectral_slope, spectral_slope_0_1_khz, spectral_slope_1_5_khz, spectral_slope_0_5_khz, spectral_variance)
+            VALUES ('b.wav', 0, 10, 10, 44100, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1)", []).unwrap();
+        conn.execute("INSERT INTO tags (grain_id, tag) VALUES (?1, 'x'), (?1, 'x')", [kept]).unwrap();
+        drop(conn);
+
+        assert_eq!(migrate(&db.0).unwrap(), 0);
+        assert_eq!(version(&db.0), SCHEMA_VERSION);
+        let conn = Connection::open(&db.0).unwrap();
+        let grains: Vec<i64> = conn.prepare("SELECT id FROM grains").unwrap().query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(grains, vec![kept]);
+        let rejected: String = conn.query_row("SELECT reason FROM rejected_grains WHERE file = 'b.wav'", [], |row| row.get(0)).unwrap();
+        assert_eq!(rejected, "missing_features");
+        let tags: i64 = conn.query_row("SELECT COUNT(*) FROM tags", [], |row| row.get(0)).unwrap();
+        assert_eq!(tags, 1);
+    }
+
+    #[test]
+    fn tags_of_legacy_grains_are_carried_over() {
+        let db = temp_db("legacy_tags");
+        migrate(&db.0).unwrap();
+        let legacy = insert_v1_grain(&db.0);
+        Connection::open(&db.0).unwrap().execute("INSERT INTO tags (grain_id, tag) VALUES (?1, 'x')", [legacy]).unwrap();
+
+        let mut writer = GrainWriter::open(&db.0).unwrap();
+        assert_eq!(writer.count_legacy_grains("a.wav").unwrap(), 1);
+        let file_id = writer.begin_file("a.wav", "profile", &FileStat{size: 1, mtime: 1}, "hash", "analysis").unwrap();
+        assert_eq!(writer.count_legacy_grains("a.wav").unwrap(), 0);
+
+        // The first new grain overlaps the legacy grain, which spans frames 1 to 2, and the second doesn't
+        let overlapping = insert_v1_grain(&db.0);
+        let separate = insert_v1_grain(&db.0);
+        let conn = Connection::open(&db.0).unwrap();
+        conn.execute("UPDATE grains SET file_id = ?1", [file_id]).unwrap();
+        conn.execute("UPDATE grains SET start_frame = 2, end_frame = 5 WHERE id = ?1", [separate]).unwrap();
+        writer.complete_file(file_id).unwrap();
+
+        let tagged: Vec<i64> = conn.prepare("SELECT grain_id FROM tags WHERE tag = 'x'").unwrap()
+            .query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(tagged, vec![overlapping]);
+        let remaining: i64 = conn.query_row("SELECT COUNT(*) FROM legacy_tags", [], |row| row.get(0)).unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn grain_ids_are_not_reused() {
+        // Schema version 5 is the last version without AUTOINCREMENT
+        let db = temp_db("ids");
+        create_at_version(&db.0, 5);
+        let first = insert_v1_grain(&db.0);
+        let last = insert_v1_grain(&db.0);
+        migrate(&db.0).unwrap();
+        Connection::open(&db.0).unwrap().execute("DELETE FROM grains WHERE id = ?1", [last]).unwrap();
+        let next = insert_v1_grain(&db.0);
+        assert!(first < last && last < next);
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let db = temp_db("newer");
+        create_at_version(&db.0, SCHEMA_VERSION as usize);
+        Connection::open(&db.0).unwrap().pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        assert!(matches!(migrate(&db.0), Err(SchemaError::Version(_))));
+        assert!(matches!(check_schema_version(&db.0), Err(SchemaError::Version(_))));
+        assert_eq!(version(&db.0), SCHEMA_VERSION + 1);
+    }
 }
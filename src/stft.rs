@@ -0,0 +1,211 @@
+// File: stft.rs
+// This file has the intra-grain spectral analysis. A long grain is split into overlapping STFT frames,
+// the spectral descriptors are found for each frame, and each descriptor is summarized by its mean,
+// standard deviation and change from the first frame to the last. The spectral flux between frames
+// shows how much the spectrum changes within the grain.
+
+use aus::analysis::Analysis;
+use aus::spectrum::{complex_to_polar_rstft, rfftfreq, rstft};
+
+/// The STFT frame size that is used if a grain profile sets a hop but no frame size
+pub const DEFAULT_STFT_FRAME_SIZE: usize = 1024;
+
+/// The spectral descriptors that are summarized over the frames of a grain
+pub const FRAME_DESCRIPTORS: [&str; 14] = [
+    "spectral_centroid",
+    "spectral_entropy",
+    "spectral_flatness",
+    "spectral_kurtosis",
+    "spectral_roll_off_50",
+    "spectral_roll_off_75",
+    "spectral_roll_off_90",
+    "spectral_roll_off_95",
+    "spectral_skewness",
+    "spectral_slope",
+    "spectral_slope_0_1_khz",
+    "spectral_slope_1_5_khz",
+    "spectral_slope_0_5_khz",
+    "spectral_variance",
+];
+
+/// The settings for intra-grain STFT analysis
+#[derive(Debug, Clone, Copy)]
+pub struct StftSettings {
+    /// The length of each frame in samples, which is also the FFT size
+    pub frame_size: usize,
+    /// The distance between frames in samples
+    pub hop: usize,
+}
+
+/// The summary of the spectral descriptors over the STFT frames of a grain. Everything is NaN if the grain
+/// was not analyzed with frames, or if it is too short for two frames.
+#[derive(Debug, Clone)]
+pub struct FrameStatistics {
+    /// The mean of each descriptor in `FRAME_DESCRIPTORS`
+    pub mean: [f64; 14],
+    /// The standard deviation of each descriptor
+    pub std: [f64; 14],
+    /// The value of each descriptor in the last frame minus its value in the first frame
+    pub delta: [f64; 14],
+    /// The mean spectral flux between neighboring frames. The flux is the squared euclidean distance between
+    /// the magnitude spectra after each is scaled to unit length, so it is 0.0 for a spectrum that doesn't change.
+    pub flux_mean: f64,
+    /// The largest spectral flux between neighboring frames
+    pub flux_max: f64,
+}
+
+impl FrameStatistics {
+    /// Statistics for a grain that was not analyzed with frames
+    pub fn unavailable() -> FrameStatistics {
+        FrameStatistics{mean: [f64::NAN; 14], std: [f64::NAN; 14], delta: [f64::NAN; 14], flux_mean: f64::NAN, flux_max: f64::NAN}
+    }
+
+    /// Gets the statistics in the order of their columns in the grains table: the mean, standard deviation
+    /// and delta of each descriptor, followed by the mean and largest spectral flux
+    pub fn values(&self) -> Vec<f64> {
+        let mut values: Vec<f64> = Vec::with_capacity(3 * FRAME_DESCRIPTORS.len() + 2);
+        for i in 0..FRAME_DESCRIPTORS.len() {
+            values.extend([self.mean[i], self.std[i], self.delta[i]]);
+        }
+        values.extend([self.flux_mean, self.flux_max]);
+        values
+    }
+
+    /// Makes statistics from values in the order of `values`
+    pub fn from_values(values: &[f64]) -> FrameStatistics {
+        let mut statistics = FrameStatistics::unavailable();
+        for i in 0..FRAME_DESCRIPTORS.len() {
+            statistics.mean[i] = values[3 * i];
+            statistics.std[i] = values[3 * i + 1];
+            statistics.delta[i] = values[3 * i + 2];
+        }
+        statistics.flux_mean = values[3 * FRAME_DESCRIPTORS.len()];
+        statistics.flux_max = values[3 * FRAME_DESCRIPTORS.len() + 1];
+        statistics
+    }
+
+    /// Gets a statistic by its column name, such as `spectral_centroid_std`. Returns `None` if there is no such column.
+    pub fn feature(&self, column: &str) -> Option<f64> {
+        match column {
+            "spectral_flux_mean" => return Some(self.flux_mean),
+            "spectral_flux_max" => return Some(self.flux_max),
+            _ => ()
+        }
+        let (descriptor, statistic) = column.rsplit_once('_')?;
+        let i = FRAME_DESCRIPTORS.iter().position(|x| *x == descriptor)?;
+        match statistic {
+            "mean" => Some(self.mean[i]),
+            "std" => Some(self.std[i]),
+            "delta" => Some(self.delta[i]),
+            _ => None
+        }
+    }
+}
+
+/// Gets the descriptors of one frame, in the order of `FRAME_DESCRIPTORS`
+fn descriptors(analysis: &Analysis) -> [f64; 14] {
+    [
+        analysis.spectral_centroid,
+        analysis.spectral_entropy,
+        analysis.spectral_flatness,
+        analysis.spectral_kurtosis,
+        analysis.spectral_roll_off_50,
+        analysis.spectral_roll_off_75,
+        analysis.spectral_roll_off_90,
+        analysis.spectral_roll_off_95,
+        analysis.spectral_skewness,
+        analysis.spectral_slope,
+        analysis.spectral_slope_0_1_khz,
+        analysis.spectral_slope_1_5_khz,
+        analysis.spectral_slope_0_5_khz,
+        analysis.spectral_variance,
+    ]
+}
+
+/// Analyzes a grain with an STFT and summarizes the descriptors of its frames. Only whole frames are analyzed,
+/// so the end of the grain that doesn't fill a frame is left out. Frames where a descriptor is undefined,
+/// such as silent frames, are left out of the mean and standard deviation of that descriptor.
+pub fn frame_statistics(grain: &[f64], sample_rate: u32, window_type: aus::WindowType, settings: &StftSettings) -> FrameStatistics {
+    if settings.frame_size == 0 || settings.hop == 0 || grain.len() < settings.frame_size + settings.hop {
+        return FrameStatistics::unavailable();
+    }
+    let num_frames = 1 + (grain.len() - settings.frame_size) / settings.hop;
+    let spectrogram = rstft(&grain[..settings.frame_size + (num_frames - 1) * settings.hop], settings.frame_size, settings.hop, window_type);
+    let (magnitude_spectrogram, _) = complex_to_polar_rstft(&spectrogram);
+    let rfft_freqs = rfftfreq(settings.frame_size, sample_rate);
+
+    let mut frames: Vec<[f64; 14]> = Vec::with_capacity(magnitude_spectrogram.len());
+    let mut flux: Vec<f64> = Vec::with_capacity(magnitude_spectrogram.len());
+    for (i, magnitude_spectrum) in magnitude_spectrogram.iter().enumerate() {
+        let previous = if i > 0 { Some(magnitude_spectrogram[i - 1].as_slice()) } else { None };
+        let analysis = aus::analysis::analyzer(magnitude_spectrum, previous, sample_rate, &rfft_freqs);
+        frames.push(descriptors(&analysis));
+        if analysis.spectral_flux.is_finite() {
+            flux.push(analysis.spectral_flux);
+        }
+    }
+
+    let mut statistics = FrameStatistics::unavailable();
+    for d in 0..FRAME_DESCRIPTORS.len() {
+        let values: Vec<f64> = frames.iter().map(|frame| frame[d]).filter(|x| x.is_finite()).collect();
+        if !values.is_empty() {
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            statistics.mean[d] = mean;
+            statistics.std[d] = (values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / values.len() as f64).sqrt();
+        }
+        statistics.delta[d] = frames[frames.len() - 1][d] - frames[0][d];
+    }
+    if !flux.is_empty() {
+        statistics.flux_mean = flux.iter().sum::<f64>() / flux.len() as f64;
+        statistics.flux_max = flux.iter().fold(f64::NEG_INFINITY, |max, x| max.max(*x));
+    }
+    statistics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const SAMPLE_RATE: u32 = 44100;
+    const SETTINGS: StftSettings = StftSettings{frame_size: 1024, hop: 512};
+
+    /// The index of the spectral centroid in `FRAME_DESCRIPTORS`
+    fn centroid() -> usize {
+        FRAME_DESCRIPTORS.iter().position(|x| *x == "spectral_centroid").unwrap()
+    }
+
+    #[test]
+    fn steady_sine_does_not_change() {
+        // The frequency is on a bin, so every frame has the same magnitude spectrum
+        let frequency = 40.0 * SAMPLE_RATE as f64 / SETTINGS.frame_size as f64;
+        let grain: Vec<f64> = (0..8192).map(|n| (2.0 * PI * frequency * n as f64 / SAMPLE_RATE as f64).sin()).collect();
+        let statistics = frame_statistics(&grain, SAMPLE_RATE, aus::WindowType::Hanning, &SETTINGS);
+        let c = centroid();
+        assert!(statistics.flux_mean.abs() < 1e-3 && statistics.flux_max.abs() < 1e-3, "flux {} {}", statistics.flux_mean, statistics.flux_max);
+        assert!(statistics.std[c] < 1e-3 * statistics.mean[c], "centroid std {}", statistics.std[c]);
+        assert!(statistics.delta[c].abs() < 1e-3 * statistics.mean[c]);
+    }
+
+    #[test]
+    fn rising_chirp_has_a_rising_centroid() {
+        // A linear chirp from 500 Hz to 4 kHz
+        let (f_start, f_end, len) = (500.0, 4000.0, 8192);
+        let duration = len as f64 / SAMPLE_RATE as f64;
+        let grain: Vec<f64> = (0..len).map(|n| {
+            let t = n as f64 / SAMPLE_RATE as f64;
+            (2.0 * PI * (f_start * t + (f_end - f_start) * t * t / (2.0 * duration))).sin()
+        }).collect();
+        let statistics = frame_statistics(&grain, SAMPLE_RATE, aus::WindowType::Hanning, &SETTINGS);
+        let c = centroid();
+        assert!(statistics.delta[c] > 2000.0, "centroid delta {}", statistics.delta[c]);
+        assert!(statistics.flux_mean > 0.0);
+    }
+
+    #[test]
+    fn short_grains_are_unavailable() {
+        let grain = vec![0.5; SETTINGS.frame_size + SETTINGS.hop - 1];
+        let statistics = frame_statistics(&grain, SAMPLE_RATE, aus::WindowType::Hanning, &SETTINGS);
+        assert!(statistics.values().iter().all(|x| x.is_nan()));
+    }
+}
@@ -0,0 +1,460 @@
+// File: tags.rs
+// This file has tag expressions, which search grains by combinations of tags, and tagging rules,
+// which tag grains automatically when they are extracted.
+
+use crate::grain_extractor::GrainEntry;
+use rusqlite::types::Value;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// A boolean combination of tags, such as `drums AND (bright OR NOT noisy)`.
+/// `NOT` binds tighter than `AND`, which binds tighter than `OR`. Tags that contain spaces, parentheses
+/// or double quotes, or that are the same as an operator, can be written in double quotes, with any double
+/// quotes inside them doubled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close
+}
+
+/// Splits a tag expression into tokens
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            chars.next();
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut tag = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        tag.push('"');
+                        chars.next();
+                    },
+                    Some('"') => break,
+                    Some(x) => tag.push(x),
+                    None => return Err(format!("Unterminated quote in tag expression {}", s))
+                }
+            }
+            tokens.push(Token::Quoted(tag));
+        } else {
+            let mut word = String::new();
+            while let Some(&x) = chars.peek() {
+                if x.is_whitespace() || x == '(' || x == ')' || x == '"' {
+                    break;
+                }
+                word.push(x);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser for tag expressions
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_operator(&self, operator: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Word(x)) if x == operator)
+    }
+
+    fn parse_or(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek_operator("OR") {
+            self.pos += 1;
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.parse_not()?;
+        while self.peek_operator("AND") {
+            self.pos += 1;
+            expr = TagExpr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<TagExpr, String> {
+        if self.peek_operator("NOT") {
+            self.pos += 1;
+            return Ok(TagExpr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_atom()
+    }
+
+    fn parse_atom(&mut self) -> Result<TagExpr, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Open) => {
+                let expr = self.parse_or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    },
+                    _ => Err(String::from("Expected a closing parenthesis in tag expression"))
+                }
+            },
+            Some(Token::Word(x)) if x == "AND" || x == "OR" || x == "NOT" => Err(format!("Expected a tag, but got {} in tag expression", x)),
+            Some(Token::Word(x)) | Some(Token::Quoted(x)) => Ok(TagExpr::Tag(x)),
+            Some(Token::Close) => Err(String::from("Unexpected closing parenthesis in tag expression")),
+            None => Err(String::from("Unexpected end of tag expression"))
+        }
+    }
+}
+
+impl FromStr for TagExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser{tokens: tokenize(s)?, pos: 0};
+        let expr = parser.parse_or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("Unexpected text after the end of tag expression {}", s));
+        }
+        Ok(expr)
+    }
+}
+
+impl TryFrom<String> for TagExpr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TagExpr> for String {
+    fn from(expr: TagExpr) -> String {
+        expr.to_string()
+    }
+}
+
+impl fmt::Display for TagExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagExpr::Tag(tag) if tag.is_empty() || tag == "AND" || tag == "OR" || tag == "NOT" || tag.contains(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '"') => {
+                write!(f, "\"{}\"", tag.replace('"', "\"\""))
+            },
+            TagExpr::Tag(tag) => write!(f, "{}", tag),
+            TagExpr::Not(expr) => write!(f, "NOT {}", expr),
+            TagExpr::And(a, b) => write!(f, "({} AND {})", a, b),
+            TagExpr::Or(a, b) => write!(f, "({} OR {})", a, b)
+        }
+    }
+}
+
+impl TagExpr {
+    /// Converts the expression to a SQL condition on the `grains` table. The tags are added to `values`
+    /// as parameters, numbered after the parameters that are already there.
+    pub fn to_sql(&self, values: &mut Vec<Value>) -> String {
+        match self {
+            TagExpr::Tag(tag) => {
+                values.push(Value::Text(tag.clone()));
+                format!("EXISTS (SELECT 1 FROM tags WHERE tags.grain_id = grains.id AND tags.tag = ?{})", values.len())
+            },
+            TagExpr::Not(expr) => format!("NOT {}", expr.to_sql(values)),
+            TagExpr::And(a, b) => format!("({} AND {})", a.to_sql(values), b.to_sql(values)),
+            TagExpr::Or(a, b) => format!("({} OR {})", a.to_sql(values), b.to_sql(values))
+        }
+    }
+}
+
+/// A comparison operator in a tagging rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual
+}
+
+impl Comparison {
+    // The operators, with the two-character operators first so that they are matched before their prefixes
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("!=", Comparison::NotEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal)
+    ];
+
+    /// Compares two values. Comparisons with NaN are always false.
+    pub fn compare(&self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterOrEqual => a >= b,
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => !a.is_nan() && !b.is_nan() && a != b
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match Comparison::OPERATORS.iter().find(|(_, comparison)| comparison == self) {
+            Some((operator, _)) => write!(f, "{}", operator),
+            None => Ok(())
+        }
+    }
+}
+
+/// A comparison of a grain feature with a number, such as `spectral_centroid > 3000`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureComparison {
+    /// The name of a column in the grains table
+    pub column: String,
+    pub comparison: Comparison,
+    pub value: f64,
+}
+
+impl FromStr for FeatureComparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for (operator, comparison) in Comparison::OPERATORS.iter() {
+            if let Some((column, value)) = s.split_once(operator) {
+                let column = column.trim();
+                if column.is_empty() {
+                    return Err(format!("Missing feature name in comparison {}", s));
+                }
+                let value: f64 = match value.trim().parse() {
+                    Ok(x) => x,
+                    Err(_) => return Err(format!("Invalid number in comparison {}", s))
+                };
+                return Ok(FeatureComparison{column: String::from(column), comparison: *comparison, value});
+            }
+        }
+        Err(format!("Expected a comparison such as spectral_centroid > 3000, but got {}", s))
+    }
+}
+
+impl fmt::Display for FeatureComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.column, self.comparison, self.value)
+    }
+}
+
+/// The condition of a tagging rule, such as `spectral_centroid > 3000 AND energy > 0.1`.
+/// Comparisons are combined with `AND` and `OR`, and `AND` binds tighter than `OR`.
+/// The condition is stored as a list of alternatives, each of which is a list of comparisons that must all be true.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct RuleCondition {
+    pub alternatives: Vec<Vec<FeatureComparison>>,
+}
+
+impl RuleCondition {
+    /// Checks whether a grain meets the condition. A comparison with a feature that the grain doesn't have is false.
+    pub fn matches(&self, grain: &GrainEntry) -> bool {
+        self.alternatives.iter().any(|comparisons| {
+            comparisons.iter().all(|c| match grain.feature(&c.column) {
+                Some(x) => c.comparison.compare(x, c.value),
+                None => false
+            })
+        })
+    }
+
+    /// Gets the feature comparisons in the condition
+    pub fn comparisons(&self) -> impl Iterator<Item = &FeatureComparison> {
+        self.alternatives.iter().flatten()
+    }
+}
+
+impl FromStr for RuleCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut alternatives: Vec<Vec<FeatureComparison>> = vec![Vec::new()];
+        let mut current: Vec<&str> = Vec::new();
+        // Collects the words since the last operator into a comparison
+        let finish = |current: &mut Vec<&str>, alternatives: &mut Vec<Vec<FeatureComparison>>| -> Result<(), String> {
+            if current.is_empty() {
+                return Err(format!("Missing comparison in tagging rule {}", s));
+            }
+            let comparison: FeatureComparison = current.join(" ").parse()?;
+            current.clear();
+            if let Some(last) = alternatives.last_mut() {
+                last.push(comparison);
+            }
+            Ok(())
+        };
+        for word in s.split_whitespace() {
+            match word {
+                "AND" => finish(&mut current, &mut alternatives)?,
+                "OR" => {
+                    finish(&mut current, &mut alternatives)?;
+                    alternatives.push(Vec::new());
+                },
+                _ => current.push(word)
+            }
+        }
+        finish(&mut current, &mut alternatives)?;
+        Ok(RuleCondition{alternatives})
+    }
+}
+
+impl TryFrom<String> for RuleCondition {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RuleCondition> for String {
+    fn from(condition: RuleCondition) -> String {
+        condition.to_string()
+    }
+}
+
+impl fmt::Display for RuleCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alternatives: Vec<String> = self.alternatives.iter().map(|comparisons| {
+            comparisons.iter().map(|c| c.to_string()).collect::<Vec<String>>().join(" AND ")
+        }).collect();
+        write!(f, "{}", alternatives.join(" OR "))
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Gets the note name of a MIDI note number, rounded to the nearest note. MIDI note 60 is C4.
+/// Returns `None` if the number is NaN or out of the MIDI range.
+pub fn note_name(midi: f64) -> Option<String> {
+    let note = midi.round();
+    if !(0.0..=127.0).contains(&note) {
+        return None;
+    }
+    let note = note as usize;
+    Some(format!("{}{}", NOTE_NAMES[note % 12], note as i32 / 12 - 1))
+}
+
+/// Finds the tags for a grain. The grain gets the tag of every rule whose condition it meets,
+/// and if `note_tags` is set, a tag such as `note:C4` for its nearest MIDI note.
+pub fn apply_rules(grain: &GrainEntry, rules: &BTreeMap<String, RuleCondition>, note_tags: bool) -> Vec<String> {
+    let mut tags: Vec<String> = rules.iter()
+        .filter(|(_, condition)| condition.matches(grain))
+        .map(|(tag, _)| tag.clone())
+        .collect();
+    if note_tags {
+        if let Some(note) = note_name(grain.midi) {
+            tags.push(format!("note:{}", note));
+        }
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(x: &str) -> Box<TagExpr> {
+        Box::new(TagExpr::Tag(String::from(x)))
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_and_and_tighter_than_or() {
+        let expr: TagExpr = "a OR NOT b AND c".parse().unwrap();
+        assert_eq!(expr, TagExpr::Or(tag("a"), Box::new(TagExpr::And(Box::new(TagExpr::Not(tag("b"))), tag("c")))));
+    }
+
+    #[test]
+    fn operators_are_left_associative() {
+        let expr: TagExpr = "a AND b AND c".parse().unwrap();
+        assert_eq!(expr, TagExpr::And(Box::new(TagExpr::And(tag("a"), tag("b"))), tag("c")));
+        let expr: TagExpr = "a OR b OR c".parse().unwrap();
+        assert_eq!(expr, TagExpr::Or(Box::new(TagExpr::Or(tag("a"), tag("b"))), tag("c")));
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        let expr: TagExpr = "drums AND (bright OR NOT noisy)".parse().unwrap();
+        assert_eq!(expr, TagExpr::And(tag("drums"), Box::new(TagExpr::Or(tag("bright"), Box::new(TagExpr::Not(tag("noisy")))))));
+        let expr: TagExpr = "NOT (a OR b)".parse().unwrap();
+        assert_eq!(expr, TagExpr::Not(Box::new(TagExpr::Or(tag("a"), tag("b")))));
+    }
+
+    #[test]
+    fn quoted_tags_can_contain_spaces_and_operators() {
+        let expr: TagExpr = "\"bowed string\" AND \"OR\"".parse().unwrap();
+        assert_eq!(expr, TagExpr::And(tag("bowed string"), tag("OR")));
+        let expr: TagExpr = "NOT\"(x)\"".parse().unwrap();
+        assert_eq!(expr, TagExpr::Not(tag("(x)")));
+    }
+
+    #[test]
+    fn lowercase_operators_are_tags() {
+        let expr: TagExpr = "and".parse().unwrap();
+        assert_eq!(expr, TagExpr::Tag(String::from("and")));
+        assert!("a and b".parse::<TagExpr>().is_err());
+    }
+
+    #[test]
+    fn invalid_expressions_are_errors() {
+        for s in ["", "   ", "a AND", "OR a", "NOT", "(a OR b", "a OR b)", "()", "a b", "\"unterminated", "a AND AND b"] {
+            assert!(s.parse::<TagExpr>().is_err(), "{:?} should not parse", s);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["a", "NOT a", "a OR NOT b AND c", "(a OR b) AND NOT (c AND \"d e\")", "\"AND\" OR \"\""] {
+            let expr: TagExpr = s.parse().unwrap();
+            assert_eq!(expr.to_string().parse::<TagExpr>().unwrap(), expr);
+        }
+    }
+
+    #[test]
+    fn quotes_in_tags_are_doubled() {
+        let expr: TagExpr = "\"12\"\" vinyl\" OR \"\"\"\"\"\"".parse().unwrap();
+        assert_eq!(expr, TagExpr::Or(tag("12\" vinyl"), tag("\"\"")));
+        for quoted in ["12\" vinyl", "\"", "say\"hi\"", "\"AND\""] {
+            let expr = TagExpr::Not(tag(quoted));
+            assert_eq!(expr.to_string().parse::<TagExpr>().unwrap(), expr, "{} doesn't round trip", expr);
+        }
+    }
+
+    #[test]
+    fn sql_parameters_are_numbered_after_existing_ones() {
+        let expr: TagExpr = "a AND NOT b".parse().unwrap();
+        let mut values = vec![Value::Integer(1)];
+        let sql = expr.to_sql(&mut values);
+        assert_eq!(values, vec![Value::Integer(1), Value::Text(String::from("a")), Value::Text(String::from("b"))]);
+        assert!(sql.contains("tags.tag = ?2") && sql.contains("NOT EXISTS (SELECT 1 FROM tags WHERE tags.grain_id = grains.id AND tags.tag = ?3)"));
+    }
+}
@@ -0,0 +1,35 @@
+// File: test_util.rs
+// This file has fixtures that are shared by the unit tests.
+
+use std::path::Path;
+
+/// A file in the temporary directory, which is deleted when it is dropped. The file name includes the
+/// process id, so that test runs at the same time don't share files.
+pub struct TempFile(pub String);
+
+impl TempFile {
+    /// Makes a temporary file path from the name of the module that uses it and a name that is unique within
+    /// the module. Any file left at the path by an earlier run is deleted.
+    pub fn new(module: &str, name: &str) -> TempFile {
+        let path = std::env::temp_dir().join(format!("grain_processor_{}_{}_{}", module, std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        TempFile(path.to_string_lossy().into_owned())
+    }
+
+    /// Makes a temporary file with the given contents
+    pub fn with_contents(module: &str, name: &str, contents: impl AsRef<[u8]>) -> TempFile {
+        let file = TempFile::new(module, name);
+        std::fs::write(&file.0, contents).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}